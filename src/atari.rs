@@ -1,55 +1,10 @@
-use crate::fields::{OpResult, OpResult::*};
 use crate::memory::Bus;
 use crate::devices::*;
+use crate::tos::TOSImage;
 use crate::Configuration;
+use chrono::{Local, NaiveDateTime};
+use std::rc::Rc;
 
-const RAM_SIZE: u32 = 0x400000;
-const BASE_ADDRESS: u32 = 0xfc0000;
-const START_ADDRESS: u32 = 0xfc0030;
-const INITIAL_SSP: u32 = 0x0104; 
-
-// Initial Memory Layout Atari ST
-const MEMORY_LAYOUT: [(usize, OpResult); 14] = [
-    //   $000.L      Reset initial SSP value
-    (0x0, Long(0x0104)),
-    //   $004.L      Reset initial PC address
-    (0x4, Long(START_ADDRESS)),
-    //   $028.L      Line 1010 (Line A routine)
-    // Line A emulator $EB9A
-    (0x28, Long(0xeb9a)),
-    //   $068.L      Interrupt level 2 (Hblank sync)
-    // Level 2 interrupt $543C
-    (0x68, Long(0x543c)),
-    //   $070.L      Interrupt level 4 (Vblank sync)
-    // Level 4 interrupt $5452
-    (0x80, Long(0x5452)),
-    //   $420.L      Memvalid (Cold start OK if #$752019F3)
-    (0x420, Long(0x752019f3)),
-    //   $424.B      Memcntlr (Memory controller low nibble)
-    (0x424, Byte(0x0)),
-    //   $426.L      Resvalid (#$31415926 to jump through 'resvector')
-    (0x426, Long(0x0)),
-    //   $42A.L      Resvector (System reset bailout vector)
-    (0x42a, Long(START_ADDRESS)),
-    //   $42E.L      Phystop (Physical RAM top)
-    (0x42e, Long(RAM_SIZE)),
-    //   $43A.L      Memval2 (#$237698AA)
-    (0x43a, Long(0x237698aa)),
-    //   $51A.L      Memval3 (#$5555AAAA)
-    (0x51a, Long(0x5555aaaa)),
-    //   $4A6.W      _Nflops (Number of floppies attached)
-    (0x4a6, Word(0x1)),
-    //   $44E.L      _V_bas_ad (Screen memory base pointer)
-    (0x44e, Long(0x78000)),
-    ];
-
-    // Set trap vectors:
-    // TRAP #1 GEMDOS $965E
-    // TRAP #2 GEM $2A338
-    // TRAP #13 BIOS $556C
-    // TRAP #14 XBIOS $5566
-    
-    
 //   $000.L      Reset initial SSP value
 //   $004.L      Reset initial PC address
 //   $008.L      Bus error
@@ -308,30 +263,313 @@ const MEMORY_LAYOUT: [(usize, OpResult); 14] = [
 
 //  $5220  *     Directory buffer
 
-pub fn st1040() -> Configuration {
-    let mut bus = Bus::new();
-    bus.attach(CartridgeROM::new(0xfffa0000));
-    bus.attach(Ram::new(0xff8000));
-    bus.attach(Monitor::new(0xff3f8000, 0xffff8201));
-    bus.attach(Blitter::new(0xffff8a00));
-    bus.attach(MMU::new(0xffff8000));
-    bus.attach(Floppy::new(0xffff8600, "examples/ST0001 Mono Demos.st"));
-    bus.attach(SoundGenerator::new(0xffff8800));
-    bus.attach(MultiFunctionPeripheral::new(0xfffffa01));
-    bus.attach(Keyboard::new(0xfffffc00));
-    bus.attach(MIDIAdapter::new(0xfffffc04));
-    bus.attach(Microwire::new(0xffff8922));
-    bus.attach(DMASoundSystem::new(0xffff8900));
-    bus.attach(SystemControlUnit::new(0xffff8e00));
-    bus.attach(JoystickPort::new(0xffff9200));
-    bus.attach(RealTimeClock::new(0xfffffc20));
-
-    Configuration {
-        base_address: BASE_ADDRESS,
-        start_address: START_ADDRESS,
-        initial_ssp: INITIAL_SSP,
-        bus: bus,
-        memory_layout: Vec::from(MEMORY_LAYOUT),
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MachineModel {
+    ST520,
+    ST1040,
+    MegaST,
+    STE,
+    MegaSTE,
+}
+
+impl MachineModel {
+    pub fn from(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "520st" | "st520" => Some(Self::ST520),
+            "1040st" | "st1040" | "st" => Some(Self::ST1040),
+            "megast" | "mega" => Some(Self::MegaST),
+            "ste" | "1040ste" => Some(Self::STE),
+            "megaste" => Some(Self::MegaSTE),
+            _ => None,
+        }
+    }
+    fn ram_size(&self) -> usize {
+        match self {
+            Self::ST520 => 0x80000,
+            Self::ST1040 | Self::STE => 0x100000,
+            Self::MegaST | Self::MegaSTE => 0x400000,
+        }
+    }
+    fn tos(&self) -> &str {
+        match self {
+            Self::ST520 | Self::ST1040 | Self::MegaST => "tos/TOS104GE.IMG",
+            Self::STE => "tos/tos106de.img",
+            Self::MegaSTE => "tos/tos206de.img",
+        }
     }
+    fn ste(&self) -> bool {
+        *self == Self::STE || *self == Self::MegaSTE
+    }
+    fn mega(&self) -> bool {
+        *self == Self::MegaST || *self == Self::MegaSTE
+    }
+}
+
+// Builds the configuration of one of the Atari models. All choices default to what the model came
+// with, e.g.
+//     Machine::new(MachineModel::ST520).ram_size(0x100000).blitter(true).build()
+pub struct Machine {
+    model: MachineModel,
+    ram_size: usize,
+    tos: Option<TOSImage>,
+    blitter: bool,
+    floppy: Option<String>,
+    hard_disk: Option<String>,
+    monochrome: bool,
+    serial: Option<String>,
+    layout: Option<KeyboardLayout>,
+    mouse_speed: f32,
+    grab_mouse: bool,
+    joysticks: Vec<(usize, JoystickInput)>,
+    midi_output: Option<String>,
+    midi_input: Option<String>,
+    printer: Option<String>,
+    time: Option<NaiveDateTime>,
+    cartridge: Option<String>,
+    headless: bool,
+    screenshot: Option<(String, Option<u64>)>,
+    recording: Option<String>,
+    frames: Option<u64>,
 }
 
+const RAM_SIZES: [usize; 6] = [0x40000, 0x80000, 0x100000, 0x200000, 0x280000, 0x400000];
+
+impl Machine {
+    pub fn new(model: MachineModel) -> Self {
+        Self {
+            model,
+            ram_size: model.ram_size(),
+            tos: None,
+            blitter: model != MachineModel::ST520 && model != MachineModel::ST1040,
+            floppy: None,
+            hard_disk: None,
+            monochrome: false,
+            serial: None,
+            layout: None,
+            mouse_speed: 1.0,
+            grab_mouse: false,
+            joysticks: Vec::new(),
+            midi_output: None,
+            midi_input: None,
+            printer: None,
+            time: None,
+            cartridge: None,
+            headless: false,
+            screenshot: None,
+            recording: None,
+            frames: None,
+        }
+    }
+    pub fn ram_size(mut self, ram_size: usize) -> Self {
+        self.ram_size = ram_size;
+        self
+    }
+    pub fn tos(mut self, tos: TOSImage) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+    pub fn blitter(mut self, blitter: bool) -> Self {
+        self.blitter = blitter;
+        self
+    }
+    pub fn floppy(mut self, image: &str) -> Self {
+        self.floppy = Some(String::from(image));
+        self
+    }
+    pub fn hard_disk(mut self, image: &str) -> Self {
+        self.hard_disk = Some(String::from(image));
+        self
+    }
+    // Attaches the SM124 monochrome monitor instead of a colour one
+    pub fn monochrome(mut self, monochrome: bool) -> Self {
+        self.monochrome = monochrome;
+        self
+    }
+    // Connects the RS-232 port to the host, see SerialLink
+    pub fn serial(mut self, spec: &str) -> Self {
+        self.serial = Some(String::from(spec));
+        self
+    }
+    // Without a layout the one of the TOS image's country is used
+    pub fn keyboard_layout(mut self, layout: KeyboardLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+    // Scales the movement of the host mouse, a grabbed mouse is hidden in the window
+    pub fn mouse(mut self, speed: f32, grab: bool) -> Self {
+        self.mouse_speed = speed;
+        self.grab_mouse = grab;
+        self
+    }
+    // Plays one of the joystick ports from the host: 0 and 1 are the IKBD's, 2 and 3 the STE's
+    // joypad ports A and B
+    pub fn joystick(mut self, port: usize, input: JoystickInput) -> Self {
+        self.joysticks.push((port, input));
+        self
+    }
+    // Records what is sent to the MIDI ports into a Standard MIDI File
+    pub fn midi_output(mut self, path: &str) -> Self {
+        self.midi_output = Some(String::from(path));
+        self
+    }
+    // Plays a Standard MIDI File (*.mid) into the MIDI ports, or raw MIDI bytes from anything else
+    pub fn midi_input(mut self, path: &str) -> Self {
+        self.midi_input = Some(String::from(path));
+        self
+    }
+    // Connects a printer to the parallel port, see Printer
+    pub fn printer(mut self, spec: &str) -> Self {
+        self.printer = Some(String::from(spec));
+        self
+    }
+    // Starts the clocks at a fixed time instead of the host's, for runs that go the same way each time
+    pub fn time(mut self, time: NaiveDateTime) -> Self {
+        self.time = Some(time);
+        self
+    }
+    pub fn cartridge(mut self, image: &str) -> Self {
+        self.cartridge = Some(String::from(image));
+        self
+    }
+    // Renders the picture without opening a window and plays no sound
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+    // Saves the picture as a PNG or PPM image at the given frame, and when SIGUSR1 or Scroll Lock
+    // asks for it
+    pub fn screenshot(mut self, path: &str, frame: Option<u64>) -> Self {
+        self.screenshot = Some((String::from(path), frame));
+        self
+    }
+    // Records the picture into a YUV4MPEG2 video and the PSG's sound into a WAV file next to it
+    pub fn record(mut self, path: &str) -> Self {
+        self.recording = Some(String::from(path));
+        self
+    }
+    // Stops after the given number of frames
+    pub fn frames(mut self, frames: u64) -> Self {
+        self.frames = Some(frames);
+        self
+    }
+    pub fn build(self) -> Result<Configuration, String> {
+        if !RAM_SIZES.contains(&self.ram_size) {
+            return Err(format!("{} KB of RAM is not a possible configuration", self.ram_size / 1024));
+        }
+        if let Some((port, _)) = self.joysticks.iter().find(|(port, _)| *port > 3) {
+            return Err(format!("There is no joystick port {}", port));
+        }
+        if self.joysticks.iter().any(|(port, _)| *port >= 2 && !self.model.ste()) {
+            return Err(String::from("Joypad ports need an STE"));
+        }
+        let tos = match self.tos {
+            Some(tos) => tos,
+            None => TOSImage::open(self.model.tos())?,
+        };
+        let mut bus = Bus::with_address_lines(24);
+        let mut sound_generator = SoundGenerator::new(0xff8800);
+        // Drive A stays empty without a disk image
+        let image = self.floppy.as_deref().map(DiskImage::open).transpose()?;
+        let mut floppy = Floppy::new(0xff8600, image, Rc::clone(&sound_generator.port_a));
+        if let Some(image) = &self.hard_disk {
+            floppy.attach_hard_disk(0, HardDisk::open(image)?);
+        }
+        let mut mfp = MultiFunctionPeripheral::new(0xfffa01);
+        let printer = match &self.printer {
+            Some(spec) => {
+                let printer = Printer::open(spec, Rc::clone(&sound_generator.port_a), Rc::clone(&sound_generator.port_b))?;
+                mfp.connect(0, Rc::clone(&printer.ready));
+                Some(printer)
+            }
+            None => None,
+        };
+        mfp.connect(5, Rc::clone(&floppy.interrupt));
+        mfp.connect(5, Rc::clone(&floppy.hdc_interrupt));
+        if let Some(spec) = &self.serial {
+            mfp.connect_serial(SerialLink::open(spec)?);
+        }
+        bus.attach(match &self.cartridge {
+            Some(image) => CartridgeROM::open(0xfa0000, image)?,
+            None => CartridgeROM::new(0xfa0000),
+        });
+        bus.attach(ROM::new(tos.base as usize, tos.data.clone()));
+        bus.attach(MMU::new(0xff8000, self.ram_size));
+        let mut monitor = Monitor::new(0xff8200, self.model.ste());
+        monitor.mouse_speed = self.mouse_speed;
+        monitor.grab_mouse = self.grab_mouse;
+        monitor.headless = self.headless;
+        // Nor does it play sound, which would also hold the emulation to real time
+        sound_generator.sound = !self.headless;
+        monitor.frames = self.frames;
+        if self.screenshot.is_some() {
+            listen_for_screenshot_requests();
+        }
+        monitor.screenshot = self.screenshot;
+        if let Some(path) = &self.recording {
+            let video = VideoRecording::create(path)?;
+            sound_generator.recording = Some(SoundRecording::create(&video.sound_path())?);
+            monitor.video = Some(video);
+        }
+        // A monochrome monitor pulls the MFP's GPIP 7 low
+        *monitor.monochrome.borrow_mut() = self.monochrome;
+        mfp.connect(7, Rc::clone(&monitor.monochrome));
+        mfp.connect_timer(1, Rc::clone(&monitor.de));
+        mfp.connect_timer_edges(1, Rc::clone(&monitor.de_edges));
+        let joysticks = JoystickMapper::new(&self.joysticks)?;
+        let joystick_states = Rc::clone(&joysticks.states);
+        let layout = self.layout.unwrap_or(KeyboardLayout::for_country(tos.country));
+        let time = self.time.unwrap_or_else(|| Local::now().naive_local());
+        let keyboard = Keyboard::new(0xfffc00, Rc::clone(&monitor.input), layout, joysticks, time);
+        mfp.connect(4, Rc::clone(&keyboard.interrupt));
+        let mut midi = MIDIAdapter::new(0xfffc04);
+        if let Some(path) = &self.midi_output {
+            midi.record(path)?;
+        }
+        if let Some(path) = &self.midi_input {
+            midi.play(path)?;
+        }
+        mfp.connect(4, Rc::clone(&midi.interrupt));
+        bus.attach(monitor);
+        if self.blitter {
+            let blitter = Blitter::new(0xff8a00);
+            mfp.connect(3, Rc::clone(&blitter.interrupt));
+            bus.attach(blitter);
+        }
+        if self.model.ste() {
+            let mut dma_sound = DMASoundSystem::new(0xff8900);
+            dma_sound.sound = !self.headless;
+            sound_generator.mixer = Some(Rc::clone(&dma_sound.mixer));
+            bus.attach(Microwire::new(0xff8922, Rc::clone(&dma_sound.mixer)));
+            // GPIP 7 is the monochrome detect XORed with the active signal, so it flips while
+            // sound plays whatever the monitor
+            mfp.connect_xor(7, Rc::clone(&dma_sound.active));
+            mfp.connect_timer(0, Rc::clone(&dma_sound.active));
+            bus.attach(dma_sound);
+        }
+        bus.attach(floppy);
+        bus.attach(sound_generator);
+        if let Some(printer) = printer {
+            bus.attach(printer);
+        }
+        bus.attach(mfp);
+        bus.attach(keyboard);
+        bus.attach(midi);
+        if self.model.ste() {
+            bus.attach(JoystickPort::new(0xff9200, joystick_states));
+        }
+        if self.model == MachineModel::MegaSTE {
+            bus.attach(SystemControlUnit::new(0xff8e00));
+        }
+        if self.model.mega() {
+            bus.attach(RealTimeClock::new(0xfffc20, time));
+        }
+
+        Ok(Configuration {
+            base_address: tos.base,
+            start_address: tos.reset,
+            initial_ssp: 0,
+            bus,
+            memory_layout: Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,106 @@
+// The MC6850 ACIA, a simple serial interface. The ST has two of them, one talking to the keyboard
+// controller, the other to the MIDI ports. Each has a control register (status when read) and a
+// data register, one byte each, and a receive and a transmit buffer of one byte. Their interrupt
+// outputs share GPIP 4 of the MFP.
+//
+// The ACIA itself does not know about time: the device it belongs to moves bytes in and out at
+// the speed of its line.
+
+use super::{signal_line, SignalLine};
+
+// Status register
+const RECEIVE_FULL: u8 = 0x01;
+const TRANSMIT_EMPTY: u8 = 0x02;
+const OVERRUN: u8 = 0x20;
+const IRQ: u8 = 0x80;
+// Control register
+const MASTER_RESET: u8 = 0x03;
+const TRANSMIT_INTERRUPT: u8 = 0x20;
+const RECEIVE_INTERRUPT: u8 = 0x80;
+
+pub struct ACIA {
+    control: u8,
+    status: u8,
+    received: u8,
+    transmitted: Option<u8>,
+    overrun: bool,
+    pub interrupt: SignalLine,
+}
+
+impl Default for ACIA {
+    fn default() -> Self {
+        Self { control: MASTER_RESET, status: TRANSMIT_EMPTY, received: 0, transmitted: None, overrun: false, interrupt: signal_line() }
+    }
+}
+
+impl ACIA {
+    pub fn read(&mut self, register: usize) -> u8 {
+        let value = if register == 0 {
+            self.status
+        } else {
+            // An overrun is reported once the byte before it has been read
+            self.status &= !(RECEIVE_FULL | OVERRUN);
+            if self.overrun {
+                self.overrun = false;
+                self.status |= OVERRUN;
+            }
+            self.received
+        };
+        self.update_interrupt();
+        value
+    }
+    pub fn write(&mut self, register: usize, value: u8) {
+        if register == 0 {
+            self.control = value;
+            if value & MASTER_RESET == MASTER_RESET {
+                self.status = TRANSMIT_EMPTY;
+                self.transmitted = None;
+                self.overrun = false;
+            }
+        } else if self.control & MASTER_RESET != MASTER_RESET {
+            self.transmitted = Some(value);
+            self.status &= !TRANSMIT_EMPTY;
+        }
+        self.update_interrupt();
+    }
+    // Whether the receiver is on and its buffer free for another byte
+    pub fn ready(&self) -> bool {
+        self.control & MASTER_RESET != MASTER_RESET && self.status & RECEIVE_FULL == 0
+    }
+    // Puts a byte from the line into the receive buffer. If the last one has not been read yet,
+    // the new one is lost.
+    pub fn receive(&mut self, byte: u8) {
+        if self.control & MASTER_RESET == MASTER_RESET {
+            return;
+        }
+        if !self.ready() {
+            self.overrun = true;
+            return;
+        }
+        self.received = byte;
+        self.status |= RECEIVE_FULL;
+        self.update_interrupt();
+    }
+    // Takes the byte to be sent out of the transmit buffer
+    pub fn transmit(&mut self) -> Option<u8> {
+        let byte = self.transmitted.take();
+        if byte.is_some() {
+            self.status |= TRANSMIT_EMPTY;
+            self.update_interrupt();
+        }
+        byte
+    }
+    pub fn transmitting(&self) -> bool {
+        self.transmitted.is_some()
+    }
+    fn update_interrupt(&mut self) {
+        let receive = self.control & RECEIVE_INTERRUPT != 0 && self.status & (RECEIVE_FULL | OVERRUN) != 0;
+        let transmit = self.control & 0x60 == TRANSMIT_INTERRUPT && self.status & TRANSMIT_EMPTY != 0;
+        if receive || transmit {
+            self.status |= IRQ;
+        } else {
+            self.status &= !IRQ;
+        }
+        *self.interrupt.borrow_mut() = receive || transmit;
+    }
+}
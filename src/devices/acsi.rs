@@ -0,0 +1,365 @@
+// Hard disks on the ACSI bus, which is driven by the same DMA chip as the floppy controller.
+// Commands are sent to the targets one byte at a time through the DMA chip's data register, the
+// first byte carrying the target number in its upper three bits. The target acknowledges each byte
+// by pulling the (shared) FDC/HDC interrupt line, transfers its data via DMA and finally presents
+// a status byte. The targets understand the common subset of SCSI group 0 commands used by the
+// Atari hard disk drivers and are backed by raw image files.
+
+use super::floppy::DMA;
+use super::SignalLine;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const SECTOR_SIZE: usize = 512;
+const COMMAND_LENGTH: usize = 6;
+// About 1 MB/s
+const CYCLES_PER_BYTE: u64 = 8;
+
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const READ: u8 = 0x08;
+const WRITE: u8 = 0x0a;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE: u8 = 0x1a;
+
+const GOOD: u8 = 0x00;
+const CHECK_CONDITION: u8 = 0x02;
+
+// Sense keys and additional sense codes
+const NO_SENSE: (u8, u8) = (0x00, 0x00);
+const MEDIUM_ERROR: (u8, u8) = (0x03, 0x11);
+const INVALID_COMMAND: (u8, u8) = (0x05, 0x20);
+const INVALID_ADDRESS: (u8, u8) = (0x05, 0x21);
+
+pub struct HardDisk {
+    file: File,
+    sectors: usize,
+    sense: (u8, u8),
+    address: usize,
+}
+
+impl HardDisk {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new().read(true).write(true).open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let sectors = file.metadata().map(|m| m.len() as usize / SECTOR_SIZE).unwrap_or(0);
+        Ok(Self { file, sectors, sense: NO_SENSE, address: 0 })
+    }
+    fn check(&mut self, sense: (u8, u8)) -> Transfer {
+        self.sense = sense;
+        Transfer::Status(CHECK_CONDITION)
+    }
+    fn execute(&mut self, command: &[u8]) -> Transfer {
+        let address = ((command[1] as usize & 0x1f) << 16) | (command[2] as usize) << 8 | command[3] as usize;
+        let length = command[4] as usize;
+        match command[0] {
+            TEST_UNIT_READY => Transfer::Status(GOOD),
+            REQUEST_SENSE => {
+                let (key, code) = self.sense;
+                // Allocation lengths of up to 4 bytes ask for the original, non-extended ACSI format
+                let mut sense = if length > 0 && length <= 4 {
+                    vec![code, (self.address >> 16) as u8 & 0x1f, (self.address >> 8) as u8, self.address as u8]
+                } else {
+                    let mut sense = vec![0; 18];
+                    sense[0] = 0x70;
+                    sense[2] = key;
+                    sense[7] = 10;
+                    sense[12] = code;
+                    sense
+                };
+                sense.truncate(if length == 0 { 4 } else { length });
+                self.sense = NO_SENSE;
+                Transfer::In(sense)
+            }
+            READ | WRITE => {
+                let count = if length == 0 { 256 } else { length };
+                self.address = address;
+                if address + count > self.sectors {
+                    return self.check(INVALID_ADDRESS);
+                }
+                if command[0] == WRITE {
+                    return Transfer::Out(count * SECTOR_SIZE);
+                }
+                let mut data = vec![0; count * SECTOR_SIZE];
+                let result = self
+                    .file
+                    .seek(SeekFrom::Start((address * SECTOR_SIZE) as u64))
+                    .and_then(|_| self.file.read_exact(&mut data));
+                match result {
+                    Ok(_) => Transfer::In(data),
+                    Err(_) => self.check(MEDIUM_ERROR),
+                }
+            }
+            INQUIRY => {
+                let mut inquiry = vec![0x00, 0x00, 0x01, 0x01, 31, 0, 0, 0];
+                inquiry.extend_from_slice(b"EM68K   ");
+                inquiry.extend_from_slice(b"ACSI HARDDISK   ");
+                inquiry.extend_from_slice(b"0001");
+                inquiry.truncate(length);
+                Transfer::In(inquiry)
+            }
+            MODE_SENSE => {
+                // Mode parameter header followed by a single block descriptor
+                let mut mode = vec![11, 0, 0, 8, 0];
+                mode.extend_from_slice(&(self.sectors as u32).to_be_bytes()[1..]);
+                mode.extend_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+                mode.truncate(length);
+                Transfer::In(mode)
+            }
+            _ => self.check(INVALID_COMMAND),
+        }
+    }
+    fn write(&mut self, data: &[u8]) -> u8 {
+        let result = self
+            .file
+            .seek(SeekFrom::Start((self.address * SECTOR_SIZE) as u64))
+            .and_then(|_| self.file.write_all(data));
+        match result {
+            Ok(_) => GOOD,
+            Err(_) => {
+                self.sense = MEDIUM_ERROR;
+                CHECK_CONDITION
+            }
+        }
+    }
+}
+
+enum Transfer {
+    In(Vec<u8>),
+    Out(usize),
+    Status(u8),
+}
+
+enum Phase {
+    Idle,
+    Command,
+    DataIn(Vec<u8>, usize),
+    DataOut(Vec<u8>, usize),
+    Status(u8),
+}
+
+pub(super) struct AcsiBus {
+    targets: [Option<HardDisk>; 8],
+    selected: usize,
+    command: Vec<u8>,
+    phase: Phase,
+    event: u64,
+    interrupt: SignalLine,
+}
+
+impl AcsiBus {
+    pub(super) fn new(interrupt: SignalLine) -> Self {
+        Self {
+            targets: [None, None, None, None, None, None, None, None],
+            selected: 0,
+            command: Vec::with_capacity(COMMAND_LENGTH),
+            phase: Phase::Idle,
+            event: 0,
+            interrupt,
+        }
+    }
+    pub(super) fn attach(&mut self, target: usize, disk: HardDisk) {
+        self.targets[target] = Some(disk);
+    }
+    fn interrupt(&mut self, value: bool) {
+        *self.interrupt.borrow_mut() = value;
+    }
+    // Command bytes are written with the DMA chip's A1 line low for the first byte only
+    pub(super) fn write(&mut self, value: u8, first: bool, cycles: u64) {
+        self.interrupt(false);
+        if first {
+            self.selected = value as usize >> 5;
+            if self.targets[self.selected].is_none() {
+                self.phase = Phase::Idle;
+                return;
+            }
+            self.command.clear();
+            self.phase = Phase::Command;
+        }
+        if let Phase::Command = self.phase {
+            self.command.push(if first { value & 0x1f } else { value });
+            if self.command.len() < COMMAND_LENGTH {
+                self.interrupt(true);
+                return;
+            }
+            let command = self.command.clone();
+            let target = self.targets[self.selected].as_mut().unwrap();
+            self.phase = match target.execute(&command) {
+                Transfer::In(data) => Phase::DataIn(data, 0),
+                Transfer::Out(length) => Phase::DataOut(Vec::with_capacity(length), length),
+                Transfer::Status(status) => Phase::Status(status),
+            };
+            self.event = cycles;
+            self.complete();
+        }
+    }
+    pub(super) fn read(&mut self) -> u8 {
+        self.interrupt(false);
+        match self.phase {
+            Phase::Status(status) => {
+                self.phase = Phase::Idle;
+                status
+            }
+            _ => 0xff,
+        }
+    }
+    fn complete(&mut self) {
+        let complete = match &self.phase {
+            Phase::DataIn(data, position) => *position >= data.len(),
+            Phase::DataOut(data, length) => data.len() >= *length,
+            Phase::Status(_) => true,
+            _ => false,
+        };
+        if !complete {
+            return;
+        }
+        if let Phase::DataOut(data, _) = &self.phase {
+            let data = data.clone();
+            let status = self.targets[self.selected].as_mut().unwrap().write(&data);
+            self.phase = Phase::Status(status);
+        } else if let Phase::DataIn(_, _) = self.phase {
+            self.phase = Phase::Status(GOOD);
+        }
+        self.interrupt(true);
+    }
+    // Moves data between the selected target and the DMA chip at the speed of the ACSI bus
+    pub(super) fn clock(&mut self, cycles: u64, dma: &mut DMA) {
+        while self.event + CYCLES_PER_BYTE <= cycles {
+            let transferred = match &mut self.phase {
+                Phase::DataIn(data, position) if dma.serves_hdc() && !dma.writing() && dma.accepts_data() => {
+                    dma.fifo.push_back(data[*position]);
+                    *position += 1;
+                    true
+                }
+                Phase::DataOut(data, _) if dma.serves_hdc() && dma.writing() => match dma.fifo.pop_front() {
+                    Some(byte) => {
+                        data.push(byte);
+                        true
+                    }
+                    None => false,
+                },
+                _ => false,
+            };
+            if !transferred {
+                self.event = cycles;
+                return;
+            }
+            self.event += CYCLES_PER_BYTE;
+            self.complete();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{port, Device, Floppy, Ram, Signal};
+    use super::*;
+    use crate::fields::{OpResult, Size};
+    use crate::memory::Bus;
+
+    const BUFFER: usize = 0x1000;
+    const MODE_A0: u16 = 0x02;
+    const MODE_HDC: u16 = 0x08;
+    const MODE_SECTOR_COUNT: u16 = 0x10;
+    const MODE_WRITE: u16 = 0x100;
+
+    // Every byte of the image tells which sector it is in
+    fn image(name: &str, sectors: usize) -> String {
+        let path = std::env::temp_dir().join(format!("em68k-{}-{}.img", name, std::process::id())).display().to_string();
+        let data: Vec<u8> = (0..sectors * SECTOR_SIZE).map(|j| (j / SECTOR_SIZE) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn setup(path: &str) -> (Box<Floppy>, Bus) {
+        let mut floppy = Floppy::new(0xff8600, None, port(0xff));
+        floppy.attach_hard_disk(0, HardDisk::open(path).unwrap());
+        let mut bus = Bus::new();
+        bus.attach(Ram::new(0x10000));
+        (floppy, bus)
+    }
+
+    fn mode(floppy: &mut Floppy, mode: u16) {
+        floppy.write(0xff8606, OpResult::Word(mode));
+    }
+
+    // Sends the command to target 0 the way the hard disk drivers do and returns its status
+    fn command(floppy: &mut Floppy, bus: &mut Bus, command: [u8; 6], sectors: u16, write: bool) -> u8 {
+        let direction = if write { MODE_WRITE } else { 0 };
+        // Toggling the direction resets the DMA
+        mode(floppy, MODE_SECTOR_COUNT | direction ^ MODE_WRITE);
+        mode(floppy, MODE_SECTOR_COUNT | direction);
+        floppy.write(0xff8604, OpResult::Word(sectors));
+        for (offset, byte) in [(0x09, BUFFER >> 16), (0x0b, BUFFER >> 8), (0x0d, BUFFER)] {
+            floppy.write(0xff8600 + offset, OpResult::Byte(byte as u8));
+        }
+        for (j, byte) in command.iter().enumerate() {
+            mode(floppy, MODE_HDC | direction | if j == 0 { 0 } else { MODE_A0 });
+            floppy.write(0xff8604, OpResult::Word(*byte as u16));
+            if j + 1 < command.len() {
+                assert!(*floppy.hdc_interrupt.borrow(), "byte {} of the command was not acknowledged", j);
+            }
+        }
+        let mut cycles = 0;
+        while !*floppy.hdc_interrupt.borrow() {
+            cycles += 64;
+            assert!(cycles < 1_000_000, "command ${:02x} did not end", command[0]);
+            if floppy.clock(cycles) == Signal::BusRequest {
+                floppy.bus_access(bus);
+            }
+        }
+        floppy.read(0xff8604, Size::Word).inner() as u8
+    }
+
+    fn memory(bus: &mut Bus, length: usize) -> Vec<u8> {
+        (0..length).map(|j| bus.read(BUFFER + j, Size::Byte).inner() as u8).collect()
+    }
+
+    #[test]
+    fn read_sectors() {
+        let path = image("read", 8);
+        let (mut floppy, mut bus) = setup(&path);
+        assert_eq!(command(&mut floppy, &mut bus, [READ, 0, 0, 3, 2, 0], 2, false), GOOD);
+        std::fs::remove_file(&path).ok();
+        let expected: Vec<u8> = (0..2 * SECTOR_SIZE).map(|j| (3 + j / SECTOR_SIZE) as u8).collect();
+        assert_eq!(memory(&mut bus, 2 * SECTOR_SIZE), expected);
+        // The byte after the data is untouched
+        assert_eq!(bus.read(BUFFER + 2 * SECTOR_SIZE, Size::Byte).inner(), 0);
+    }
+
+    #[test]
+    fn write_sector() {
+        let path = image("write", 8);
+        let (mut floppy, mut bus) = setup(&path);
+        for j in 0..SECTOR_SIZE {
+            bus.write(BUFFER + j, OpResult::Byte(0xe5));
+        }
+        assert_eq!(command(&mut floppy, &mut bus, [WRITE, 0, 0, 5, 1, 0], 1, true), GOOD);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert!(data[5 * SECTOR_SIZE..6 * SECTOR_SIZE].iter().all(|&byte| byte == 0xe5));
+        assert_eq!(data[4 * SECTOR_SIZE..5 * SECTOR_SIZE], [4; SECTOR_SIZE]);
+        assert_eq!(data[6 * SECTOR_SIZE..7 * SECTOR_SIZE], [6; SECTOR_SIZE]);
+    }
+
+    #[test]
+    fn reads_beyond_the_end_check_the_condition() {
+        let path = image("beyond", 8);
+        let (mut floppy, mut bus) = setup(&path);
+        assert_eq!(command(&mut floppy, &mut bus, [READ, 0, 0, 7, 2, 0], 2, false), CHECK_CONDITION);
+        std::fs::remove_file(&path).ok();
+        // 16 bytes of extended sense fill the DMA's FIFO, so they make it to memory
+        assert_eq!(command(&mut floppy, &mut bus, [REQUEST_SENSE, 0, 0, 0, 16, 0], 1, false), GOOD);
+        let sense = memory(&mut bus, 16);
+        assert_eq!((sense[0], sense[2], sense[12]), (0x70, INVALID_ADDRESS.0, INVALID_ADDRESS.1));
+    }
+
+    #[test]
+    fn missing_targets_do_not_answer() {
+        let path = image("missing", 8);
+        let (mut floppy, _) = setup(&path);
+        std::fs::remove_file(&path).ok();
+        mode(&mut floppy, MODE_HDC);
+        floppy.write(0xff8604, OpResult::Word((1 << 5 | TEST_UNIT_READY) as u16));
+        assert!(!*floppy.hdc_interrupt.borrow());
+    }
+}
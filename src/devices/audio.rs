@@ -0,0 +1,125 @@
+// Sound output to the host. Devices producing sound (the sound chip, the STE's DMA sound) open a
+// channel each and push samples into it as they are generated in emulated time. Channels are
+// mixed and played by rodio from a separate thread. Since the samples are produced in step with
+// the CPU, a channel whose buffer is full holds up the emulation until the host has caught up,
+// which keeps the emulated machine running in real time.
+//
+// Biquad implements the filters used to shape the sound (see the Audio EQ Cookbook).
+
+use rodio::{OutputStream, OutputStreamHandle, Source};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::f32::consts::{PI, SQRT_2};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const SAMPLE_RATE: u32 = 44100;
+// Samples buffered before the emulation waits for the host (100 ms)
+const MAX_QUEUED: usize = SAMPLE_RATE as usize / 10;
+
+thread_local! {
+    // All channels share one output stream, which has to stay alive as long as they play
+    static OUTPUT: RefCell<Option<(OutputStream, OutputStreamHandle)>> = const { RefCell::new(None) };
+}
+
+type Queue = Arc<Mutex<VecDeque<f32>>>;
+
+pub struct AudioChannel {
+    queue: Queue,
+}
+
+impl AudioChannel {
+    // Stereo channels take interleaved samples, left first
+    pub fn open(channels: u16) -> Result<Self, String> {
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        OUTPUT.with(|output| {
+            let mut output = output.borrow_mut();
+            if output.is_none() {
+                *output = Some(OutputStream::try_default().map_err(|e| e.to_string())?);
+            }
+            let (_, handle) = output.as_ref().unwrap();
+            handle.play_raw(Playback { queue: Arc::clone(&queue), channels }).map_err(|e| e.to_string())
+        })?;
+        Ok(Self { queue })
+    }
+    pub fn push(&mut self, samples: &[f32]) {
+        while self.queue.lock().unwrap().len() > MAX_QUEUED {
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.queue.lock().unwrap().extend(samples);
+    }
+}
+
+struct Playback {
+    queue: Queue,
+    channels: u16,
+}
+
+impl Iterator for Playback {
+    type Item = f32;
+    // Plays silence when the emulation falls behind
+    fn next(&mut self) -> Option<f32> {
+        Some(self.queue.lock().unwrap().pop_front().unwrap_or(0.0))
+    }
+}
+
+impl Source for Playback {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+pub struct Biquad {
+    coefficients: [f32; 5],
+    state: [f32; 4],
+}
+
+impl Biquad {
+    // Second order Butterworth low pass
+    pub fn low_pass(cutoff: f32, rate: f32) -> Self {
+        let omega = 2.0 * PI * cutoff / rate;
+        let alpha = omega.sin() / SQRT_2;
+        let b = (1.0 - omega.cos()) / 2.0;
+        Self::new([b, 2.0 * b, b], [1.0 + alpha, -2.0 * omega.cos(), 1.0 - alpha])
+    }
+    // Boosts or cuts the frequencies below (high = false) or above the given one
+    pub fn shelf(frequency: f32, gain: f32, high: bool, rate: f32) -> Self {
+        let a = 10f32.powf(gain / 40.0);
+        let omega = 2.0 * PI * frequency / rate;
+        let (cos, alpha) = (omega.cos(), omega.sin() / SQRT_2);
+        let root = 2.0 * a.sqrt() * alpha;
+        let sign = if high { -1.0 } else { 1.0 };
+        Self::new(
+            [
+                a * ((a + 1.0) - sign * (a - 1.0) * cos + root),
+                sign * 2.0 * a * ((a - 1.0) - sign * (a + 1.0) * cos),
+                a * ((a + 1.0) - sign * (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) + sign * (a - 1.0) * cos + root,
+                -sign * 2.0 * ((a - 1.0) + sign * (a + 1.0) * cos),
+                (a + 1.0) + sign * (a - 1.0) * cos - root,
+            ],
+        )
+    }
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self { coefficients: [b[0] / a[0], b[1] / a[0], b[2] / a[0], a[1] / a[0], a[2] / a[0]], state: [0.0; 4] }
+    }
+    pub fn process(&mut self, input: f32) -> f32 {
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+        let [x1, x2, y1, y2] = self.state;
+        let output = b0 * input + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+        self.state = [input, x1, output, y1];
+        output
+    }
+}
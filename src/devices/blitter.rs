@@ -0,0 +1,334 @@
+// The BLiTTER, a DMA device for moving and combining rectangular blocks of words. It reads a
+// source word (shifted by the skew through a 32 bit buffer), combines it with the halftone pattern
+// and the destination word using one of 16 logical operations and writes the result back, masked
+// by the end masks at the left and right edge of each line.
+//
+// While running, the BLiTTER owns the bus: in HOG mode until it is done, otherwise it takes turns
+// with the CPU every 64 bus cycles. Its busy signal is wired to GPIP bit 3 of the MFP.
+
+use super::{read_bytes, signal_line, write_bytes, Device, Signal, SignalLine};
+use crate::fields::{OpResult, Size};
+use crate::memory::{Bus, MemoryRange};
+use crate::processor::IRQ;
+
+const HALFTONE: usize = 0x00;
+const SRC_X_INC: usize = 0x20;
+const SRC_Y_INC: usize = 0x22;
+const SRC_ADDRESS: usize = 0x24;
+const ENDMASK_1: usize = 0x28;
+const ENDMASK_2: usize = 0x2a;
+const ENDMASK_3: usize = 0x2c;
+const DST_X_INC: usize = 0x2e;
+const DST_Y_INC: usize = 0x30;
+const DST_ADDRESS: usize = 0x32;
+const X_COUNT: usize = 0x36;
+const Y_COUNT: usize = 0x38;
+const HOP: usize = 0x3a;
+const OP: usize = 0x3b;
+const LINE_NUMBER: usize = 0x3c;
+const SKEW: usize = 0x3d;
+
+// Line number register
+const BUSY: u8 = 0x80;
+const HOG: u8 = 0x40;
+const SMUDGE: u8 = 0x20;
+// Skew register
+const FXSR: u8 = 0x80;
+const NFSR: u8 = 0x40;
+
+// Cycles the BLiTTER and the CPU get in turn when sharing the bus (64 bus cycles)
+const BUS_SHARE: u64 = 256;
+
+pub struct Blitter {
+    address: usize,
+    registers: [u8; 0x3e],
+    // Words left in the current line, 0 at the start of a line
+    x_left: u32,
+    buffer: u32,
+    resume: u64,
+    pub interrupt: SignalLine,
+}
+
+impl Blitter {
+    pub fn new(address: usize) -> Box<Self> {
+        Box::new(Self { address, registers: [0; 0x3e], x_left: 0, buffer: 0, resume: 0, interrupt: signal_line() })
+    }
+    fn word(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.registers[offset], self.registers[offset + 1]])
+    }
+    fn set_word(&mut self, offset: usize, value: u16) {
+        self.registers[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    fn long(&self, offset: usize) -> usize {
+        (self.word(offset) as usize) << 16 | self.word(offset + 2) as usize
+    }
+    fn set_long(&mut self, offset: usize, value: usize) {
+        self.set_word(offset, (value >> 16) as u16);
+        self.set_word(offset + 2, value as u16);
+    }
+    fn increment(&mut self, offset: usize, increment: usize) {
+        let address = self.long(offset).wrapping_add(self.word(increment) as i16 as usize) & 0xfffffe;
+        self.set_long(offset, address);
+    }
+    fn busy(&self) -> bool {
+        self.registers[LINE_NUMBER] & BUSY != 0
+    }
+    fn read_source(&mut self, bus: &mut Bus, last: bool) {
+        let word = bus.read(self.long(SRC_ADDRESS), Size::Word).inner();
+        // Going right to left, new words enter the buffer from the other side
+        self.buffer = if (self.word(SRC_X_INC) as i16) < 0 { self.buffer >> 16 | word << 16 } else { self.buffer << 16 | word };
+        self.increment(SRC_ADDRESS, if last { SRC_Y_INC } else { SRC_X_INC });
+    }
+    // Transfers one word of the destination
+    fn transfer(&mut self, bus: &mut Bus) {
+        // The CPU may have cleared the line count between two turns, which ends the blit
+        if self.word(Y_COUNT) == 0 {
+            self.registers[LINE_NUMBER] &= !BUSY;
+            self.x_left = 0;
+            return;
+        }
+        // A count of 0 means 65536
+        let x_count = match self.word(X_COUNT) {
+            0 => 0x10000,
+            count => count as u32,
+        };
+        let skew = self.registers[SKEW];
+        if self.x_left == 0 {
+            self.x_left = x_count;
+            if skew & FXSR != 0 {
+                self.read_source(bus, false);
+            }
+        }
+        let first = self.x_left == x_count;
+        let last = self.x_left == 1;
+        if !(last && skew & NFSR != 0) {
+            self.read_source(bus, last);
+        } else {
+            // The Y increment replaces the X increment of the last read
+            let increment = (self.word(SRC_Y_INC) as i16 as usize).wrapping_sub(self.word(SRC_X_INC) as i16 as usize);
+            self.set_long(SRC_ADDRESS, self.long(SRC_ADDRESS).wrapping_add(increment) & 0xfffffe);
+        }
+        let source = (self.buffer >> (skew & 0xf)) as u16;
+        let line = self.registers[LINE_NUMBER];
+        let halftone = if line & SMUDGE != 0 { self.word(HALFTONE + 2 * (source as usize & 0xf)) } else { self.word(HALFTONE + 2 * (line as usize & 0xf)) };
+        let source = match self.registers[HOP] & 0x3 {
+            0 => 0xffff,
+            1 => halftone,
+            2 => source,
+            _ => source & halftone,
+        };
+        let mask = if first {
+            self.word(ENDMASK_1)
+        } else if last {
+            self.word(ENDMASK_3)
+        } else {
+            self.word(ENDMASK_2)
+        };
+        let op = self.registers[OP] & 0xf;
+        let destination = self.long(DST_ADDRESS);
+        // Operations not involving the destination do not need to read it
+        let old = if mask != 0xffff || !matches!(op, 0 | 3 | 12 | 15) { bus.read(destination, Size::Word).inner() as u16 } else { 0 };
+        let mut result = 0;
+        for (bit, value) in [(1, source & old), (2, source & !old), (4, !source & old), (8, !source & !old)] {
+            if op & bit != 0 {
+                result |= value;
+            }
+        }
+        bus.write(destination, OpResult::Word((old & !mask) | (result & mask)));
+        self.increment(DST_ADDRESS, if last { DST_Y_INC } else { DST_X_INC });
+        self.x_left -= 1;
+        if last {
+            let lines = self.word(Y_COUNT) - 1;
+            self.set_word(Y_COUNT, lines);
+            // The halftone line follows the direction of the destination
+            let step = if (self.word(DST_Y_INC) as i16) < 0 { 0xf } else { 1 };
+            self.registers[LINE_NUMBER] = (line & 0xf0) | (line.wrapping_add(step) & 0xf);
+            if lines == 0 {
+                self.registers[LINE_NUMBER] &= !BUSY;
+            }
+        }
+    }
+    fn read_byte(&self, address: usize) -> u8 {
+        let offset = address - self.address;
+        match offset {
+            // The X count register counts down while a line is transferred
+            X_COUNT | 0x37 if self.x_left != 0 => (self.x_left as u16).to_be_bytes()[offset - X_COUNT],
+            _ => self.registers[offset],
+        }
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        let offset = address - self.address;
+        self.registers[offset] = value;
+        if offset == LINE_NUMBER && value & BUSY != 0 {
+            if self.word(Y_COUNT) == 0 {
+                self.registers[LINE_NUMBER] &= !BUSY;
+            }
+            // Setting BUSY again makes a BLiTTER sharing the bus continue at once
+            self.resume = 0;
+        }
+    }
+}
+
+impl Device for Blitter {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x3e)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| self.write_byte(a, b));
+        *self.interrupt.borrow_mut() = self.busy();
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        if self.busy() && cycles >= self.resume {
+            Signal::BusRequest
+        } else {
+            Signal::Ok
+        }
+    }
+    fn bus_access(&mut self, bus: &mut Bus) {
+        let end = if self.registers[LINE_NUMBER] & HOG != 0 { u64::MAX } else { bus.cycles + BUS_SHARE };
+        while self.busy() && bus.cycles < end {
+            self.transfer(bus);
+        }
+        bus.bus_error = None;
+        self.resume = bus.cycles + BUS_SHARE;
+        *self.interrupt.borrow_mut() = self.busy();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Ram;
+    use super::*;
+
+    const SOURCE: usize = 0x100;
+    const DESTINATION: usize = 0x200;
+
+    fn word(blitter: &mut Blitter, offset: usize, value: u16) {
+        blitter.write(0xff8a00 + offset, OpResult::Word(value));
+    }
+
+    fn long(blitter: &mut Blitter, offset: usize, value: u32) {
+        blitter.write(0xff8a00 + offset, OpResult::Long(value));
+    }
+
+    // A single line of x_count words from SOURCE to DESTINATION without end masks
+    fn setup(bus: &mut Bus, source: &[u16], destination: &[u16], x_count: u16) -> Box<Blitter> {
+        for (j, value) in source.iter().enumerate() {
+            bus.write(SOURCE + 2 * j, OpResult::Word(*value));
+        }
+        for (j, value) in destination.iter().enumerate() {
+            bus.write(DESTINATION + 2 * j, OpResult::Word(*value));
+        }
+        let mut blitter = Blitter::new(0xff8a00);
+        for j in 0..16 {
+            word(&mut blitter, HALFTONE + 2 * j, 0xaaaa);
+        }
+        word(&mut blitter, SRC_X_INC, 2);
+        word(&mut blitter, SRC_Y_INC, 2);
+        long(&mut blitter, SRC_ADDRESS, SOURCE as u32);
+        for offset in [ENDMASK_1, ENDMASK_2, ENDMASK_3] {
+            word(&mut blitter, offset, 0xffff);
+        }
+        word(&mut blitter, DST_X_INC, 2);
+        word(&mut blitter, DST_Y_INC, 2);
+        long(&mut blitter, DST_ADDRESS, DESTINATION as u32);
+        word(&mut blitter, X_COUNT, x_count);
+        word(&mut blitter, Y_COUNT, 1);
+        blitter
+    }
+
+    fn run(blitter: &mut Blitter, bus: &mut Bus, hop: u8, op: u8, skew: u8) {
+        blitter.write(0xff8a00 + HOP, OpResult::Word((hop as u16) << 8 | op as u16));
+        blitter.write(0xff8a00 + LINE_NUMBER, OpResult::Word(((BUSY | HOG) as u16) << 8 | skew as u16));
+        blitter.bus_access(bus);
+        assert!(!blitter.busy());
+    }
+
+    fn bus() -> Bus {
+        let mut bus = Bus::new();
+        bus.attach(Ram::new(0x1000));
+        bus
+    }
+
+    fn destination(bus: &mut Bus, words: usize) -> Vec<u16> {
+        (0..words).map(|j| bus.read(DESTINATION + 2 * j, Size::Word).inner() as u16).collect()
+    }
+
+    #[test]
+    fn halftone_and_logical_operations() {
+        let (source, old, halftone) = (0x0ff0, 0x3c3c, 0xaaaa);
+        let cases = [
+            (2, 0, 0),
+            (2, 1, source & old),
+            (2, 3, source),
+            (2, 6, source ^ old),
+            (2, 12, !source),
+            (2, 15, 0xffff),
+            (0, 3, 0xffff),
+            (1, 3, halftone),
+            (3, 7, (source & halftone) | old),
+        ];
+        for (hop, op, expected) in cases {
+            let mut bus = bus();
+            let mut blitter = setup(&mut bus, &[source], &[old], 1);
+            run(&mut blitter, &mut bus, hop, op, 0);
+            assert_eq!(destination(&mut bus, 1), [expected], "HOP {} OP {}", hop, op);
+        }
+    }
+
+    #[test]
+    fn skew_and_end_masks() {
+        let mut bus = bus();
+        let mut blitter = setup(&mut bus, &[0x1234, 0x5678], &[0, 0], 2);
+        word(&mut blitter, ENDMASK_1, 0xff00);
+        word(&mut blitter, ENDMASK_3, 0x00ff);
+        run(&mut blitter, &mut bus, 2, 3, 4);
+        assert_eq!(destination(&mut bus, 2), [0x0100, 0x0067]);
+    }
+
+    #[test]
+    fn extra_source_read_fills_the_buffer_first() {
+        let mut bus = bus();
+        let mut blitter = setup(&mut bus, &[0x1234, 0x5678, 0x9abc], &[0, 0], 2);
+        run(&mut blitter, &mut bus, 2, 3, FXSR | 4);
+        assert_eq!(destination(&mut bus, 2), [0x4567, 0x89ab]);
+        assert_eq!(blitter.long(SRC_ADDRESS), SOURCE + 6);
+    }
+
+    #[test]
+    fn no_final_source_read_skips_the_last_word() {
+        let mut bus = bus();
+        let mut blitter = setup(&mut bus, &[0x1234, 0x5678, 0x9abc], &[0, 0], 2);
+        run(&mut blitter, &mut bus, 2, 3, FXSR | NFSR | 4);
+        assert_eq!(destination(&mut bus, 2), [0x4567, 0x4567]);
+        // The Y increment is still added, less the X increment of the read left out
+        assert_eq!(blitter.long(SRC_ADDRESS), SOURCE + 4);
+    }
+
+    #[test]
+    fn clearing_the_line_count_ends_a_shared_blit() {
+        let mut bus = bus();
+        let mut blitter = setup(&mut bus, &[0x1234], &[0], 1);
+        word(&mut blitter, SRC_Y_INC, 0);
+        word(&mut blitter, DST_Y_INC, 0);
+        word(&mut blitter, Y_COUNT, 1000);
+        blitter.write(0xff8a00 + HOP, OpResult::Word(0x0203));
+        blitter.write(0xff8a00 + LINE_NUMBER, OpResult::Byte(BUSY));
+        blitter.bus_access(&mut bus);
+        assert!(blitter.busy());
+        word(&mut blitter, Y_COUNT, 0);
+        blitter.bus_access(&mut bus);
+        assert!(!blitter.busy());
+        assert_eq!(destination(&mut bus, 1), [0x1234]);
+    }
+}
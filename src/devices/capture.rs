@@ -0,0 +1,235 @@
+// Capturing the picture and the sound into host files, e.g. for tests which compare the screens
+// of a machine without display with the expected ones.
+//
+// Screenshots are PNG or PPM images, chosen by the extension of the file. Recordings put the
+// frames into a YUV4MPEG2 (.y4m) video, which video tools such as ffmpeg read directly, and the
+// sound of the PSG into a WAV file of the same name. Both are in emulated time, so they match
+// however fast the emulation runs.
+
+use super::audio::SAMPLE_RATE;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn rgb(pixel: u32) -> [u8; 3] {
+    [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    png.extend(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(kind);
+    png.extend(data);
+    let crc = crc32(&png[start..]);
+    png.extend(&crc.to_be_bytes());
+}
+
+// The image data is stored without compression, which needs no more than zlib's framing
+fn png(pixels: &[u32], width: usize, height: usize) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((3 * width + 1) * height);
+    for row in pixels.chunks(width) {
+        raw.push(0);
+        raw.extend(row.iter().flat_map(|pixel| rgb(*pixel)));
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(0xffff);
+    let count = blocks.len();
+    for (j, block) in blocks.enumerate() {
+        zlib.push((j + 1 == count) as u8);
+        zlib.extend(&(block.len() as u16).to_le_bytes());
+        zlib.extend(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in &raw {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend(&(b << 16 | a).to_be_bytes());
+    let mut header = Vec::new();
+    header.extend(&(width as u32).to_be_bytes());
+    header.extend(&(height as u32).to_be_bytes());
+    // 8 bits per sample, RGB
+    header.extend(&[8, 2, 0, 0, 0]);
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn save_screenshot(path: &str, pixels: &[u32], width: usize, height: usize) -> Result<(), String> {
+    let data = if path.to_ascii_lowercase().ends_with(".png") {
+        png(pixels, width, height)
+    } else {
+        let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        ppm.extend(pixels.iter().flat_map(|pixel| rgb(*pixel)));
+        ppm
+    };
+    std::fs::write(path, data).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Screenshots on demand, asked for by a SIGUSR1 from the host
+static SCREENSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_screenshot(_signal: libc::c_int) {
+    SCREENSHOT_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+pub fn listen_for_screenshot_requests() {
+    unsafe {
+        libc::signal(libc::SIGUSR1, request_screenshot as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub fn screenshot_requested() -> bool {
+    SCREENSHOT_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+pub struct VideoRecording {
+    file: BufWriter<File>,
+    path: String,
+    rate: Option<(u64, u64)>,
+    warned: bool,
+}
+
+impl VideoRecording {
+    pub fn create(path: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Self { file: BufWriter::new(file), path: String::from(path), rate: None, warned: false })
+    }
+    // The sound goes next to the video
+    pub fn sound_path(&self) -> String {
+        match self.path.rsplit_once('.') {
+            Some((stem, _)) => format!("{}.wav", stem),
+            None => format!("{}.wav", self.path),
+        }
+    }
+    // The frame rate, given as a fraction, is the one of the first frame: the file has only one.
+    // Frames at another rate (after a switch between 50, 60 and 71 Hz) are still added, so that
+    // part of the video plays at the wrong speed.
+    pub fn frame(&mut self, pixels: &[u32], width: usize, height: usize, rate: (u64, u64)) {
+        match self.rate {
+            None => {
+                self.rate = Some(rate);
+                writeln!(self.file, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", width, height, rate.0, rate.1).ok();
+            }
+            Some(first) if first.0 * rate.1 != rate.0 * first.1 && !self.warned => {
+                eprintln!("The frame rate changed, frames at another rate than the first play at the wrong speed in {}", self.path);
+                self.warned = true;
+            }
+            _ => {}
+        }
+        let mut planes = vec![0u8; 3 * pixels.len()];
+        for (j, pixel) in pixels.iter().enumerate() {
+            let [r, g, b] = rgb(*pixel).map(|c| c as f32);
+            planes[j] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b) as u8;
+            planes[pixels.len() + j] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b) as u8;
+            planes[2 * pixels.len() + j] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b) as u8;
+        }
+        self.file.write_all(b"FRAME\n").ok();
+        self.file.write_all(&planes).ok();
+    }
+}
+
+// 16 bit mono samples, with the sizes in the header kept up to date after every write
+pub struct SoundRecording {
+    file: File,
+    samples: u32,
+}
+
+impl SoundRecording {
+    pub fn create(path: &str) -> Result<Self, String> {
+        let mut file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut header = b"RIFF\x24\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+        header.extend(&SAMPLE_RATE.to_le_bytes());
+        header.extend(&(2 * SAMPLE_RATE).to_le_bytes());
+        header.extend(b"\x02\0\x10\0data\0\0\0\0");
+        file.write_all(&header).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Self { file, samples: 0 })
+    }
+    pub fn push(&mut self, samples: &[f32]) {
+        let data: Vec<u8> = samples.iter().flat_map(|sample| ((sample.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes()).collect();
+        self.file.write_all(&data).ok();
+        self.samples += samples.len() as u32;
+        let size = 2 * self.samples;
+        self.file.seek(SeekFrom::Start(4)).ok();
+        self.file.write_all(&(size + 36).to_le_bytes()).ok();
+        self.file.seek(SeekFrom::Start(40)).ok();
+        self.file.write_all(&size.to_le_bytes()).ok();
+        self.file.seek(SeekFrom::End(0)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    // Undoes the stored blocks of the zlib stream in the IDAT chunk
+    fn image_data(png: &[u8]) -> Vec<u8> {
+        let mut chunk = 8;
+        while &png[chunk + 4..chunk + 8] != b"IDAT" {
+            chunk += 12 + u32::from_be_bytes(png[chunk..chunk + 4].try_into().unwrap()) as usize;
+        }
+        let mut position = chunk + 10;
+        let mut data = Vec::new();
+        loop {
+            let last = png[position] & 1 != 0;
+            let length = u16::from_le_bytes([png[position + 1], png[position + 2]]) as usize;
+            assert_eq!(!length as u16, u16::from_le_bytes([png[position + 3], png[position + 4]]));
+            data.extend(&png[position + 5..position + 5 + length]);
+            position += 5 + length;
+            if last {
+                return data;
+            }
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+    }
+
+    #[test]
+    fn png_header_and_chunks() {
+        let png = png(&[0xff0000, 0x00ff00, 0x0000ff, 0xffffff], 2, 2);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        assert_eq!(u32::from_be_bytes(png[29..33].try_into().unwrap()), crc32(&png[12..29]));
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+        assert_eq!(image_data(&png), [0, 0xff, 0, 0, 0, 0xff, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn png_splits_large_images_into_blocks() {
+        let pixels: Vec<u32> = (0..100 * 300).map(|j| j * 0x010203).collect();
+        let png = png(&pixels, 100, 300);
+        let data = image_data(&png);
+        assert_eq!(data.len(), 301 * 300);
+        for (y, row) in data.chunks(301).enumerate() {
+            assert_eq!(row[0], 0);
+            assert_eq!(row[1..4], rgb(pixels[100 * y]));
+        }
+        // Adler-32 of the data closes the zlib stream
+        let idat_end = png.len() - 12 - 4;
+        let (a, b) = data
+            .iter()
+            .fold((1u32, 0u32), |(a, b), byte| ((a + *byte as u32) % 65521, (b + (a + *byte as u32) % 65521) % 65521));
+        assert_eq!(png[idat_end - 4..idat_end], (b << 16 | a).to_be_bytes());
+    }
+}
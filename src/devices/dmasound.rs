@@ -0,0 +1,418 @@
+// The STE's DMA sound. It plays 8 bit signed samples, mono or stereo (left and right byte in
+// turn), from the frame between the start and end address at one of four sample rates derived
+// from the 8 MHz clock. At the end of a frame it either stops or, in loop mode, starts over at the
+// frame start, which may have been changed in the meantime. While playing, its active signal is
+// pulled, which the MFP sees on GPIP 7 and counts with Timer A.
+//
+// The output goes through a National LMC1992 volume and tone controller, which also mixes in
+// the sound chip. It is set up via the Microwire interface, a serial port shifting out the bits
+// of the data register selected by the mask register. The LMC1992 listens to commands starting
+// with its address %10, followed by three bits selecting the setting and six bits of value.
+
+use super::audio::{AudioChannel, Biquad, SAMPLE_RATE};
+use super::{read_bytes, signal_line, write_bytes, Device, Signal, SignalLine, CPU_FREQUENCY};
+use crate::fields::{OpResult, Size};
+use crate::memory::{Bus, MemoryRange};
+use crate::processor::IRQ;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const CONTROL: usize = 0x01;
+const FRAME_START: usize = 0x03;
+const FRAME_COUNTER: usize = 0x09;
+const FRAME_END: usize = 0x0f;
+const MODE: usize = 0x21;
+
+// Control register
+const PLAY: u8 = 0x1;
+const LOOP: u8 = 0x2;
+// Mode register
+const MONO: u8 = 0x80;
+
+// CPU cycles per sample at 50066 Hz, the lower rates take twice as long each
+const CYCLES_PER_SAMPLE: u64 = 160;
+const SAMPLES_PER_PUSH: usize = 512;
+const BASS_FREQUENCY: f32 = 118.0;
+const TREBLE_FREQUENCY: f32 = 8900.0;
+
+// Microwire
+const DATA: usize = 0x00;
+const MASK: usize = 0x02;
+const CYCLES_PER_BIT: u64 = 8;
+const LMC1992: u16 = 0b10;
+
+pub type Mixer = Rc<RefCell<LMC1992Settings>>;
+
+// Volumes are gains, bass and treble in dB
+pub struct LMC1992Settings {
+    pub master: f32,
+    pub left: f32,
+    pub right: f32,
+    pub bass: f32,
+    pub treble: f32,
+    pub mix: u16,
+}
+
+impl LMC1992Settings {
+    fn command(&mut self, function: u16, value: u16) {
+        let gain = |steps: u16, max: u16| 10f32.powf((steps.min(max) as f32 - max as f32) * 2.0 / 20.0);
+        match function {
+            0 => self.mix = value & 0x3,
+            1 => self.bass = (value.min(12) as f32 - 6.0) * 2.0,
+            2 => self.treble = (value.min(12) as f32 - 6.0) * 2.0,
+            3 => self.master = gain(value, 40),
+            4 => self.right = gain(value, 20),
+            5 => self.left = gain(value, 20),
+            _ => {}
+        }
+    }
+    // The sound chip is mixed in attenuated by 12 dB, at full volume or not at all
+    pub fn psg_gain(&self) -> f32 {
+        let mix = match self.mix {
+            0 => 0.25,
+            1 => 1.0,
+            _ => 0.0,
+        };
+        mix * self.master * (self.left + self.right) / 2.0
+    }
+}
+
+pub struct DMASoundSystem {
+    address: usize,
+    registers: [u8; 0x22],
+    counter: usize,
+    end: usize,
+    cycles: u64,
+    next_sample: u64,
+    // The last two samples, which the output is interpolated between
+    previous: (f32, f32),
+    current: (f32, f32),
+    host_samples: u64,
+    samples: Vec<f32>,
+    tone: (f32, f32),
+    filters: [Biquad; 4],
+    output: Option<AudioChannel>,
+    pub sound: bool,
+    pub active: SignalLine,
+    pub mixer: Mixer,
+}
+
+impl DMASoundSystem {
+    pub fn new(address: usize) -> Box<Self> {
+        let mixer = LMC1992Settings { master: 1.0, left: 1.0, right: 1.0, bass: 0.0, treble: 0.0, mix: 1 };
+        Box::new(Self {
+            address,
+            registers: [0; 0x22],
+            counter: 0,
+            end: 0,
+            cycles: 0,
+            next_sample: 0,
+            previous: (0.0, 0.0),
+            current: (0.0, 0.0),
+            host_samples: 0,
+            samples: Vec::with_capacity(SAMPLES_PER_PUSH),
+            tone: (0.0, 0.0),
+            filters: Self::tone_filters(0.0, 0.0),
+            output: None,
+            sound: true,
+            active: signal_line(),
+            mixer: Rc::new(RefCell::new(mixer)),
+        })
+    }
+    fn tone_filters(bass: f32, treble: f32) -> [Biquad; 4] {
+        let rate = SAMPLE_RATE as f32;
+        [
+            Biquad::shelf(BASS_FREQUENCY, bass, false, rate),
+            Biquad::shelf(TREBLE_FREQUENCY, treble, true, rate),
+            Biquad::shelf(BASS_FREQUENCY, bass, false, rate),
+            Biquad::shelf(TREBLE_FREQUENCY, treble, true, rate),
+        ]
+    }
+    fn address_register(&self, offset: usize) -> usize {
+        (self.registers[offset] as usize) << 16 | (self.registers[offset + 2] as usize) << 8 | (self.registers[offset + 4] & 0xfe) as usize
+    }
+    fn playing(&self) -> bool {
+        self.registers[CONTROL] & PLAY != 0
+    }
+    fn sample_period(&self) -> u64 {
+        CYCLES_PER_SAMPLE << (3 - (self.registers[MODE] & 0x3))
+    }
+    fn start_frame(&mut self) {
+        self.counter = self.address_register(FRAME_START);
+        self.end = self.address_register(FRAME_END);
+    }
+    fn read_byte(&self, address: usize) -> u8 {
+        let offset = address - self.address;
+        match offset {
+            0x09 | 0x0b | 0x0d => (self.counter >> (8 * (2 - (offset - FRAME_COUNTER) / 2))) as u8,
+            _ => self.registers[offset],
+        }
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        let offset = address - self.address;
+        let playing = self.playing();
+        match offset {
+            CONTROL => self.registers[CONTROL] = value & (PLAY | LOOP),
+            FRAME_COUNTER..=0x0d => {}
+            MODE => self.registers[MODE] = value & (MONO | 0x3),
+            _ => self.registers[offset] = value,
+        }
+        if self.playing() && !playing {
+            self.start_frame();
+            self.next_sample = self.cycles;
+        }
+        *self.active.borrow_mut() = self.playing();
+    }
+    // Plays the next sample, reading it from memory if there is a bus to read from
+    fn next(&mut self, bus: Option<&mut Bus>) {
+        let channels = if self.registers[MODE] & MONO != 0 { 1 } else { 2 };
+        if let Some(bus) = bus {
+            let mut read = |address| bus.read(address, Size::Byte).inner() as u8 as i8 as f32 / 128.0;
+            let left = read(self.counter);
+            let right = if channels == 2 { read(self.counter + 1) } else { left };
+            self.previous = self.current;
+            self.current = (left, right);
+        }
+        self.counter += channels;
+        self.next_sample += self.sample_period();
+        if self.counter >= self.end {
+            // In loop mode the active signal drops until the next clock, marking the frame's end
+            *self.active.borrow_mut() = false;
+            if self.registers[CONTROL] & LOOP != 0 {
+                self.start_frame();
+            } else {
+                self.registers[CONTROL] &= !PLAY;
+                self.current = (0.0, 0.0);
+            }
+        }
+    }
+    fn generate(&mut self, cycles: u64) {
+        let sample_period = self.sample_period();
+        loop {
+            let time = self.host_samples * CPU_FREQUENCY / SAMPLE_RATE as u64;
+            if time >= cycles {
+                break;
+            }
+            // Linear interpolation between the two most recent samples
+            let elapsed = (time + sample_period).saturating_sub(self.next_sample).min(sample_period);
+            let t = elapsed as f32 / sample_period as f32;
+            let left = self.previous.0 + (self.current.0 - self.previous.0) * t;
+            let right = self.previous.1 + (self.current.1 - self.previous.1) * t;
+            self.samples.extend([left, right]);
+            self.host_samples += 1;
+        }
+        if self.samples.len() >= SAMPLES_PER_PUSH {
+            let mixer = self.mixer.borrow();
+            if self.tone != (mixer.bass, mixer.treble) {
+                self.tone = (mixer.bass, mixer.treble);
+                self.filters = Self::tone_filters(mixer.bass, mixer.treble);
+            }
+            let gains = [mixer.master * mixer.left, mixer.master * mixer.right];
+            for frame in self.samples.chunks_mut(2) {
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let bass = self.filters[2 * channel].process(*sample);
+                    *sample = self.filters[2 * channel + 1].process(bass) * gains[channel];
+                }
+            }
+            if let Some(output) = self.output.as_mut() {
+                output.push(&self.samples);
+            }
+            self.samples.clear();
+        }
+    }
+}
+
+impl Device for DMASoundSystem {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x22)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| self.write_byte(a, b));
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        if self.sound && self.output.is_none() {
+            match AudioChannel::open(2) {
+                Ok(output) => self.output = Some(output),
+                Err(error) => {
+                    eprintln!("Unable to play DMA sound ({}), continuing without", error);
+                    self.sound = false;
+                }
+            }
+        }
+        *self.active.borrow_mut() = self.playing();
+        let due = self.playing() && self.next_sample <= cycles;
+        if due && self.sound {
+            self.cycles = cycles;
+            return Signal::BusRequest;
+        }
+        // Without an output the samples need not be read, but the frame still has to be played
+        while self.playing() && self.next_sample <= cycles {
+            self.next(None);
+        }
+        if self.sound {
+            self.generate(cycles);
+        }
+        self.cycles = cycles;
+        Signal::Ok
+    }
+    fn bus_access(&mut self, bus: &mut Bus) {
+        let cycles = bus.cycles;
+        while self.playing() && self.next_sample <= self.cycles {
+            self.generate(self.next_sample);
+            self.next(Some(bus));
+        }
+        self.generate(self.cycles);
+        bus.bus_error = None;
+        bus.cycles = cycles;
+    }
+}
+
+pub struct Microwire {
+    address: usize,
+    data: u16,
+    mask: u16,
+    cycles: u64,
+    // Cycle count at which the current transfer started
+    started: Option<u64>,
+    mixer: Mixer,
+}
+
+impl Microwire {
+    pub fn new(address: usize, mixer: Mixer) -> Box<Self> {
+        Box::new(Self { address, data: 0, mask: 0, cycles: 0, started: None, mixer })
+    }
+    // During a transfer both registers rotate left by one bit per bit shifted out
+    fn shifted(&self) -> u32 {
+        match self.started {
+            Some(started) => ((self.cycles - started) / CYCLES_PER_BIT).min(16) as u32 % 16,
+            None => 0,
+        }
+    }
+    fn transfer(&mut self) {
+        let mut command = 0u16;
+        let mut bits = 0;
+        for bit in (0..16).rev() {
+            if self.mask & (1 << bit) != 0 {
+                command = command << 1 | (self.data >> bit) & 1;
+                bits += 1;
+            }
+        }
+        if bits >= 11 && (command >> 9) & 0x3 == LMC1992 {
+            self.mixer.borrow_mut().command((command >> 6) & 0x7, command & 0x3f);
+        }
+    }
+}
+
+impl Device for Microwire {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x4)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        let shifted = self.shifted();
+        let registers = [self.data.rotate_left(shifted), self.mask.rotate_left(shifted)];
+        read_bytes(address, size, |a| {
+            let offset = a - self.address;
+            registers[offset / 2].to_be_bytes()[offset % 2]
+        })
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        let mut registers = [self.data.to_be_bytes(), self.mask.to_be_bytes()];
+        write_bytes(address, result, |a, b| {
+            let offset = a - self.address;
+            registers[offset / 2][offset % 2] = b;
+        });
+        self.mask = u16::from_be_bytes(registers[MASK / 2]);
+        let data = u16::from_be_bytes(registers[DATA / 2]);
+        // Writing the data register starts a transfer, unless one is running
+        if address - self.address <= DATA + 1 && self.started.is_none() {
+            self.data = data;
+            self.started = Some(self.cycles);
+            self.transfer();
+        }
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        self.cycles = cycles;
+        if let Some(started) = self.started {
+            if cycles - started >= 16 * CYCLES_PER_BIT {
+                self.started = None;
+            }
+        }
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn microwire() -> (Box<Microwire>, Mixer) {
+        let mixer = DMASoundSystem::new(0xff8900).mixer;
+        (Microwire::new(0xff8922, Rc::clone(&mixer)), mixer)
+    }
+
+    // Address, function and value of an LMC1992 command
+    fn command(function: u16, value: u16) -> u16 {
+        LMC1992 << 9 | function << 6 | value
+    }
+
+    fn send(microwire: &mut Microwire, mask: u16, data: u16) {
+        microwire.write(0xff8924, OpResult::Word(mask));
+        microwire.write(0xff8922, OpResult::Word(data));
+        microwire.clock(microwire.cycles + 16 * CYCLES_PER_BIT);
+    }
+
+    #[test]
+    fn lmc1992_commands() {
+        let (mut microwire, mixer) = microwire();
+        // Master volume at 20 of 40 steps
+        send(&mut microwire, 0x07ff, command(3, 20));
+        assert!((mixer.borrow().master - 0.01).abs() < 1e-6);
+        // The command bits are the ones selected by the mask, wherever they are
+        send(&mut microwire, 0xffe0, command(5, 10) << 5 | 0x1f);
+        assert!((mixer.borrow().left - 0.1).abs() < 1e-6);
+        send(&mut microwire, 0x07ff, command(1, 12));
+        assert_eq!(mixer.borrow().bass, 12.0);
+        send(&mut microwire, 0x07ff, command(0, 2));
+        assert_eq!(mixer.borrow().mix, 2);
+    }
+
+    #[test]
+    fn other_addresses_and_short_commands_are_ignored() {
+        let (mut microwire, mixer) = microwire();
+        send(&mut microwire, 0x07ff, command(3, 0) ^ 0x600);
+        send(&mut microwire, 0x03ff, command(3, 0));
+        assert_eq!(mixer.borrow().master, 1.0);
+    }
+
+    #[test]
+    fn registers_rotate_while_shifting_out() {
+        let (mut microwire, _) = microwire();
+        microwire.write(0xff8924, OpResult::Word(0x07ff));
+        microwire.write(0xff8922, OpResult::Word(0x1234));
+        microwire.clock(4 * CYCLES_PER_BIT);
+        assert_eq!(microwire.read(0xff8922, Size::Word).inner(), 0x2341);
+        assert_eq!(microwire.read(0xff8924, Size::Word).inner(), 0x7ff0);
+        // Writes during a transfer do not start another one
+        microwire.write(0xff8922, OpResult::Word(command(3, 40)));
+        microwire.clock(16 * CYCLES_PER_BIT);
+        assert_eq!(microwire.read(0xff8922, Size::Word).inner(), 0x1234);
+        assert_eq!(microwire.read(0xff8924, Size::Word).inner(), 0x07ff);
+    }
+}
@@ -0,0 +1,1075 @@
+// The floppy disk subsystem of the ST: the DMA chip at $FF8604-$FF860D, the WD1772 floppy disk
+// controller (FDC) sitting behind it and two double sided drives. The drive and side are selected
+// through port A of the sound chip, the FDC interrupt is wired to GPIP bit 5 of the MFP. The DMA
+// chip also serves the hard disks on the ACSI bus (see the acsi module).
+//
+// Timing follows the real hardware: the disk spins at 300 rpm, data passes the head at 250 kbit/s,
+// and every command waits for the head to step, settle and for the wanted sector to come around.
+// Disk images are raw sector dumps (.st files); the disk geometry is taken from the boot sector and
+// the raw track layout including address marks and CRCs is synthesized from it on the fly.
+
+use super::acsi::{AcsiBus, HardDisk};
+use super::{read_bytes, signal_line, write_bytes, Device, Port, Signal, SignalLine, CPU_FREQUENCY};
+use crate::fields::{OpResult, Size};
+use crate::memory::{Bus, MemoryRange};
+use crate::processor::IRQ;
+use std::collections::VecDeque;
+use std::fs;
+use std::rc::Rc;
+
+const CYCLES_PER_MS: u64 = CPU_FREQUENCY / 1000;
+const CYCLES_PER_REVOLUTION: u64 = 200 * CYCLES_PER_MS;
+const CYCLES_PER_BYTE: u64 = 256;
+const INDEX_PULSE_LENGTH: u64 = 4 * CYCLES_PER_MS;
+const SETTLE_DELAY: u64 = 15 * CYCLES_PER_MS;
+const STEP_RATES: [u64; 4] = [6, 12, 2, 3];
+const SPIN_UP_REVOLUTIONS: usize = 6;
+const MOTOR_OFF_REVOLUTIONS: usize = 10;
+const SEARCH_REVOLUTIONS: usize = 5;
+const RESTORE_STEPS: usize = 255;
+const MAX_CYLINDER: usize = 85;
+const TRACK_LENGTH: usize = 6250;
+const SECTOR_SIZE: usize = 512;
+const FIFO_SIZE: usize = 16;
+
+// FDC status register
+const BUSY: u8 = 0x01;
+const INDEX: u8 = 0x02;
+const DATA_REQUEST: u8 = 0x02;
+const TRACK_ZERO: u8 = 0x04;
+const LOST_DATA: u8 = 0x04;
+const SEEK_ERROR: u8 = 0x10;
+const RECORD_NOT_FOUND: u8 = 0x10;
+const SPIN_UP: u8 = 0x20;
+const WRITE_PROTECT: u8 = 0x40;
+const MOTOR_ON: u8 = 0x80;
+
+// FDC command flags
+const UPDATE_TRACK: u8 = 0x10;
+const MULTIPLE_SECTORS: u8 = 0x10;
+const NO_SPIN_UP: u8 = 0x08;
+const SETTLE: u8 = 0x04;
+const VERIFY: u8 = 0x04;
+const INTERRUPT_ON_INDEX: u8 = 0x04;
+const IMMEDIATE_INTERRUPT: u8 = 0x08;
+
+// DMA mode register
+const MODE_A0: u16 = 0x02;
+const MODE_REGISTER_SELECT: u16 = 0x06;
+const MODE_HDC: u16 = 0x08;
+const MODE_SECTOR_COUNT: u16 = 0x10;
+const MODE_DMA_OFF: u16 = 0x40;
+const MODE_FDC: u16 = 0x80;
+const MODE_WRITE: u16 = 0x100;
+
+pub struct DiskImage {
+    data: Vec<u8>,
+    tracks: usize,
+    sides: usize,
+    sectors: usize,
+    pub write_protected: bool,
+}
+
+impl DiskImage {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Self::from(data))
+    }
+    pub fn from(data: Vec<u8>) -> Self {
+        let total = data.len() / SECTOR_SIZE;
+        let word = |offset: usize| data.get(offset).map_or(0, |&b| b as usize) | data.get(offset + 1).map_or(0, |&b| (b as usize) << 8);
+        let mut geometry = (word(0x18), word(0x1a));
+        let valid = |(sectors, sides): (usize, usize)| {
+            sectors > 0 && sectors <= 11 && sides > 0 && sides <= 2 && total.is_multiple_of(sectors * sides)
+        };
+        // Boot sectors of some images do not contain a sensible BPB, so we fall back on the image size
+        if !valid(geometry) {
+            let candidates = [(9, 2), (9, 1), (10, 2), (10, 1), (11, 2), (11, 1)];
+            geometry = *candidates
+                .iter()
+                .find(|&&(sectors, sides)| valid((sectors, sides)) && total / (sectors * sides) <= MAX_CYLINDER + 1)
+                .unwrap_or(&(9, 2));
+        }
+        let (sectors, sides) = geometry;
+        Self { tracks: total / (sectors * sides), sides, sectors, data, write_protected: false }
+    }
+    fn offset(&self, track: usize, side: usize, sector: u8) -> Option<usize> {
+        if track >= self.tracks || side >= self.sides || sector == 0 || sector as usize > self.sectors {
+            return None;
+        }
+        Some(((track * self.sides + side) * self.sectors + sector as usize - 1) * SECTOR_SIZE)
+    }
+    fn read_sector(&self, track: usize, side: usize, sector: u8) -> Option<&[u8]> {
+        self.offset(track, side, sector).map(|offset| &self.data[offset..offset + SECTOR_SIZE])
+    }
+    fn write_sector(&mut self, track: usize, side: usize, sector: u8, data: &[u8]) {
+        if let Some(offset) = self.offset(track, side, sector) {
+            self.data[offset..offset + SECTOR_SIZE].copy_from_slice(&data[..SECTOR_SIZE]);
+        }
+    }
+    // Synthesizes the raw contents of a track in the standard ST format, i.e. what the FDC would
+    // see passing under the head, starting at the index hole.
+    fn raw_track(&self, track: usize, side: usize) -> Track {
+        let mut raw = Vec::with_capacity(TRACK_LENGTH);
+        let mut ids = Vec::new();
+        if track < self.tracks && side < self.sides {
+            let gap1 = if self.sectors > 10 { 10 } else { 60 };
+            let slot = (TRACK_LENGTH - gap1) / self.sectors;
+            let sync = if slot >= 574 { 12 } else { 3 };
+            raw.resize(gap1, 0x4e);
+            for sector in 1..=self.sectors as u8 {
+                let start = raw.len();
+                raw.resize(start + sync, 0x00);
+                let id = [0xa1, 0xa1, 0xa1, 0xfe, track as u8, side as u8, sector, 2];
+                let position = raw.len() + 3;
+                raw.extend_from_slice(&id);
+                raw.extend_from_slice(&crc16(&id).to_be_bytes());
+                raw.resize(raw.len() + 22, 0x4e);
+                raw.resize(raw.len() + sync, 0x00);
+                let mark = [0xa1, 0xa1, 0xa1, 0xfb];
+                let data = self.read_sector(track, side, sector).unwrap();
+                let mut crc = mark.to_vec();
+                crc.extend_from_slice(data);
+                raw.extend_from_slice(&mark);
+                ids.push(IdField { track: track as u8, side: side as u8, sector, position });
+                raw.extend_from_slice(data);
+                raw.extend_from_slice(&crc16(&crc).to_be_bytes());
+                raw.resize(start + slot, 0x4e);
+            }
+        }
+        raw.resize(TRACK_LENGTH, 0x4e);
+        Track { raw, ids }
+    }
+    // Extracts the sectors from the byte stream written by a Write Track command. $F5 bytes are
+    // written as $A1 sync marks by the FDC and precede the ID and data address marks.
+    fn format_track(&mut self, track: usize, side: usize, raw: &[u8]) {
+        let mut id = None;
+        let mut j = 0;
+        while j < raw.len() {
+            if raw[j] != 0xf5 {
+                j += 1;
+                continue;
+            }
+            while j < raw.len() && raw[j] == 0xf5 {
+                j += 1;
+            }
+            match raw.get(j) {
+                Some(0xfe) if j + 4 < raw.len() => {
+                    id = Some((raw[j + 3], raw[j + 4]));
+                    j += 5;
+                }
+                Some(0xfb) | Some(0xf8) => {
+                    if let Some((sector, size)) = id.take() {
+                        let length = 128 << (size & 0x3);
+                        if length == SECTOR_SIZE && j + 1 + length <= raw.len() {
+                            self.write_sector(track, side, sector, &raw[j + 1..j + 1 + length]);
+                        }
+                        j += length;
+                    }
+                    j += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+struct IdField {
+    track: u8,
+    side: u8,
+    sector: u8,
+    position: usize,
+}
+
+impl IdField {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.track, self.side, self.sector, 2];
+        let crc = crc16(&[0xa1, 0xa1, 0xa1, 0xfe, self.track, self.side, self.sector, 2]);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes
+    }
+}
+
+struct Track {
+    raw: Vec<u8>,
+    ids: Vec<IdField>,
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+struct Drive {
+    image: Option<DiskImage>,
+    cylinder: usize,
+    track: Option<(usize, usize, Track)>,
+}
+
+impl Drive {
+    fn new(image: Option<DiskImage>) -> Self {
+        Self { image, cylinder: 0, track: None }
+    }
+    fn track(&mut self, side: usize) -> Option<&Track> {
+        let cylinder = self.cylinder;
+        let image = self.image.as_ref()?;
+        match &self.track {
+            Some((c, s, _)) if *c == cylinder && *s == side => {}
+            _ => self.track = Some((cylinder, side, image.raw_track(cylinder, side))),
+        }
+        self.track.as_ref().map(|(_, _, track)| track)
+    }
+    fn step(&mut self, outwards: bool) {
+        if outwards {
+            self.cylinder = self.cylinder.saturating_sub(1);
+        } else if self.cylinder < MAX_CYLINDER {
+            self.cylinder += 1;
+        }
+    }
+    fn write_protected(&self) -> bool {
+        self.image.as_ref().is_some_and(|image| image.write_protected)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Phase {
+    Idle,
+    SpinUp,
+    Step,
+    Settle,
+    Verify,
+    FindSector,
+    ReadSector(usize),
+    WriteSector(usize),
+    ReadAddress(usize),
+    WaitIndex,
+    ReadTrack(usize),
+    WriteTrack(usize),
+}
+
+struct WD1772 {
+    command: u8,
+    status: u8,
+    track: u8,
+    sector: u8,
+    data: u8,
+    data_request: bool,
+    outwards: bool,
+    steps: usize,
+    motor: bool,
+    phase: Phase,
+    event: u64,
+    index_pulses: usize,
+    idle_revolutions: usize,
+    interrupt_on_index: bool,
+    forced_interrupt: bool,
+    type_one_status: bool,
+    buffer: Vec<u8>,
+}
+
+impl WD1772 {
+    fn new() -> Self {
+        Self {
+            command: 0,
+            status: 0,
+            track: 0,
+            sector: 1,
+            data: 0,
+            data_request: false,
+            outwards: true,
+            steps: 0,
+            motor: false,
+            phase: Phase::Idle,
+            event: u64::MAX,
+            index_pulses: 0,
+            idle_revolutions: 0,
+            interrupt_on_index: false,
+            forced_interrupt: false,
+            type_one_status: true,
+            buffer: Vec::new(),
+        }
+    }
+    fn step_rate(&self) -> u64 {
+        STEP_RATES[self.command as usize & 0x3] * CYCLES_PER_MS
+    }
+}
+
+pub(super) struct DMA {
+    mode: u16,
+    address: u32,
+    pub(super) sector_count: u16,
+    pub(super) fifo: VecDeque<u8>,
+    transferred: usize,
+}
+
+impl DMA {
+    fn new() -> Self {
+        Self { mode: 0, address: 0, sector_count: 0, fifo: VecDeque::with_capacity(FIFO_SIZE), transferred: 0 }
+    }
+    fn reset(&mut self) {
+        self.fifo.clear();
+        self.sector_count = 0;
+        self.transferred = 0;
+    }
+    fn serves_fdc(&self) -> bool {
+        self.mode & (MODE_FDC | MODE_DMA_OFF) == MODE_FDC
+    }
+    pub(super) fn serves_hdc(&self) -> bool {
+        self.mode & (MODE_FDC | MODE_DMA_OFF) == 0
+    }
+    pub(super) fn writing(&self) -> bool {
+        self.mode & MODE_WRITE != 0
+    }
+    pub(super) fn accepts_data(&self) -> bool {
+        self.fifo.len() < FIFO_SIZE && self.sector_count > 0
+    }
+    fn wants_bus(&self) -> bool {
+        if self.writing() {
+            self.fifo.is_empty() && self.sector_count > 0
+        } else {
+            self.fifo.len() >= FIFO_SIZE
+        }
+    }
+    fn count(&mut self, bytes: usize) {
+        self.transferred += bytes;
+        if self.transferred >= SECTOR_SIZE {
+            self.transferred -= SECTOR_SIZE;
+            self.sector_count = self.sector_count.saturating_sub(1);
+        }
+    }
+    fn status(&self, data_request: bool) -> u8 {
+        // Bit 0 is the (active low) DMA error flag
+        0x01 | ((self.sector_count != 0) as u8) << 1 | (data_request as u8) << 2
+    }
+}
+
+pub struct Floppy {
+    address: usize,
+    dma: DMA,
+    fdc: WD1772,
+    drives: [Drive; 2],
+    drive_select: Port,
+    acsi: AcsiBus,
+    pub interrupt: SignalLine,
+    pub hdc_interrupt: SignalLine,
+    cycles: u64,
+}
+
+impl Floppy {
+    pub fn new(address: usize, image: Option<DiskImage>, drive_select: Port) -> Box<Self> {
+        let hdc_interrupt = signal_line();
+        Box::new(Self {
+            address,
+            dma: DMA::new(),
+            fdc: WD1772::new(),
+            drives: [Drive::new(image), Drive::new(None)],
+            drive_select,
+            acsi: AcsiBus::new(Rc::clone(&hdc_interrupt)),
+            interrupt: signal_line(),
+            hdc_interrupt,
+            cycles: 0,
+        })
+    }
+    pub fn insert(&mut self, drive: usize, image: DiskImage) {
+        self.drives[drive] = Drive::new(Some(image));
+    }
+    pub fn eject(&mut self, drive: usize) -> Option<DiskImage> {
+        self.drives[drive].track = None;
+        self.drives[drive].image.take()
+    }
+    pub fn attach_hard_disk(&mut self, target: usize, disk: HardDisk) {
+        self.acsi.attach(target, disk);
+    }
+    // Port A of the sound chip: bit 0 selects the side, bits 1 and 2 drives A and B (all active low)
+    fn selected_drive(&self) -> Option<usize> {
+        let select = *self.drive_select.borrow();
+        if select & 0x02 == 0 {
+            Some(0)
+        } else if select & 0x04 == 0 {
+            Some(1)
+        } else {
+            None
+        }
+    }
+    fn side(&self) -> usize {
+        (!*self.drive_select.borrow() & 0x01) as usize
+    }
+    fn drive(&mut self) -> Option<&mut Drive> {
+        let drive = self.selected_drive()?;
+        Some(&mut self.drives[drive])
+    }
+    fn spinning(&self) -> bool {
+        self.fdc.motor && self.selected_drive().is_some_and(|drive| self.drives[drive].image.is_some())
+    }
+    // Position of the head relative to the start of the track in bytes
+    fn position(&self, cycles: u64) -> usize {
+        ((cycles % CYCLES_PER_REVOLUTION) / CYCLES_PER_BYTE) as usize
+    }
+    // Number of cycles until the given track position passes under the head
+    fn time_to(&self, position: usize) -> u64 {
+        let current = self.position(self.cycles);
+        let bytes = (position + TRACK_LENGTH - current) % TRACK_LENGTH;
+        (bytes.max(1) as u64) * CYCLES_PER_BYTE
+    }
+    fn interrupt(&mut self, value: bool) {
+        *self.interrupt.borrow_mut() = value;
+    }
+    fn finish(&mut self) {
+        self.fdc.status &= !BUSY;
+        self.fdc.phase = Phase::Idle;
+        self.fdc.event = u64::MAX;
+        self.fdc.idle_revolutions = 0;
+        self.interrupt(true);
+    }
+    fn status(&mut self) -> u8 {
+        let mut status = self.fdc.status & !MOTOR_ON;
+        if self.fdc.motor {
+            status |= MOTOR_ON;
+        }
+        if self.fdc.type_one_status {
+            status &= !(INDEX | TRACK_ZERO | WRITE_PROTECT);
+            let index = self.cycles % CYCLES_PER_REVOLUTION < INDEX_PULSE_LENGTH;
+            if self.spinning() && index {
+                status |= INDEX;
+            }
+            if let Some(drive) = self.drive() {
+                if drive.cylinder == 0 {
+                    status |= TRACK_ZERO;
+                }
+                if drive.write_protected() {
+                    status |= WRITE_PROTECT;
+                }
+            }
+        } else {
+            status &= !DATA_REQUEST;
+            if self.fdc.data_request {
+                status |= DATA_REQUEST;
+            }
+        }
+        if !self.fdc.forced_interrupt {
+            self.interrupt(false);
+        }
+        status
+    }
+    fn command(&mut self, command: u8) {
+        if command & 0xf0 == 0xd0 {
+            self.force_interrupt(command);
+            return;
+        }
+        if self.fdc.status & BUSY != 0 {
+            return;
+        }
+        self.interrupt(false);
+        self.fdc.forced_interrupt = false;
+        self.fdc.interrupt_on_index = false;
+        self.fdc.command = command;
+        self.fdc.status = BUSY;
+        self.fdc.data_request = false;
+        self.fdc.type_one_status = command & 0x80 == 0;
+        self.fdc.index_pulses = 0;
+        self.fdc.idle_revolutions = 0;
+        if command & NO_SPIN_UP == 0 && !self.fdc.motor {
+            self.fdc.motor = true;
+            self.fdc.phase = Phase::SpinUp;
+            self.fdc.event = u64::MAX;
+        } else {
+            self.fdc.motor = true;
+            self.execute();
+        }
+    }
+    fn execute(&mut self) {
+        let command = self.fdc.command;
+        if self.fdc.type_one_status {
+            self.fdc.status |= SPIN_UP;
+        }
+        self.fdc.phase = Phase::Step;
+        self.fdc.event = self.cycles;
+        self.fdc.steps = 0;
+        match command >> 4 {
+            0x0 => {
+                self.fdc.track = 0xff;
+                self.fdc.data = 0;
+            }
+            0x1 => {}
+            0x2..=0x7 => match command >> 5 {
+                0x2 => self.fdc.outwards = false,
+                0x3 => self.fdc.outwards = true,
+                _ => {}
+            },
+            0xa | 0xb | 0xf if self.drive().is_some_and(|drive| drive.write_protected()) => {
+                self.fdc.status |= WRITE_PROTECT;
+                self.finish();
+            }
+            0x8..=0xf => {
+                self.fdc.phase = Phase::Settle;
+                self.fdc.event = self.cycles + if command & SETTLE != 0 { SETTLE_DELAY } else { 0 };
+            }
+            _ => {}
+        }
+    }
+    fn force_interrupt(&mut self, command: u8) {
+        if self.fdc.status & BUSY != 0 {
+            self.fdc.status &= !BUSY;
+        } else {
+            self.fdc.status = 0;
+            self.fdc.type_one_status = true;
+        }
+        self.fdc.phase = Phase::Idle;
+        self.fdc.event = u64::MAX;
+        self.fdc.data_request = false;
+        self.fdc.idle_revolutions = 0;
+        self.fdc.interrupt_on_index = command & INTERRUPT_ON_INDEX != 0;
+        self.fdc.forced_interrupt = command & IMMEDIATE_INTERRUPT != 0;
+        self.interrupt(self.fdc.forced_interrupt);
+    }
+    fn step(&mut self) {
+        let outwards = self.fdc.outwards;
+        if self.fdc.command & 0xe0 == 0 || self.fdc.command & UPDATE_TRACK != 0 {
+            self.fdc.track = if outwards { self.fdc.track.wrapping_sub(1) } else { self.fdc.track.wrapping_add(1) };
+        }
+        if let Some(drive) = self.drive() {
+            drive.step(outwards);
+        }
+    }
+    fn index_pulse(&mut self) {
+        if self.fdc.interrupt_on_index {
+            self.interrupt(true);
+        }
+        match self.fdc.phase {
+            Phase::Idle => {
+                self.fdc.idle_revolutions += 1;
+                if self.fdc.idle_revolutions >= MOTOR_OFF_REVOLUTIONS {
+                    self.fdc.motor = false;
+                }
+            }
+            Phase::SpinUp => {
+                self.fdc.index_pulses += 1;
+                if self.fdc.index_pulses >= SPIN_UP_REVOLUTIONS {
+                    self.fdc.index_pulses = 0;
+                    self.execute();
+                }
+            }
+            Phase::Verify | Phase::FindSector | Phase::ReadAddress(0) => {
+                self.fdc.index_pulses += 1;
+                if self.fdc.index_pulses >= SEARCH_REVOLUTIONS {
+                    self.fdc.status |= if self.fdc.phase == Phase::Verify { SEEK_ERROR } else { RECORD_NOT_FOUND };
+                    self.finish();
+                }
+            }
+            Phase::WaitIndex => {
+                self.fdc.phase = if self.fdc.command & 0x10 == 0 { Phase::ReadTrack(0) } else { Phase::WriteTrack(0) };
+                if let Phase::ReadTrack(_) = self.fdc.phase {
+                    let side = self.side();
+                    self.fdc.buffer = self.drive().and_then(|drive| drive.track(side)).map_or(Vec::new(), |t| t.raw.clone());
+                } else {
+                    self.fdc.buffer.clear();
+                    self.fdc.data_request = true;
+                }
+                self.fdc.event = self.cycles + CYCLES_PER_BYTE;
+            }
+            _ => {}
+        }
+    }
+    // Next ID field passing under the head which matches the given sector (or any, if None)
+    fn find_id(&mut self, sector: Option<u8>) -> Option<(usize, Vec<u8>)> {
+        let side = self.side();
+        let current = self.position(self.cycles);
+        let track = self.drive()?.track(side)?;
+        let candidates = track.ids.iter().filter(|id| sector.is_none_or(|s| s == id.sector));
+        let id = candidates.min_by_key(|id| (id.position + TRACK_LENGTH - current - 1) % TRACK_LENGTH)?;
+        Some((id.position, id.bytes()))
+    }
+    fn advance(&mut self) {
+        self.cycles = self.cycles.max(self.fdc.event);
+        match self.fdc.phase {
+            Phase::Step => {
+                let command = self.fdc.command;
+                match command >> 4 {
+                    0x0 => {
+                        let track_zero = self.drive().is_some_and(|drive| drive.cylinder == 0);
+                        if track_zero {
+                            self.fdc.track = 0;
+                            return self.settle();
+                        } else if self.fdc.steps == RESTORE_STEPS {
+                            self.fdc.status |= SEEK_ERROR;
+                            return self.finish();
+                        }
+                        self.fdc.outwards = true;
+                    }
+                    0x1 if self.fdc.track == self.fdc.data => return self.settle(),
+                    0x1 => self.fdc.outwards = self.fdc.data < self.fdc.track,
+                    _ if self.fdc.steps > 0 => return self.settle(),
+                    _ => {}
+                }
+                self.fdc.steps += 1;
+                self.step();
+                self.fdc.event = self.cycles + self.fdc.step_rate();
+            }
+            Phase::Settle if self.fdc.type_one_status => self.settle(),
+            Phase::Settle => self.search(),
+            Phase::Verify => {
+                let cylinder = self.drive().map_or(usize::MAX, |drive| drive.cylinder);
+                if self.fdc.track as usize != cylinder || self.find_id(None).is_none() {
+                    self.fdc.event = u64::MAX;
+                    return;
+                }
+                self.finish();
+            }
+            Phase::FindSector => {
+                self.fdc.index_pulses = 0;
+                let side = self.side();
+                let cylinder = self.drive().map_or(usize::MAX, |drive| drive.cylinder);
+                let sector = self.fdc.sector;
+                let data = self
+                    .drive()
+                    .and_then(|drive| drive.image.as_ref())
+                    .and_then(|image| image.read_sector(cylinder, side, sector))
+                    .map(|data| data.to_vec());
+                match data {
+                    Some(data) if self.fdc.command & 0x20 == 0 => {
+                        self.fdc.buffer = data;
+                        self.fdc.phase = Phase::ReadSector(0);
+                    }
+                    Some(_) => {
+                        self.fdc.buffer.clear();
+                        self.fdc.data_request = true;
+                        self.fdc.phase = Phase::WriteSector(0);
+                    }
+                    None => {
+                        self.fdc.event = u64::MAX;
+                        return;
+                    }
+                }
+                // The data field starts after gap 2 and the data address mark
+                self.fdc.event = self.cycles + 38 * CYCLES_PER_BYTE;
+            }
+            Phase::ReadSector(n) => {
+                let byte = self.fdc.buffer[n];
+                self.deliver(byte);
+                if n + 1 < SECTOR_SIZE {
+                    self.fdc.phase = Phase::ReadSector(n + 1);
+                    self.fdc.event = self.cycles + CYCLES_PER_BYTE;
+                } else {
+                    self.next_sector();
+                }
+            }
+            Phase::WriteSector(n) => {
+                let byte = self.fetch();
+                self.fdc.buffer.push(byte);
+                if n + 1 < SECTOR_SIZE {
+                    self.fdc.phase = Phase::WriteSector(n + 1);
+                    self.fdc.event = self.cycles + CYCLES_PER_BYTE;
+                } else {
+                    self.fdc.data_request = false;
+                    let (side, sector, buffer) = (self.side(), self.fdc.sector, self.fdc.buffer.clone());
+                    if let Some(drive) = self.drive() {
+                        let cylinder = drive.cylinder;
+                        drive.track = None;
+                        if let Some(image) = drive.image.as_mut() {
+                            image.write_sector(cylinder, side, sector, &buffer);
+                        }
+                    }
+                    self.next_sector();
+                }
+            }
+            Phase::ReadAddress(0) => {
+                match self.find_id(None) {
+                    Some((position, bytes)) => {
+                        self.fdc.buffer = bytes;
+                        self.fdc.phase = Phase::ReadAddress(1);
+                        self.fdc.event = self.cycles + self.time_to(position + 1);
+                    }
+                    None => self.fdc.event = u64::MAX,
+                }
+            }
+            Phase::ReadAddress(n) => {
+                let byte = self.fdc.buffer[n - 1];
+                self.deliver(byte);
+                if n < 6 {
+                    self.fdc.phase = Phase::ReadAddress(n + 1);
+                    self.fdc.event = self.cycles + CYCLES_PER_BYTE;
+                } else {
+                    // The WD1772 reports the track number of the ID field in its sector register
+                    self.fdc.sector = self.fdc.buffer[0];
+                    self.finish();
+                }
+            }
+            Phase::ReadTrack(n) => {
+                if n < self.fdc.buffer.len() {
+                    let byte = self.fdc.buffer[n];
+                    self.deliver(byte);
+                }
+                if n + 1 < TRACK_LENGTH {
+                    self.fdc.phase = Phase::ReadTrack(n + 1);
+                    self.fdc.event = self.cycles + CYCLES_PER_BYTE;
+                } else {
+                    self.finish();
+                }
+            }
+            Phase::WriteTrack(n) => {
+                let byte = self.fetch();
+                self.fdc.buffer.push(byte);
+                if n + 1 < TRACK_LENGTH {
+                    self.fdc.phase = Phase::WriteTrack(n + 1);
+                    self.fdc.event = self.cycles + CYCLES_PER_BYTE;
+                } else {
+                    self.fdc.data_request = false;
+                    let (side, buffer) = (self.side(), self.fdc.buffer.clone());
+                    if let Some(drive) = self.drive() {
+                        let cylinder = drive.cylinder;
+                        drive.track = None;
+                        if let Some(image) = drive.image.as_mut() {
+                            image.format_track(cylinder, side, &buffer);
+                        }
+                    }
+                    self.finish();
+                }
+            }
+            Phase::Idle | Phase::SpinUp | Phase::WaitIndex => self.fdc.event = u64::MAX,
+        }
+    }
+    // End of the stepping part of a type I command, optionally followed by verifying the track
+    fn settle(&mut self) {
+        if self.fdc.command & VERIFY == 0 {
+            return self.finish();
+        }
+        if self.fdc.phase == Phase::Settle {
+            self.fdc.phase = Phase::Verify;
+            self.fdc.index_pulses = 0;
+            self.fdc.event = self.cycles;
+            return;
+        }
+        self.fdc.phase = Phase::Settle;
+        self.fdc.event = self.cycles + SETTLE_DELAY;
+    }
+    // Start of the actual disk access of a type II or III command
+    fn search(&mut self) {
+        self.fdc.index_pulses = 0;
+        match self.fdc.command >> 4 {
+            0x8..=0xb => self.find_sector(),
+            0xc => {
+                self.fdc.phase = Phase::ReadAddress(0);
+                self.fdc.event = self.cycles;
+            }
+            _ => {
+                self.fdc.phase = Phase::WaitIndex;
+                self.fdc.event = u64::MAX;
+            }
+        }
+    }
+    fn find_sector(&mut self) {
+        self.fdc.phase = Phase::FindSector;
+        self.fdc.event = u64::MAX;
+        let cylinder = self.drive().map_or(usize::MAX, |drive| drive.cylinder);
+        if self.fdc.track as usize != cylinder {
+            return;
+        }
+        if let Some((position, _)) = self.find_id(Some(self.fdc.sector)) {
+            self.fdc.event = self.cycles + self.time_to(position + 7);
+        }
+    }
+    fn next_sector(&mut self) {
+        if self.fdc.command & MULTIPLE_SECTORS != 0 {
+            self.fdc.sector = self.fdc.sector.wrapping_add(1);
+            self.find_sector();
+        } else {
+            self.finish();
+        }
+    }
+    // Hands a byte read from the disk to the DMA, or leaves it in the data register for the CPU
+    fn deliver(&mut self, byte: u8) {
+        if self.dma.serves_fdc() && !self.dma.writing() && self.dma.sector_count > 0 {
+            self.dma.fifo.push_back(byte);
+        } else {
+            if self.fdc.data_request {
+                self.fdc.status |= LOST_DATA;
+            }
+            self.fdc.data = byte;
+            self.fdc.data_request = true;
+        }
+    }
+    // Fetches the next byte to be written to the disk from the DMA or the data register
+    fn fetch(&mut self) -> u8 {
+        if self.dma.serves_fdc() && self.dma.writing() {
+            match self.dma.fifo.pop_front() {
+                Some(byte) => return byte,
+                None => {
+                    self.fdc.status |= LOST_DATA;
+                    return 0;
+                }
+            }
+        }
+        if self.fdc.data_request {
+            self.fdc.status |= LOST_DATA;
+            return 0;
+        }
+        self.fdc.data_request = true;
+        self.fdc.data
+    }
+    fn read_fdc(&mut self) -> u8 {
+        match (self.dma.mode & MODE_REGISTER_SELECT) >> 1 {
+            0 => self.status(),
+            1 => self.fdc.track,
+            2 => self.fdc.sector,
+            _ => {
+                self.fdc.data_request = false;
+                self.fdc.data
+            }
+        }
+    }
+    fn write_fdc(&mut self, value: u8) {
+        match (self.dma.mode & MODE_REGISTER_SELECT) >> 1 {
+            0 => self.command(value),
+            1 => self.fdc.track = value,
+            2 => self.fdc.sector = value,
+            _ => {
+                self.fdc.data_request = false;
+                self.fdc.data = value;
+            }
+        }
+    }
+    fn read_byte(&mut self, address: usize) -> u8 {
+        match address - self.address {
+            0x05 if self.dma.mode & MODE_SECTOR_COUNT != 0 => self.dma.sector_count as u8,
+            0x05 if self.dma.mode & MODE_HDC != 0 => self.acsi.read(),
+            0x05 => self.read_fdc(),
+            0x07 => self.dma.status(self.fdc.data_request),
+            0x09 => (self.dma.address >> 16) as u8,
+            0x0b => (self.dma.address >> 8) as u8,
+            0x0d => self.dma.address as u8,
+            _ => 0,
+        }
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        match address - self.address {
+            0x05 if self.dma.mode & MODE_SECTOR_COUNT != 0 => self.dma.sector_count = value as u16,
+            0x05 if self.dma.mode & MODE_HDC != 0 => self.acsi.write(value, self.dma.mode & MODE_A0 == 0, self.cycles),
+            0x05 => self.write_fdc(value),
+            0x06 => {
+                let mode = (self.dma.mode & 0xff) | ((value as u16 & 0x01) << 8);
+                // Toggling the direction bit is the documented way of resetting the DMA
+                if (mode ^ self.dma.mode) & MODE_WRITE != 0 {
+                    self.dma.reset();
+                }
+                self.dma.mode = mode;
+            }
+            0x07 => self.dma.mode = (self.dma.mode & 0xff00) | value as u16,
+            0x09 => self.dma.address = (self.dma.address & 0x00ffff) | (value as u32) << 16,
+            0x0b => self.dma.address = (self.dma.address & 0xff00ff) | (value as u32) << 8,
+            0x0d => self.dma.address = (self.dma.address & 0xffff00) | (value & 0xfe) as u32,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Floppy {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address + 0x04, self.address + 0x0e)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| self.write_byte(a, b));
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        // Index pulses and controller events are processed in the order in which they occur
+        loop {
+            let index = if self.spinning() { (self.cycles / CYCLES_PER_REVOLUTION + 1) * CYCLES_PER_REVOLUTION } else { u64::MAX };
+            if index.min(self.fdc.event) > cycles {
+                break;
+            }
+            if index <= self.fdc.event {
+                self.cycles = index;
+                self.index_pulse();
+            } else {
+                self.advance();
+            }
+        }
+        self.cycles = cycles;
+        self.acsi.clock(cycles, &mut self.dma);
+        if self.dma.mode & MODE_DMA_OFF == 0 && self.dma.wants_bus() {
+            Signal::BusRequest
+        } else {
+            Signal::Ok
+        }
+    }
+    fn bus_access(&mut self, bus: &mut Bus) {
+        if self.dma.writing() {
+            for _ in 0..FIFO_SIZE / 2 {
+                let word = bus.read(self.dma.address as usize, Size::Word).inner() as u16;
+                self.dma.fifo.extend(&word.to_be_bytes());
+                self.dma.address += 2;
+            }
+        } else {
+            for _ in 0..FIFO_SIZE / 2 {
+                let word = u16::from_be_bytes([self.dma.fifo.pop_front().unwrap(), self.dma.fifo.pop_front().unwrap()]);
+                bus.write(self.dma.address as usize, OpResult::Word(word));
+                self.dma.address += 2;
+            }
+        }
+        self.dma.count(FIFO_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{port, Ram};
+    use super::*;
+
+    const BUFFER: u32 = 0x1000;
+
+    // The contents of each sector tell where it is
+    fn sector(track: u8, side: u8, sector: u8) -> Vec<u8> {
+        (0..SECTOR_SIZE).map(|j| track ^ side << 7 ^ sector << 4 ^ j as u8).collect()
+    }
+
+    // 80 tracks of 9 sectors on 2 sides
+    fn image() -> DiskImage {
+        let mut data = Vec::new();
+        for track in 0..80 {
+            for side in 0..2 {
+                for number in 1..=9 {
+                    data.extend(sector(track, side, number));
+                }
+            }
+        }
+        DiskImage::from(data)
+    }
+
+    // Drive A, side 0 selected
+    fn floppy() -> (Box<Floppy>, Bus) {
+        let floppy = Floppy::new(0xff8600, Some(image()), port(0x05));
+        let mut bus = Bus::new();
+        bus.attach(Ram::new(0x10000));
+        (floppy, bus)
+    }
+
+    fn mode(floppy: &mut Floppy, mode: u16) {
+        floppy.write(0xff8606, OpResult::Word(mode));
+    }
+
+    fn register(floppy: &mut Floppy, mode: u16, value: u16) {
+        self::mode(floppy, mode);
+        floppy.write(0xff8604, OpResult::Word(value));
+    }
+
+    // Sets up the DMA for the given number of sectors and direction
+    fn dma(floppy: &mut Floppy, sectors: u16, write: bool) {
+        let direction = if write { MODE_WRITE } else { 0 };
+        mode(floppy, MODE_FDC | direction ^ MODE_WRITE);
+        register(floppy, MODE_FDC | MODE_SECTOR_COUNT | direction, sectors);
+        for (offset, byte) in [(0x09, BUFFER >> 16), (0x0b, BUFFER >> 8), (0x0d, BUFFER)] {
+            floppy.write(0xff8600 + offset, OpResult::Byte(byte as u8));
+        }
+    }
+
+    // Runs the command given to the FDC to its end and returns the status
+    fn run(floppy: &mut Floppy, bus: &mut Bus, command: u8) -> u8 {
+        let direction = floppy.dma.mode & MODE_WRITE;
+        register(floppy, MODE_FDC | direction, command as u16);
+        let mut cycles = floppy.cycles;
+        while !*floppy.interrupt.borrow() {
+            cycles += CYCLES_PER_BYTE;
+            assert!(cycles < 10 * CPU_FREQUENCY, "command ${:02x} did not end", command);
+            if floppy.clock(cycles) == Signal::BusRequest {
+                floppy.bus_access(bus);
+            }
+        }
+        mode(floppy, MODE_FDC | direction);
+        floppy.read(0xff8604, Size::Word).inner() as u8
+    }
+
+    fn memory(bus: &mut Bus, length: usize) -> Vec<u8> {
+        (0..length).map(|j| bus.read(BUFFER as usize + j, Size::Byte).inner() as u8).collect()
+    }
+
+    fn dma_address(floppy: &mut Floppy) -> u32 {
+        [0x09, 0x0b, 0x0d].iter().fold(0, |address, offset| address << 8 | floppy.read(0xff8600 + offset, Size::Byte).inner())
+    }
+
+    #[test]
+    fn read_sector() {
+        let (mut floppy, mut bus) = floppy();
+        dma(&mut floppy, 1, false);
+        register(&mut floppy, MODE_FDC | 0x04, 3);
+        assert_eq!(run(&mut floppy, &mut bus, 0x80) & !MOTOR_ON, 0);
+        assert_eq!(memory(&mut bus, SECTOR_SIZE), sector(0, 0, 3));
+        assert_eq!(dma_address(&mut floppy), BUFFER + SECTOR_SIZE as u32);
+        // The sector count is down to 0, which the DMA status shows in bit 1
+        assert_eq!(floppy.read(0xff8606, Size::Word).inner() & 0x02, 0);
+    }
+
+    #[test]
+    fn seek_and_read_from_the_other_side() {
+        let (mut floppy, mut bus) = floppy();
+        register(&mut floppy, MODE_FDC | 0x06, 5);
+        assert_eq!(run(&mut floppy, &mut bus, 0x14) & (SEEK_ERROR | TRACK_ZERO), 0);
+        *floppy.drive_select.borrow_mut() = 0x04;
+        dma(&mut floppy, 1, false);
+        register(&mut floppy, MODE_FDC | 0x04, 9);
+        assert_eq!(run(&mut floppy, &mut bus, 0x80) & RECORD_NOT_FOUND, 0);
+        assert_eq!(memory(&mut bus, SECTOR_SIZE), sector(5, 1, 9));
+    }
+
+    #[test]
+    fn missing_sector_is_not_found() {
+        let (mut floppy, mut bus) = floppy();
+        dma(&mut floppy, 1, false);
+        register(&mut floppy, MODE_FDC | 0x04, 10);
+        assert_eq!(run(&mut floppy, &mut bus, 0x80) & RECORD_NOT_FOUND, RECORD_NOT_FOUND);
+        assert_eq!(dma_address(&mut floppy), BUFFER);
+    }
+
+    #[test]
+    fn multiple_sectors_count_down_the_dma() {
+        let (mut floppy, mut bus) = floppy();
+        dma(&mut floppy, 3, false);
+        register(&mut floppy, MODE_FDC | 0x04, 8);
+        // Sector 10 ends the command
+        assert_eq!(run(&mut floppy, &mut bus, 0x90) & RECORD_NOT_FOUND, RECORD_NOT_FOUND);
+        assert_eq!(memory(&mut bus, 2 * SECTOR_SIZE), [sector(0, 0, 8), sector(0, 0, 9)].concat());
+        assert_eq!(floppy.dma.sector_count, 1);
+        assert_eq!(floppy.read(0xff8606, Size::Word).inner() & 0x02, 0x02);
+    }
+
+    #[test]
+    fn write_sector() {
+        let (mut floppy, mut bus) = floppy();
+        let data: Vec<u8> = (0..SECTOR_SIZE).map(|j| (j * 7) as u8).collect();
+        for (j, byte) in data.iter().enumerate() {
+            bus.write(BUFFER as usize + j, OpResult::Byte(*byte));
+        }
+        dma(&mut floppy, 1, true);
+        register(&mut floppy, MODE_FDC | MODE_WRITE | 0x04, 2);
+        assert_eq!(run(&mut floppy, &mut bus, 0xa0) & (LOST_DATA | WRITE_PROTECT), 0);
+        let image = floppy.eject(0).unwrap();
+        assert_eq!(image.read_sector(0, 0, 2).unwrap(), &data[..]);
+        assert_eq!(image.read_sector(0, 0, 3).unwrap(), &sector(0, 0, 3)[..]);
+    }
+
+    #[test]
+    fn write_protected_disks_are_not_written() {
+        let (mut floppy, mut bus) = floppy();
+        let mut image = image();
+        image.write_protected = true;
+        floppy.insert(0, image);
+        dma(&mut floppy, 1, true);
+        register(&mut floppy, MODE_FDC | MODE_WRITE | 0x04, 1);
+        assert_eq!(run(&mut floppy, &mut bus, 0xa0) & WRITE_PROTECT, WRITE_PROTECT);
+        assert_eq!(floppy.eject(0).unwrap().read_sector(0, 0, 1).unwrap(), &sector(0, 0, 1)[..]);
+    }
+}
@@ -0,0 +1,655 @@
+// The keyboard. The ST's keyboard has its own processor (an HD6301), the IKBD, which scans the
+// keys, the mouse and the joysticks and talks to the ST through an ACIA at 7812.5 baud. It reports
+// keys as they are pressed (the scancode) and released (the scancode with bit 7 set), the mouse
+// and joysticks in packets starting with a header byte $F6-$FF, and takes commands changing how it
+// does that (see the Intelligent Keyboard Protocol). It also keeps the time of day.
+//
+// Keys, mouse movements and joystick changes arrive from the host as InputEvents. Keys are
+// translated into the scancodes of the keyboard layout of the TOS in use, by what is printed on
+// them: e.g. Z is next to T on a German keyboard.
+
+use super::acia::ACIA;
+use super::joystick::JoystickMapper;
+use super::{read_bytes, write_bytes, Device, Signal, SignalLine, CPU_FREQUENCY};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use minifb::Key;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// 10 bits at 7812.5 baud
+const CYCLES_PER_BYTE: u64 = 10240;
+
+// Commands and the number of parameters they take
+const RESET: u8 = 0x80;
+const MOUSE_BUTTON_ACTION: u8 = 0x07;
+const RELATIVE_MOUSE: u8 = 0x08;
+const ABSOLUTE_MOUSE: u8 = 0x09;
+const MOUSE_KEYCODE: u8 = 0x0a;
+const MOUSE_THRESHOLD: u8 = 0x0b;
+const MOUSE_SCALE: u8 = 0x0c;
+const INTERROGATE_MOUSE: u8 = 0x0d;
+const LOAD_MOUSE_POSITION: u8 = 0x0e;
+const Y_AT_BOTTOM: u8 = 0x0f;
+const Y_AT_TOP: u8 = 0x10;
+const RESUME: u8 = 0x11;
+const DISABLE_MOUSE: u8 = 0x12;
+const PAUSE: u8 = 0x13;
+const JOYSTICK_EVENTS: u8 = 0x14;
+const JOYSTICK_INTERROGATION: u8 = 0x15;
+const INTERROGATE_JOYSTICK: u8 = 0x16;
+const JOYSTICK_MONITORING: u8 = 0x17;
+const FIRE_BUTTON_MONITORING: u8 = 0x18;
+const JOYSTICK_KEYCODE: u8 = 0x19;
+const DISABLE_JOYSTICKS: u8 = 0x1a;
+const SET_CLOCK: u8 = 0x1b;
+const INTERROGATE_CLOCK: u8 = 0x1c;
+const MEMORY_LOAD: u8 = 0x20;
+const MEMORY_READ: u8 = 0x21;
+const EXECUTE: u8 = 0x22;
+const PARAMETERS: [(u8, usize); 13] = [
+    (RESET, 1),
+    (MOUSE_BUTTON_ACTION, 1),
+    (ABSOLUTE_MOUSE, 4),
+    (MOUSE_KEYCODE, 2),
+    (MOUSE_THRESHOLD, 2),
+    (MOUSE_SCALE, 2),
+    (LOAD_MOUSE_POSITION, 5),
+    (JOYSTICK_MONITORING, 1),
+    (JOYSTICK_KEYCODE, 6),
+    (SET_CLOCK, 6),
+    (MEMORY_LOAD, 3),
+    (MEMORY_READ, 2),
+    (EXECUTE, 2),
+];
+// Status inquiries are the commands they ask about with bit 7 set
+const STATUS: u8 = 0x80;
+
+// Packet headers
+const VERSION: u8 = 0xf1;
+const STATUS_REPORT: u8 = 0xf6;
+const ABSOLUTE_POSITION: u8 = 0xf7;
+const RELATIVE_POSITION: u8 = 0xf8;
+const TIME_OF_DAY: u8 = 0xfc;
+const JOYSTICK_REPORT: u8 = 0xfd;
+const JOYSTICK_EVENT: u8 = 0xfe;
+
+// Keys the mouse is reported as in keycode mode
+const LEFT_BUTTON: u8 = 0x74;
+const RIGHT_BUTTON: u8 = 0x75;
+const UP: u8 = 0x48;
+const DOWN: u8 = 0x50;
+const LEFT: u8 = 0x4b;
+const RIGHT: u8 = 0x4d;
+
+// Input from the host. Mouse movements are relative, joystick states have the fire button in
+// bit 7 and up, down, left and right in bits 0-3.
+pub enum InputEvent {
+    Key(Key, bool),
+    MouseMove(i32, i32),
+    MouseButtons(bool, bool),
+    Joystick(usize, u8),
+}
+
+pub type InputQueue = Rc<RefCell<VecDeque<InputEvent>>>;
+
+pub fn input_queue() -> InputQueue {
+    Rc::new(RefCell::new(VecDeque::new()))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyboardLayout {
+    DE,
+    US,
+    UK,
+    FR,
+}
+
+impl KeyboardLayout {
+    pub fn from(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "de" => Some(Self::DE),
+            "us" => Some(Self::US),
+            "uk" => Some(Self::UK),
+            "fr" => Some(Self::FR),
+            _ => None,
+        }
+    }
+    // The layout matching the country code in the TOS header
+    pub fn for_country(country: usize) -> Self {
+        match country {
+            1 | 8 => Self::DE,
+            2 | 7 => Self::FR,
+            3 => Self::UK,
+            _ => Self::US,
+        }
+    }
+    fn scancode(&self, key: Key) -> Option<u8> {
+        // Keys whose place differs from the US layout
+        let scancode = match (self, key) {
+            (Self::DE, Key::Y) => 0x2c,
+            (Self::DE, Key::Z) => 0x15,
+            (Self::DE, Key::Minus) => 0x35,
+            (Self::UK, Key::Backslash) => 0x60,
+            (Self::FR, Key::A) => 0x10,
+            (Self::FR, Key::Q) => 0x1e,
+            (Self::FR, Key::W) => 0x2c,
+            (Self::FR, Key::Z) => 0x11,
+            (Self::FR, Key::M) => 0x27,
+            (Self::FR, Key::Comma) => 0x32,
+            (Self::FR, Key::Semicolon) => 0x33,
+            (Self::FR, Key::Minus) => 0x0d,
+            _ => return us_scancode(key),
+        };
+        Some(scancode)
+    }
+}
+
+fn us_scancode(key: Key) -> Option<u8> {
+    const LETTERS: [u8; 26] = [
+        0x1e, 0x30, 0x2e, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32, 0x31, 0x18, 0x19, 0x10, 0x13, 0x1f,
+        0x14, 0x16, 0x2f, 0x11, 0x2d, 0x15, 0x2c,
+    ];
+    let scancode = match key {
+        Key::Key0 => 0x0b,
+        key if (key as u8) < Key::A as u8 => key as u8 + 1,
+        key if (key as u8) <= Key::Z as u8 => LETTERS[(key as u8 - Key::A as u8) as usize],
+        key if (Key::F1 as u8..=Key::F10 as u8).contains(&(key as u8)) => 0x3b + (key as u8 - Key::F1 as u8),
+        Key::Escape => 0x01,
+        Key::Minus => 0x0c,
+        Key::Equal => 0x0d,
+        Key::Backspace => 0x0e,
+        Key::Tab => 0x0f,
+        Key::LeftBracket => 0x1a,
+        Key::RightBracket => 0x1b,
+        Key::Enter => 0x1c,
+        Key::LeftCtrl | Key::RightCtrl => 0x1d,
+        Key::Semicolon => 0x27,
+        Key::Apostrophe => 0x28,
+        Key::Backquote => 0x29,
+        Key::LeftShift => 0x2a,
+        Key::Backslash => 0x2b,
+        Key::Comma => 0x33,
+        Key::Period => 0x34,
+        Key::Slash => 0x35,
+        Key::RightShift => 0x36,
+        Key::LeftAlt | Key::RightAlt => 0x38,
+        Key::Space => 0x39,
+        Key::CapsLock => 0x3a,
+        Key::Home => 0x47,
+        Key::Up => 0x48,
+        Key::Left => 0x4b,
+        Key::Right => 0x4d,
+        Key::Down => 0x50,
+        Key::Insert => 0x52,
+        Key::Delete => 0x53,
+        // Keys the host keyboard lacks sit where the ST has them: Undo and Help next to the cursor keys
+        Key::End | Key::F11 => 0x61,
+        Key::PageDown | Key::F12 => 0x62,
+        Key::NumPadMinus => 0x4a,
+        Key::NumPadPlus => 0x4e,
+        Key::NumPadSlash => 0x65,
+        Key::NumPadAsterisk => 0x66,
+        Key::NumPad7 => 0x67,
+        Key::NumPad8 => 0x68,
+        Key::NumPad9 => 0x69,
+        Key::NumPad4 => 0x6a,
+        Key::NumPad5 => 0x6b,
+        Key::NumPad6 => 0x6c,
+        Key::NumPad1 => 0x6d,
+        Key::NumPad2 => 0x6e,
+        Key::NumPad3 => 0x6f,
+        Key::NumPad0 => 0x70,
+        Key::NumPadDot => 0x71,
+        Key::NumPadEnter => 0x72,
+        _ => return None,
+    };
+    Some(scancode)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum MouseMode {
+    Relative,
+    Absolute,
+    Keycode,
+    Disabled,
+}
+
+// Monitoring and keycode mode of the joysticks are not supported, they are treated like
+// interrogation mode
+#[derive(Copy, Clone, PartialEq)]
+enum JoystickMode {
+    Event,
+    Interrogation,
+    Disabled,
+}
+
+fn bcd(value: u32) -> u8 {
+    (value / 10 % 10 * 16 + value % 10) as u8
+}
+
+struct IKBD {
+    layout: KeyboardLayout,
+    command: Vec<u8>,
+    // Bytes of a memory load still to come
+    loading: usize,
+    output: VecDeque<u8>,
+    paused: bool,
+    mouse_mode: MouseMode,
+    joystick_mode: JoystickMode,
+    button_action: u8,
+    y_at_bottom: bool,
+    threshold: (i32, i32),
+    scale: (i32, i32),
+    maximum: (i32, i32),
+    keycode_deltas: (i32, i32),
+    // Movement not reported yet
+    movement: (i32, i32),
+    position: (i32, i32),
+    buttons: (bool, bool),
+    // Button presses and releases since the last absolute position report
+    button_changes: u8,
+    joysticks: [u8; 2],
+    // Time of day and when it was last set
+    time: NaiveDateTime,
+    time_set: u64,
+}
+
+impl IKBD {
+    fn new(layout: KeyboardLayout, time: NaiveDateTime) -> Self {
+        let mut ikbd = Self {
+            layout,
+            command: Vec::new(),
+            loading: 0,
+            output: VecDeque::new(),
+            paused: false,
+            mouse_mode: MouseMode::Relative,
+            joystick_mode: JoystickMode::Event,
+            button_action: 0,
+            y_at_bottom: false,
+            threshold: (1, 1),
+            scale: (1, 1),
+            maximum: (0, 0),
+            keycode_deltas: (1, 1),
+            movement: (0, 0),
+            position: (0, 0),
+            buttons: (false, false),
+            button_changes: 0,
+            joysticks: [0; 2],
+            time,
+            time_set: 0,
+        };
+        ikbd.reset();
+        ikbd
+    }
+    fn reset(&mut self) {
+        self.output.clear();
+        self.paused = false;
+        self.mouse_mode = MouseMode::Relative;
+        self.joystick_mode = JoystickMode::Event;
+        self.button_action = 0;
+        self.y_at_bottom = false;
+        self.threshold = (1, 1);
+        self.scale = (1, 1);
+        self.movement = (0, 0);
+        self.button_changes = 0;
+    }
+    fn send(&mut self, bytes: &[u8]) {
+        self.output.extend(bytes);
+    }
+    fn key(&mut self, scancode: u8, pressed: bool) {
+        self.send(&[if pressed { scancode } else { scancode | 0x80 }]);
+    }
+    fn status(&mut self, bytes: &[u8]) {
+        let mut report = [0; 8];
+        report[0] = STATUS_REPORT;
+        report[1..=bytes.len()].copy_from_slice(bytes);
+        self.send(&report);
+    }
+    fn clock(&self, cycles: u64) -> NaiveDateTime {
+        self.time + Duration::seconds(((cycles - self.time_set) / CPU_FREQUENCY) as i64)
+    }
+    // Fields which are no valid BCD numbers are left alone
+    fn set_clock(&mut self, fields: &[u8], cycles: u64) {
+        let time = self.clock(cycles);
+        let current = [time.year() as u32 % 100, time.month(), time.day(), time.hour(), time.minute(), time.second()];
+        let mut values = [0; 6];
+        for (j, &field) in fields.iter().enumerate() {
+            values[j] = if field >> 4 < 10 && field & 0xf < 10 { (field >> 4) as u32 * 10 + (field & 0xf) as u32 } else { current[j] };
+        }
+        let year = if values[0] < 80 { 2000 } else { 1900 } + values[0] as i32;
+        if let Some(time) = NaiveDate::from_ymd_opt(year, values[1], values[2]).and_then(|date| date.and_hms_opt(values[3], values[4], values[5])) {
+            self.time = time;
+            self.time_set = cycles;
+        }
+    }
+    fn absolute_position(&mut self) {
+        let (x, y) = self.position;
+        let buttons = std::mem::take(&mut self.button_changes);
+        self.send(&[ABSOLUTE_POSITION, buttons, (x >> 8) as u8, x as u8, (y >> 8) as u8, y as u8]);
+    }
+    fn receive(&mut self, byte: u8, cycles: u64) {
+        if self.loading > 0 {
+            self.loading -= 1;
+            return;
+        }
+        self.command.push(byte);
+        let command = self.command[0];
+        let parameters = PARAMETERS.iter().find(|(c, _)| *c == command).map_or(0, |(_, n)| *n);
+        if self.command.len() <= parameters {
+            return;
+        }
+        let command = std::mem::take(&mut self.command);
+        let p = |j: usize| command[j] as i32;
+        // Any command but pause resumes the output
+        self.paused = false;
+        match command[0] {
+            RESET if command[1] == 0x01 => {
+                self.reset();
+                self.send(&[VERSION]);
+            }
+            MOUSE_BUTTON_ACTION => self.button_action = command[1],
+            RELATIVE_MOUSE => self.mouse_mode = MouseMode::Relative,
+            ABSOLUTE_MOUSE => {
+                self.mouse_mode = MouseMode::Absolute;
+                self.maximum = (p(1) << 8 | p(2), p(3) << 8 | p(4));
+                self.position = (self.position.0.min(self.maximum.0), self.position.1.min(self.maximum.1));
+            }
+            MOUSE_KEYCODE => {
+                self.mouse_mode = MouseMode::Keycode;
+                self.keycode_deltas = (p(1).max(1), p(2).max(1));
+            }
+            MOUSE_THRESHOLD => self.threshold = (p(1).max(1), p(2).max(1)),
+            MOUSE_SCALE => self.scale = (p(1).max(1), p(2).max(1)),
+            INTERROGATE_MOUSE if self.mouse_mode == MouseMode::Absolute => self.absolute_position(),
+            LOAD_MOUSE_POSITION => self.position = ((p(2) << 8 | p(3)).min(self.maximum.0), (p(4) << 8 | p(5)).min(self.maximum.1)),
+            Y_AT_BOTTOM => self.y_at_bottom = true,
+            Y_AT_TOP => self.y_at_bottom = false,
+            DISABLE_MOUSE => self.mouse_mode = MouseMode::Disabled,
+            RESUME => {}
+            PAUSE => self.paused = true,
+            JOYSTICK_EVENTS => self.joystick_mode = JoystickMode::Event,
+            JOYSTICK_INTERROGATION | JOYSTICK_MONITORING | FIRE_BUTTON_MONITORING | JOYSTICK_KEYCODE => {
+                self.joystick_mode = JoystickMode::Interrogation
+            }
+            INTERROGATE_JOYSTICK => self.send(&[JOYSTICK_REPORT, self.joysticks[0], self.joysticks[1]]),
+            DISABLE_JOYSTICKS => self.joystick_mode = JoystickMode::Disabled,
+            SET_CLOCK => self.set_clock(&command[1..], cycles),
+            INTERROGATE_CLOCK => {
+                let time = self.clock(cycles);
+                self.send(&[TIME_OF_DAY, bcd(time.year() as u32), bcd(time.month()), bcd(time.day())]);
+                self.send(&[bcd(time.hour()), bcd(time.minute()), bcd(time.second())]);
+            }
+            // The controller's own memory is not emulated, so loaded code is ignored and reads give zeroes
+            MEMORY_LOAD => self.loading = command[3] as usize,
+            MEMORY_READ => self.status(&[0; 6]),
+            inquiry if inquiry & STATUS != 0 => self.inquiry(inquiry & !STATUS),
+            _ => {}
+        }
+    }
+    fn inquiry(&mut self, command: u8) {
+        match command {
+            MOUSE_BUTTON_ACTION => self.status(&[MOUSE_BUTTON_ACTION, self.button_action]),
+            RELATIVE_MOUSE | ABSOLUTE_MOUSE | MOUSE_KEYCODE => match self.mouse_mode {
+                MouseMode::Absolute => {
+                    let (x, y) = self.maximum;
+                    self.status(&[ABSOLUTE_MOUSE, (x >> 8) as u8, x as u8, (y >> 8) as u8, y as u8])
+                }
+                MouseMode::Keycode => self.status(&[MOUSE_KEYCODE, self.keycode_deltas.0 as u8, self.keycode_deltas.1 as u8]),
+                _ => self.status(&[RELATIVE_MOUSE]),
+            },
+            MOUSE_THRESHOLD => self.status(&[MOUSE_THRESHOLD, self.threshold.0 as u8, self.threshold.1 as u8]),
+            MOUSE_SCALE => self.status(&[MOUSE_SCALE, self.scale.0 as u8, self.scale.1 as u8]),
+            Y_AT_BOTTOM | Y_AT_TOP => self.status(&[if self.y_at_bottom { Y_AT_BOTTOM } else { Y_AT_TOP }]),
+            DISABLE_MOUSE => self.status(&[if self.mouse_mode == MouseMode::Disabled { DISABLE_MOUSE } else { 0 }]),
+            JOYSTICK_EVENTS | JOYSTICK_INTERROGATION | JOYSTICK_KEYCODE => {
+                self.status(&[if self.joystick_mode == JoystickMode::Event { JOYSTICK_EVENTS } else { JOYSTICK_INTERROGATION }])
+            }
+            DISABLE_JOYSTICKS => self.status(&[if self.joystick_mode == JoystickMode::Disabled { DISABLE_JOYSTICKS } else { 0 }]),
+            _ => {}
+        }
+    }
+    fn mouse_move(&mut self, dx: i32, dy: i32) {
+        let dy = if self.y_at_bottom { -dy } else { dy };
+        self.movement = (self.movement.0 + dx, self.movement.1 + dy);
+        match self.mouse_mode {
+            MouseMode::Relative => {
+                let header = RELATIVE_POSITION | (self.buttons.0 as u8) << 1 | self.buttons.1 as u8;
+                while self.movement.0.abs() >= self.threshold.0 || self.movement.1.abs() >= self.threshold.1 {
+                    let (x, y) = (self.movement.0.clamp(-128, 127), self.movement.1.clamp(-128, 127));
+                    self.send(&[header, x as u8, y as u8]);
+                    self.movement = (self.movement.0 - x, self.movement.1 - y);
+                }
+            }
+            MouseMode::Absolute => {
+                let (x, y) = (self.movement.0 / self.scale.0, self.movement.1 / self.scale.1);
+                self.movement = (self.movement.0 - x * self.scale.0, self.movement.1 - y * self.scale.1);
+                self.position = ((self.position.0 + x).clamp(0, self.maximum.0), (self.position.1 + y).clamp(0, self.maximum.1));
+            }
+            MouseMode::Keycode => {
+                let (dx, dy) = self.keycode_deltas;
+                while self.movement.0.abs() >= dx {
+                    let key = if self.movement.0 < 0 { LEFT } else { RIGHT };
+                    self.send(&[key, key | 0x80]);
+                    self.movement.0 -= dx * self.movement.0.signum();
+                }
+                while self.movement.1.abs() >= dy {
+                    let key = if self.movement.1 < 0 { UP } else { DOWN };
+                    self.send(&[key, key | 0x80]);
+                    self.movement.1 -= dy * self.movement.1.signum();
+                }
+            }
+            MouseMode::Disabled => self.movement = (0, 0),
+        }
+    }
+    fn mouse_buttons(&mut self, left: bool, right: bool) {
+        let (was_left, was_right) = std::mem::replace(&mut self.buttons, (left, right));
+        if self.mouse_mode == MouseMode::Disabled {
+            return;
+        }
+        if self.button_action & 0x4 != 0 || self.mouse_mode == MouseMode::Keycode {
+            if left != was_left {
+                self.key(LEFT_BUTTON, left);
+            }
+            if right != was_right {
+                self.key(RIGHT_BUTTON, right);
+            }
+            return;
+        }
+        match self.mouse_mode {
+            MouseMode::Relative => self.send(&[RELATIVE_POSITION | (left as u8) << 1 | right as u8, 0, 0]),
+            _ => {
+                // Bits 0 and 1: right button pressed and released, bits 2 and 3 the same for the left one
+                let mut changes = 0;
+                for (shift, now, before) in [(0, right, was_right), (2, left, was_left)] {
+                    if now && !before {
+                        changes |= 1 << shift;
+                    } else if !now && before {
+                        changes |= 2 << shift;
+                    }
+                }
+                self.button_changes |= changes;
+                let pressed = changes & 0x5 != 0 && self.button_action & 0x1 != 0;
+                let released = changes & 0xa != 0 && self.button_action & 0x2 != 0;
+                if pressed || released {
+                    self.absolute_position();
+                }
+            }
+        }
+    }
+    fn joystick(&mut self, port: usize, state: u8) {
+        if self.joysticks[port] == state {
+            return;
+        }
+        self.joysticks[port] = state;
+        // Joystick 0 shares its port with the mouse
+        if self.joystick_mode == JoystickMode::Event && (port == 1 || self.mouse_mode == MouseMode::Disabled) {
+            self.send(&[JOYSTICK_EVENT + port as u8, state]);
+        }
+    }
+    fn event(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Key(key, pressed) => {
+                if let Some(scancode) = self.layout.scancode(key) {
+                    self.key(scancode, pressed);
+                }
+            }
+            InputEvent::MouseMove(dx, dy) => self.mouse_move(dx, dy),
+            InputEvent::MouseButtons(left, right) => self.mouse_buttons(left, right),
+            InputEvent::Joystick(port, state) => self.joystick(port, state),
+        }
+    }
+}
+
+pub struct Keyboard {
+    address: usize,
+    acia: ACIA,
+    ikbd: IKBD,
+    input: InputQueue,
+    joysticks: JoystickMapper,
+    cycles: u64,
+    // When the byte being sent to the IKBD started and when the next one may be received
+    transmit_start: Option<u64>,
+    next_receive: u64,
+    pub interrupt: SignalLine,
+}
+
+impl Keyboard {
+    // The time of day starts at the given time
+    pub fn new(address: usize, input: InputQueue, layout: KeyboardLayout, joysticks: JoystickMapper, time: NaiveDateTime) -> Box<Self> {
+        let acia = ACIA::default();
+        let interrupt = Rc::clone(&acia.interrupt);
+        Box::new(Self { address, acia, ikbd: IKBD::new(layout, time), input, joysticks, cycles: 0, transmit_start: None, next_receive: 0, interrupt })
+    }
+}
+
+impl Device for Keyboard {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x4)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| match a - self.address {
+            offset @ (0 | 2) => self.acia.read(offset / 2),
+            _ => 0xff,
+        })
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| {
+            if let offset @ (0 | 2) = a - self.address {
+                self.acia.write(offset / 2, b);
+            }
+        });
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        self.cycles = cycles;
+        while let Some(event) = self.input.borrow_mut().pop_front() {
+            if let Some(event) = self.joysticks.map(event) {
+                self.ikbd.event(event);
+            }
+        }
+        self.joysticks.poll(cycles);
+        let states = *self.joysticks.states.borrow();
+        for (port, state) in states.iter().take(2).enumerate() {
+            self.ikbd.event(InputEvent::Joystick(port, *state as u8));
+        }
+        if self.acia.transmitting() {
+            let start = *self.transmit_start.get_or_insert(cycles);
+            if cycles >= start + CYCLES_PER_BYTE {
+                self.transmit_start = None;
+                if let Some(byte) = self.acia.transmit() {
+                    self.ikbd.receive(byte, cycles);
+                }
+            }
+        }
+        if cycles >= self.next_receive && self.acia.ready() && !self.ikbd.paused {
+            if let Some(byte) = self.ikbd.output.pop_front() {
+                self.acia.receive(byte);
+                self.next_receive = cycles + CYCLES_PER_BYTE;
+            }
+        }
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ikbd() -> IKBD {
+        IKBD::new(KeyboardLayout::US, NaiveDate::from_ymd(1989, 12, 31).and_hms(12, 0, 0))
+    }
+
+    // Sends the bytes to the IKBD and returns what it answers
+    fn command(ikbd: &mut IKBD, bytes: &[u8], cycles: u64) -> Vec<u8> {
+        for &byte in bytes {
+            ikbd.receive(byte, cycles);
+        }
+        ikbd.output.drain(..).collect()
+    }
+
+    #[test]
+    fn commands_wait_for_their_parameters() {
+        let mut ikbd = ikbd();
+        assert_eq!(command(&mut ikbd, &[RESET], 0), []);
+        assert_eq!(command(&mut ikbd, &[0x01], 0), [VERSION]);
+        assert_eq!(command(&mut ikbd, &[MOUSE_SCALE, 2], 0), []);
+        assert_eq!(command(&mut ikbd, &[3, STATUS | MOUSE_SCALE], 0), [STATUS_REPORT, MOUSE_SCALE, 2, 3, 0, 0, 0, 0]);
+        // Unknown commands are ignored
+        assert_eq!(command(&mut ikbd, &[0x42, STATUS | Y_AT_TOP], 0), [STATUS_REPORT, Y_AT_TOP, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn memory_loads_are_skipped() {
+        let mut ikbd = ikbd();
+        // The loaded bytes are no commands, not even a reset
+        assert_eq!(command(&mut ikbd, &[MEMORY_LOAD, 0x00, 0x80, 3, RESET, 0x01, INTERROGATE_JOYSTICK], 0), []);
+        assert_eq!(command(&mut ikbd, &[INTERROGATE_JOYSTICK], 0), [JOYSTICK_REPORT, 0, 0]);
+    }
+
+    #[test]
+    fn clock_runs_from_the_time_set() {
+        let mut ikbd = ikbd();
+        assert_eq!(command(&mut ikbd, &[SET_CLOCK, 0x89, 0x12, 0x31, 0x23, 0x59, 0x50], 0), []);
+        let answer = command(&mut ikbd, &[INTERROGATE_CLOCK], 20 * CPU_FREQUENCY);
+        assert_eq!(answer, [TIME_OF_DAY, 0x90, 0x01, 0x01, 0x00, 0x00, 0x10]);
+        // Fields which are no BCD numbers keep their value
+        command(&mut ikbd, &[SET_CLOCK, 0xff, 0xff, 0xff, 0x08, 0xff, 0x00], 20 * CPU_FREQUENCY);
+        let answer = command(&mut ikbd, &[INTERROGATE_CLOCK], 20 * CPU_FREQUENCY);
+        assert_eq!(answer, [TIME_OF_DAY, 0x90, 0x01, 0x01, 0x08, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn absolute_mouse_is_scaled_and_kept_in_bounds() {
+        let mut ikbd = ikbd();
+        command(&mut ikbd, &[ABSOLUTE_MOUSE, 0x01, 0x3f, 0x00, 0xc7, MOUSE_SCALE, 2, 2], 0);
+        command(&mut ikbd, &[LOAD_MOUSE_POSITION, 0, 0x01, 0x00, 0x00, 0x64], 0);
+        ikbd.mouse_move(11, -300);
+        assert_eq!(command(&mut ikbd, &[INTERROGATE_MOUSE], 0), [ABSOLUTE_POSITION, 0, 0x01, 0x05, 0x00, 0x00]);
+        assert_eq!(
+            command(&mut ikbd, &[STATUS | ABSOLUTE_MOUSE], 0),
+            [STATUS_REPORT, ABSOLUTE_MOUSE, 0x01, 0x3f, 0x00, 0xc7, 0, 0]
+        );
+    }
+
+    #[test]
+    fn relative_mouse_packets_split_large_movements() {
+        let mut ikbd = ikbd();
+        ikbd.mouse_move(200, -3);
+        assert_eq!(ikbd.output.drain(..).collect::<Vec<u8>>(), [RELATIVE_POSITION, 127, 0xfd, RELATIVE_POSITION, 73, 0]);
+        command(&mut ikbd, &[Y_AT_BOTTOM, MOUSE_THRESHOLD, 4, 4], 0);
+        ikbd.mouse_move(2, 3);
+        assert!(ikbd.output.is_empty());
+        ikbd.mouse_move(2, 0);
+        assert_eq!(ikbd.output.drain(..).collect::<Vec<u8>>(), [RELATIVE_POSITION, 4, 0xfd]);
+    }
+}
@@ -0,0 +1,411 @@
+// Joysticks. The ST has two joystick ports read by the IKBD, port 0 doubling as the mouse port.
+// The STE adds two enhanced ports at $FF9200 made for Atari's Jaguar joypads: their 17 buttons
+// (A, B, C, pause, option and a keypad) and the stick form a matrix of four rows, which programs
+// select one at a time by writing a mask to $FF9202, and read back from $FF9200 and $FF9202.
+// Plain joysticks work in these ports as well.
+//
+// On the host a joystick is either the keyboard's cursor keys and a fire key, which are then no
+// longer passed on to the ST's keyboard, or a Linux input device: an event device
+// (/dev/input/eventN) or a joystick device (/dev/input/jsN). Ports 0 and 1 are the IKBD's, 2 and
+// 3 the STE's ports A and B.
+
+use super::{read_bytes, write_bytes, Device, InputEvent, Signal};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+use minifb::Key;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+
+// Joystick states, the lowest byte being what the IKBD reports
+pub const UP: u32 = 0x01;
+pub const DOWN: u32 = 0x02;
+pub const LEFT: u32 = 0x04;
+pub const RIGHT: u32 = 0x08;
+pub const FIRE: u32 = 0x80;
+const BUTTON_B: u32 = 0x100;
+const BUTTON_C: u32 = 0x200;
+const PAUSE: u32 = 0x400;
+const OPTION: u32 = 0x800;
+// Keypad keys 0-9, then * and #
+const KEYPAD: u32 = 0x1000;
+const STAR: u32 = KEYPAD << 10;
+const HASH: u32 = KEYPAD << 11;
+
+pub type JoystickStates = Rc<RefCell<[u32; 4]>>;
+
+// The Jaguar joypad's matrix: the four lines of each row and the button on the fire line
+const MATRIX: [([u32; 4], u32); 4] = [
+    ([UP, DOWN, LEFT, RIGHT], FIRE),
+    ([STAR, KEYPAD << 7, KEYPAD << 4, KEYPAD << 1], BUTTON_B),
+    ([KEYPAD, KEYPAD << 8, KEYPAD << 5, KEYPAD << 2], BUTTON_C),
+    ([HASH, KEYPAD << 9, KEYPAD << 6, KEYPAD << 3], OPTION),
+];
+
+// How often input devices are read
+const POLL_CYCLES: u64 = 40000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoystickInput {
+    // The cursor keys and the given fire key
+    Keys(Key),
+    Device(String),
+}
+
+impl JoystickInput {
+    // keys[:space|ctrl|alt|shift|insert] or the path of an input device
+    pub fn from(spec: &str) -> Option<Self> {
+        let fire = match spec {
+            "keys" | "keys:ctrl" => Key::RightCtrl,
+            "keys:space" => Key::Space,
+            "keys:alt" => Key::RightAlt,
+            "keys:shift" => Key::RightShift,
+            "keys:insert" => Key::Insert,
+            path if path.starts_with('/') => return Some(Self::Device(String::from(path))),
+            _ => return None,
+        };
+        Some(Self::Keys(fire))
+    }
+}
+
+// Keys of a joypad played with the keyboard besides the cursor keys and the fire key
+fn joypad_key(key: Key) -> Option<u32> {
+    let button = match key {
+        Key::RightShift => BUTTON_B,
+        Key::RightAlt => BUTTON_C,
+        Key::Pause => PAUSE,
+        Key::Menu => OPTION,
+        Key::NumPadAsterisk => STAR,
+        Key::NumPadSlash => HASH,
+        Key::NumPad0 => KEYPAD,
+        key if (Key::NumPad1 as u8..=Key::NumPad9 as u8).contains(&(key as u8)) => KEYPAD << (key as u8 - Key::NumPad0 as u8),
+        _ => return None,
+    };
+    Some(button)
+}
+
+// Event device codes
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+const EVIOCGVERSION: u32 = 0x80044501;
+const EVIOCGABS: u32 = 0x80184540;
+// Joystick device event types
+const JS_EVENT_BUTTON: u8 = 0x01;
+const JS_EVENT_AXIS: u8 = 0x02;
+const JS_EVENT_INIT: u8 = 0x80;
+
+fn event_button(code: u16) -> u32 {
+    match code {
+        // Trigger and thumb buttons of joysticks, A, B, X and Y of gamepads
+        0x120 | 0x130 => FIRE,
+        0x121 | 0x131 => BUTTON_B,
+        0x122 | 0x133 | 0x134 => BUTTON_C,
+        0x13a => OPTION,
+        0x13b => PAUSE,
+        0x220 => UP,
+        0x221 => DOWN,
+        0x222 => LEFT,
+        0x223 => RIGHT,
+        _ => 0,
+    }
+}
+
+// The directions an axis points to, at least a quarter of its range off the centre
+fn axis_directions(value: i32, (minimum, maximum): (i32, i32), negative: u32, positive: u32) -> u32 {
+    let centre = (minimum + maximum) / 2;
+    let dead_zone = (maximum - minimum) / 4;
+    if value < centre - dead_zone {
+        negative
+    } else if value > centre + dead_zone {
+        positive
+    } else {
+        0
+    }
+}
+
+struct InputDevice {
+    file: File,
+    evdev: bool,
+    // Ranges of the stick's axes, what each of stick, hat and buttons is pressed
+    ranges: [(i32, i32); 2],
+    stick: [u32; 2],
+    hat: [u32; 2],
+    buttons: u32,
+}
+
+impl InputDevice {
+    fn open(path: &str) -> Result<Self, String> {
+        let file = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let fd = file.as_raw_fd();
+        let mut version: libc::c_int = 0;
+        let evdev = unsafe { libc::ioctl(fd, EVIOCGVERSION as _, &mut version) } >= 0;
+        let mut ranges = [(-32767, 32767); 2];
+        if evdev {
+            for (axis, range) in ranges.iter_mut().enumerate() {
+                let mut info: libc::input_absinfo = unsafe { std::mem::zeroed() };
+                if unsafe { libc::ioctl(fd, (EVIOCGABS + axis as u32) as _, &mut info) } >= 0 {
+                    *range = (info.minimum, info.maximum);
+                }
+            }
+        }
+        Ok(Self { file, evdev, ranges, stick: [0; 2], hat: [0; 2], buttons: 0 })
+    }
+    fn state(&self) -> u32 {
+        self.stick[0] | self.stick[1] | self.hat[0] | self.hat[1] | self.buttons
+    }
+    fn set_button(&mut self, button: u32, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+    fn poll(&mut self) {
+        let size = if self.evdev { std::mem::size_of::<libc::input_event>() } else { 8 };
+        let mut buffer = [0u8; 1536];
+        while let Ok(length) = self.file.read(&mut buffer) {
+            if length == 0 {
+                break;
+            }
+            for event in buffer[..length].chunks_exact(size) {
+                if self.evdev {
+                    let event = unsafe { std::ptr::read_unaligned(event.as_ptr() as *const libc::input_event) };
+                    self.event(event.type_, event.code, event.value);
+                } else {
+                    let value = i16::from_ne_bytes([event[4], event[5]]) as i32;
+                    self.js_event(event[6] & !JS_EVENT_INIT, event[7], value);
+                }
+            }
+        }
+    }
+    fn event(&mut self, kind: u16, code: u16, value: i32) {
+        match (kind, code) {
+            (EV_KEY, code) => self.set_button(event_button(code), value != 0),
+            (EV_ABS, ABS_X) => self.stick[0] = axis_directions(value, self.ranges[0], LEFT, RIGHT),
+            (EV_ABS, ABS_Y) => self.stick[1] = axis_directions(value, self.ranges[1], UP, DOWN),
+            (EV_ABS, ABS_HAT0X) => self.hat[0] = axis_directions(value, (-1, 1), LEFT, RIGHT),
+            (EV_ABS, ABS_HAT0Y) => self.hat[1] = axis_directions(value, (-1, 1), UP, DOWN),
+            _ => {}
+        }
+    }
+    // Joystick devices number their buttons and axes, the hat usually being the last two axes
+    fn js_event(&mut self, kind: u8, number: u8, value: i32) {
+        const BUTTONS: [u32; 8] = [FIRE, BUTTON_B, BUTTON_C, BUTTON_C, 0, 0, OPTION, PAUSE];
+        match (kind, number) {
+            (JS_EVENT_BUTTON, number) => self.set_button(BUTTONS.get(number as usize).copied().unwrap_or(0), value != 0),
+            (JS_EVENT_AXIS, 0) => self.stick[0] = axis_directions(value, self.ranges[0], LEFT, RIGHT),
+            (JS_EVENT_AXIS, 1) => self.stick[1] = axis_directions(value, self.ranges[1], UP, DOWN),
+            (JS_EVENT_AXIS, 6) => self.hat[0] = axis_directions(value, self.ranges[0], LEFT, RIGHT),
+            (JS_EVENT_AXIS, 7) => self.hat[1] = axis_directions(value, self.ranges[1], UP, DOWN),
+            _ => {}
+        }
+    }
+}
+
+enum Input {
+    Keys { fire: Key, pressed: u32 },
+    Device(InputDevice),
+}
+
+// Feeds the joystick ports from the host
+pub struct JoystickMapper {
+    inputs: Vec<(usize, Input)>,
+    next_poll: u64,
+    pub states: JoystickStates,
+}
+
+impl JoystickMapper {
+    pub fn new(joysticks: &[(usize, JoystickInput)]) -> Result<Self, String> {
+        let mut inputs = Vec::new();
+        for (port, input) in joysticks {
+            let input = match input {
+                JoystickInput::Keys(fire) => Input::Keys { fire: *fire, pressed: 0 },
+                JoystickInput::Device(path) => Input::Device(InputDevice::open(path)?),
+            };
+            inputs.push((*port, input));
+        }
+        Ok(Self { inputs, next_poll: 0, states: Rc::new(RefCell::new([0; 4])) })
+    }
+    // Takes the keys played as a joystick out of the keyboard's input
+    pub fn map(&mut self, event: InputEvent) -> Option<InputEvent> {
+        if let InputEvent::Key(key, down) = event {
+            let mut mapped = false;
+            for (port, input) in &mut self.inputs {
+                if let Input::Keys { fire, pressed } = input {
+                    let button = match key {
+                        Key::Up => UP,
+                        Key::Down => DOWN,
+                        Key::Left => LEFT,
+                        Key::Right => RIGHT,
+                        key if key == *fire => FIRE,
+                        key if *port >= 2 => joypad_key(key).unwrap_or(0),
+                        _ => 0,
+                    };
+                    if button != 0 {
+                        mapped = true;
+                        if down {
+                            *pressed |= button;
+                        } else {
+                            *pressed &= !button;
+                        }
+                    }
+                }
+            }
+            if mapped {
+                self.update();
+                return None;
+            }
+        }
+        Some(event)
+    }
+    pub fn poll(&mut self, cycles: u64) {
+        if cycles < self.next_poll {
+            return;
+        }
+        self.next_poll = cycles + POLL_CYCLES;
+        for (_, input) in &mut self.inputs {
+            if let Input::Device(device) = input {
+                device.poll();
+            }
+        }
+        self.update();
+    }
+    fn update(&mut self) {
+        let mut states = [0; 4];
+        for (port, input) in &self.inputs {
+            states[*port] |= match input {
+                Input::Keys { pressed, .. } => *pressed,
+                Input::Device(device) => device.state(),
+            };
+        }
+        *self.states.borrow_mut() = states;
+    }
+}
+
+// The STE's enhanced joystick ports. Paddles and light pen are not supported.
+pub struct JoystickPort {
+    address: usize,
+    // Rows selected on port A in bits 0-3 and B in bits 4-7, active low
+    select: u8,
+    states: JoystickStates,
+}
+
+impl JoystickPort {
+    pub fn new(address: usize, states: JoystickStates) -> Box<Self> {
+        Box::new(Self { address, select: 0xff, states })
+    }
+    // The row selected on a port and the state of the joypad in it
+    fn row(&self, port: usize) -> Option<(usize, u32)> {
+        let mask = self.select >> (4 * port);
+        let row = (0..4).find(|row| mask & (1 << row) == 0)?;
+        Some((row, self.states.borrow()[2 + port]))
+    }
+    fn buttons(&self) -> u8 {
+        let mut value = 0xff;
+        for port in 0..2 {
+            if let Some((row, state)) = self.row(port) {
+                if row == 0 && state & PAUSE != 0 {
+                    value &= !(1 << (2 * port));
+                }
+                if state & MATRIX[row].1 != 0 {
+                    value &= !(2 << (2 * port));
+                }
+            }
+        }
+        value
+    }
+    fn lines(&self) -> u8 {
+        let mut value = 0xff;
+        for port in 0..2 {
+            if let Some((row, state)) = self.row(port) {
+                for (line, button) in MATRIX[row].0.iter().enumerate() {
+                    if state & button != 0 {
+                        value &= !(1 << (4 * port + line));
+                    }
+                }
+            }
+        }
+        value
+    }
+}
+
+impl Device for JoystickPort {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x24)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| match a - self.address {
+            1 => self.buttons(),
+            2 => self.lines(),
+            3 => self.select,
+            _ => 0xff,
+        })
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| {
+            if a - self.address == 3 {
+                self.select = b;
+            }
+        });
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(states: [u32; 4], select: u8) -> (u8, u8) {
+        let mut port = JoystickPort::new(0xff9200, Rc::new(RefCell::new(states)));
+        port.write(0xff9203, OpResult::Byte(select));
+        (port.read(0xff9201, Size::Byte).inner() as u8, port.read(0xff9202, Size::Byte).inner() as u8)
+    }
+
+    #[test]
+    fn rows_put_their_buttons_on_the_lines() {
+        // Port A's first row, with the stick and fire button, and pause on its own line
+        assert_eq!(port([0, 0, UP | RIGHT | FIRE | PAUSE, 0], 0xfe), (0xfc, 0xf6));
+        // Port B's second row, keys *, 7, 4 and 1 and button B, but not pause
+        assert_eq!(port([0, 0, 0, STAR | KEYPAD << 4 | BUTTON_B | PAUSE], 0xdf), (0xf7, 0xaf));
+        // Both ports at once, the third row on A and the fourth on B
+        assert_eq!(port([0, 0, KEYPAD << 8 | BUTTON_C, HASH | OPTION], 0x7b), (0xf5, 0xed));
+        // Buttons of other rows are not read
+        assert_eq!(port([0, 0, UP | FIRE | KEYPAD, HASH], 0xfd), (0xff, 0xff));
+        // Nor is any row when none is selected
+        assert_eq!(port([0, 0, UP | FIRE | PAUSE, UP | FIRE | PAUSE], 0xff), (0xff, 0xff));
+    }
+
+    #[test]
+    fn keys_are_mapped_to_their_ports() {
+        let mut mapper =
+            JoystickMapper::new(&[(1, JoystickInput::Keys(Key::RightCtrl)), (2, JoystickInput::Keys(Key::Space))]).unwrap();
+        assert!(mapper.map(InputEvent::Key(Key::Up, true)).is_none());
+        assert!(mapper.map(InputEvent::Key(Key::RightCtrl, true)).is_none());
+        assert_eq!(*mapper.states.borrow(), [0, UP | FIRE, UP, 0]);
+        // Joypad keys only on the STE's ports
+        assert!(mapper.map(InputEvent::Key(Key::NumPad7, true)).is_none());
+        assert!(mapper.map(InputEvent::Key(Key::Pause, true)).is_none());
+        assert!(mapper.map(InputEvent::Key(Key::Space, true)).is_none());
+        assert_eq!(*mapper.states.borrow(), [0, UP | FIRE, UP | FIRE | PAUSE | KEYPAD << 7, 0]);
+        assert!(mapper.map(InputEvent::Key(Key::Up, false)).is_none());
+        assert!(mapper.map(InputEvent::Key(Key::NumPad7, false)).is_none());
+        assert_eq!(*mapper.states.borrow(), [0, FIRE, FIRE | PAUSE, 0]);
+        // Other keys are left to the keyboard
+        assert!(matches!(mapper.map(InputEvent::Key(Key::A, true)), Some(InputEvent::Key(Key::A, true))));
+        assert!(matches!(mapper.map(InputEvent::MouseMove(1, 2)), Some(InputEvent::MouseMove(1, 2))));
+    }
+}
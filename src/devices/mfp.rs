@@ -0,0 +1,744 @@
+// The MC68901 multi function peripheral. On the ST it collects the interrupts of most other
+// peripherals on its general purpose I/O port (GPIP) and hands them to the CPU as vectored
+// level 6 interrupts.
+// Interrupt channels are numbered by priority, channel 15 being the highest. Channels 8-15 are
+// controlled by the "A" registers (IERA, IPRA, ...), channels 0-7 by the "B" registers.
+//
+// The four timers count down at the MFP's clock of 2.4576 MHz, divided by a prescaler, and
+// request an interrupt whenever they reach zero, reloading from their data register. Timers A
+// and B can also count the edges of their inputs TAI and TBI (event count mode) or count only
+// while the input is active (pulse width mode). On the ST, Timer C is the 200 Hz system tick,
+// Timer B counts the lines displayed, and on the STE Timer A counts the frames of DMA sound.
+//
+// The USART is the ST's RS-232 port. Timer D provides its clock, which is divided by 16 (or 1),
+// so each character takes as long as its start, data, parity and stop bits at that rate. The
+// characters are exchanged with a SerialLink on the host.
+
+use super::serial::SerialLink;
+use super::{read_bytes, write_bytes, Device, EdgeCounter, Signal, SignalLine, CPU_FREQUENCY};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+
+const GPIP: usize = 0x00;
+const AER: usize = 0x01;
+const DDR: usize = 0x02;
+const IERA: usize = 0x03;
+const IERB: usize = 0x04;
+const IPRA: usize = 0x05;
+const IPRB: usize = 0x06;
+const ISRA: usize = 0x07;
+const ISRB: usize = 0x08;
+const IMRA: usize = 0x09;
+const IMRB: usize = 0x0a;
+const VR: usize = 0x0b;
+const TACR: usize = 0x0c;
+const TBCR: usize = 0x0d;
+const TCDCR: usize = 0x0e;
+const TADR: usize = 0x0f;
+const TDDR: usize = 0x12;
+const UCR: usize = 0x14;
+const RSR: usize = 0x15;
+const TSR: usize = 0x16;
+const UDR: usize = 0x17;
+
+// USART control register
+const DIVIDE_BY_16: u8 = 0x80;
+const PARITY: u8 = 0x04;
+// Receiver and transmitter status registers
+const BUFFER_FULL: u8 = 0x80;
+const BUFFER_EMPTY: u8 = 0x80;
+const ENABLE: u8 = 0x01;
+
+// Interrupt channel of each GPIP bit
+const GPIP_CHANNELS: [usize; 8] = [0, 1, 2, 3, 6, 7, 14, 15];
+
+const INTERRUPT_LEVEL: u32 = 6;
+
+// Timers A-D: interrupt channel and the AER bit selecting the active edge of their input
+const TIMER_CHANNELS: [usize; 4] = [13, 8, 5, 4];
+const TIMER_INPUT_EDGES: [u8; 2] = [0x10, 0x08];
+const PRESCALERS: [u64; 8] = [0, 4, 10, 16, 50, 64, 100, 200];
+const MFP_FREQUENCY: u64 = 2_457_600;
+
+const TRANSMIT_EMPTY: usize = 10;
+const RECEIVE_FULL: usize = 12;
+
+#[derive(Clone, Copy, PartialEq)]
+enum TimerMode {
+    Stopped,
+    Delay(u64),
+    EventCount,
+    PulseWidth(u64),
+}
+
+#[derive(Default)]
+struct Timer {
+    control: u8,
+    data: u8,
+    counter: u8,
+    // MFP clock cycles not yet counted by the prescaler
+    prescaler: u64,
+    input: Option<SignalLine>,
+    level: bool,
+    // Counts the input's level changes if it may change several times between two clocks
+    edges: Option<EdgeCounter>,
+    seen_edges: u32,
+}
+
+impl Timer {
+    fn mode(&self) -> TimerMode {
+        match self.control & 0xf {
+            0 => TimerMode::Stopped,
+            8 => TimerMode::EventCount,
+            control if control > 8 => TimerMode::PulseWidth(PRESCALERS[control as usize & 0x7]),
+            control => TimerMode::Delay(PRESCALERS[control as usize]),
+        }
+    }
+    // Counts down the given number of times and returns how often the timer ran out. A counter
+    // or data register of 0 counts 256.
+    fn count(&mut self, ticks: u64) -> u64 {
+        let counter = match self.counter {
+            0 => 256,
+            counter => counter as u64,
+        };
+        if ticks < counter {
+            self.counter = (counter - ticks) as u8;
+            return 0;
+        }
+        let period = match self.data {
+            0 => 256,
+            data => data as u64,
+        };
+        let remaining = ticks - counter;
+        self.counter = (period - remaining % period) as u8;
+        1 + remaining / period
+    }
+    // Advances the prescaler by the given number of MFP clock cycles
+    fn prescale(&mut self, clocks: u64, prescaler: u64) -> u64 {
+        let clocks = self.prescaler + clocks;
+        self.prescaler = clocks % prescaler;
+        self.count(clocks / prescaler)
+    }
+}
+
+pub struct MultiFunctionPeripheral {
+    address: usize,
+    registers: [u8; 24],
+    inputs: Vec<(usize, SignalLine)>,
+    xor_inputs: Vec<(usize, SignalLine)>,
+    gpip_input: u8,
+    ier: u16,
+    ipr: u16,
+    isr: u16,
+    imr: u16,
+    timers: [Timer; 4],
+    clocks: u64,
+    serial: Option<SerialLink>,
+    received: u8,
+    // The character being sent and when it started
+    transmitting: Option<(u8, u64)>,
+    next_receive: u64,
+}
+
+impl MultiFunctionPeripheral {
+    pub fn new(address: usize) -> Box<Self> {
+        let mut registers = [0; 24];
+        registers[TSR] = BUFFER_EMPTY;
+        Box::new(Self {
+            address,
+            registers,
+            inputs: Vec::new(),
+            xor_inputs: Vec::new(),
+            gpip_input: 0xff,
+            ier: 0,
+            ipr: 0,
+            isr: 0,
+            imr: 0,
+            timers: Default::default(),
+            clocks: 0,
+            serial: None,
+            received: 0,
+            transmitting: None,
+            next_receive: 0,
+        })
+    }
+    // Wires an (active low) interrupt line to the given GPIP bit. Several lines may share one bit,
+    // in which case any of them pulls it low.
+    pub fn connect(&mut self, bit: usize, line: SignalLine) {
+        self.inputs.push((bit, line));
+        self.gpip_input = self.input_levels();
+    }
+    // Wires a line through an XOR gate in front of the given GPIP bit: while it is active, it
+    // inverts the level the other lines give the bit.
+    pub fn connect_xor(&mut self, bit: usize, line: SignalLine) {
+        self.xor_inputs.push((bit, line));
+        self.gpip_input = self.input_levels();
+    }
+    // Wires the input of Timer A (0) or B (1). Unlike the GPIP lines, the line carries the level
+    // of the input (true is high).
+    pub fn connect_timer(&mut self, timer: usize, line: SignalLine) {
+        self.timers[timer].level = *line.borrow();
+        self.timers[timer].input = Some(line);
+    }
+    // Adds the count of the level changes of a timer's input, so that none are missed when it
+    // changes several times within a clock
+    pub fn connect_timer_edges(&mut self, timer: usize, edges: EdgeCounter) {
+        self.timers[timer].seen_edges = *edges.borrow();
+        self.timers[timer].edges = Some(edges);
+    }
+    pub fn connect_serial(&mut self, link: SerialLink) {
+        self.serial = Some(link);
+    }
+    fn input_levels(&self) -> u8 {
+        let mut levels = 0xff;
+        for (bit, line) in &self.inputs {
+            if *line.borrow() {
+                levels &= !(1 << bit);
+            }
+        }
+        for (bit, line) in &self.xor_inputs {
+            if *line.borrow() {
+                levels ^= 1 << bit;
+            }
+        }
+        levels
+    }
+    fn gpip(&self) -> u8 {
+        let ddr = self.registers[DDR];
+        (self.registers[GPIP] & ddr) | (self.gpip_input & !ddr)
+    }
+    fn update_inputs(&mut self) {
+        let levels = self.input_levels();
+        let changed = (levels ^ self.gpip_input) & !self.registers[DDR];
+        let aer = self.registers[AER];
+        for (bit, &channel) in GPIP_CHANNELS.iter().enumerate() {
+            let mask = 1 << bit;
+            // The active edge register selects falling (0) or rising (1) edges
+            if changed & mask != 0 && (levels & mask == aer & mask) {
+                self.request(channel);
+            }
+        }
+        self.gpip_input = levels;
+    }
+    fn update_timers(&mut self, clocks: u64) {
+        let elapsed = clocks - self.clocks;
+        self.clocks = clocks;
+        for timer in 0..4 {
+            let (edge, previous, changes) = match &self.timers[timer].input {
+                Some(line) => {
+                    let edge = TIMER_INPUT_EDGES[timer];
+                    let level = *line.borrow();
+                    let previous = std::mem::replace(&mut self.timers[timer].level, level);
+                    let changes = match &self.timers[timer].edges {
+                        Some(edges) => {
+                            let edges = *edges.borrow();
+                            edges.wrapping_sub(std::mem::replace(&mut self.timers[timer].seen_edges, edges))
+                        }
+                        None => (level != previous) as u32,
+                    };
+                    (self.registers[AER] & edge != 0, previous, changes)
+                }
+                None => (false, self.timers[timer].level, 0),
+            };
+            let level = self.timers[timer].level;
+            let timeouts = match self.timers[timer].mode() {
+                TimerMode::Stopped => 0,
+                TimerMode::Delay(prescaler) => self.timers[timer].prescale(elapsed, prescaler),
+                // The active edge is the one selected in the AER for GPIP 4 (TAI) or 3 (TBI)
+                // The level changes alternate, the first one leading away from the previous level
+                TimerMode::EventCount if previous == edge => self.timers[timer].count(changes as u64 / 2),
+                TimerMode::EventCount => self.timers[timer].count((changes as u64).div_ceil(2)),
+                // Pulses end with the active edge, before that the timer counts
+                TimerMode::PulseWidth(prescaler) if level != edge => self.timers[timer].prescale(elapsed, prescaler),
+                TimerMode::PulseWidth(_) => 0,
+            };
+            if timeouts > 0 {
+                self.request(TIMER_CHANNELS[timer]);
+            }
+        }
+    }
+    // MFP clock cycles per character, unless Timer D is stopped
+    fn character_time(&self) -> Option<u64> {
+        let prescaler = match self.timers[3].mode() {
+            TimerMode::Delay(prescaler) => prescaler,
+            _ => return None,
+        };
+        let ucr = self.registers[UCR];
+        let divider = if ucr & DIVIDE_BY_16 != 0 { 16 } else { 1 };
+        let data = match self.timers[3].data {
+            0 => 256,
+            data => data as u64,
+        };
+        // Counted in half bits for the 1.5 stop bits format. Timer D's output toggles each time it
+        // runs out, so a bit lasts twice its period.
+        let half_bits = 2 * (1 + 8 - (ucr as u64 >> 5 & 0x3) + (ucr & PARITY != 0) as u64) + [0, 2, 3, 4][ucr as usize >> 3 & 0x3];
+        Some(half_bits * divider * prescaler * data)
+    }
+    fn update_usart(&mut self) {
+        let time = match self.character_time() {
+            Some(time) => time,
+            None => return,
+        };
+        if let Some((byte, start)) = self.transmitting {
+            if self.clocks >= start + time {
+                self.transmitting = None;
+                if let Some(link) = self.serial.as_mut() {
+                    link.send(byte);
+                }
+                self.registers[TSR] |= BUFFER_EMPTY;
+                self.request(TRANSMIT_EMPTY);
+            }
+        }
+        // The host is asked for a character only once the last one has been read, so none get lost
+        let rsr = self.registers[RSR];
+        if rsr & ENABLE != 0 && rsr & BUFFER_FULL == 0 && self.clocks >= self.next_receive {
+            self.next_receive = self.clocks + time;
+            if let Some(byte) = self.serial.as_mut().and_then(|link| link.receive()) {
+                self.received = byte;
+                self.registers[RSR] |= BUFFER_FULL;
+                self.request(RECEIVE_FULL);
+            }
+        }
+    }
+    fn write_timer_control(&mut self, timer: usize, value: u8) {
+        let mode = self.timers[timer].mode();
+        self.timers[timer].control = value;
+        if self.timers[timer].mode() != mode {
+            self.timers[timer].prescaler = 0;
+        }
+    }
+    fn request(&mut self, channel: usize) {
+        if self.ier & (1 << channel) != 0 {
+            self.ipr |= 1 << channel;
+        }
+    }
+    fn software_end_of_interrupt(&self) -> bool {
+        self.registers[VR] & 0x08 != 0
+    }
+    // The highest pending channel that is not masked
+    fn requesting_channel(&self) -> Option<usize> {
+        let requests = self.ipr & self.imr;
+        if requests == 0 {
+            return None;
+        }
+        let channel = 15 - requests.leading_zeros() as usize;
+        // Interrupts of lower or equal priority wait until the current one has been serviced
+        if self.isr >> channel != 0 {
+            return None;
+        }
+        Some(channel)
+    }
+    fn read_register(&mut self, register: usize) -> u8 {
+        match register {
+            GPIP => self.gpip(),
+            IERA => (self.ier >> 8) as u8,
+            IERB => self.ier as u8,
+            IPRA => (self.ipr >> 8) as u8,
+            IPRB => self.ipr as u8,
+            ISRA => (self.isr >> 8) as u8,
+            ISRB => self.isr as u8,
+            IMRA => (self.imr >> 8) as u8,
+            IMRB => self.imr as u8,
+            TACR | TBCR => self.timers[register - TACR].control,
+            TCDCR => self.timers[2].control << 4 | self.timers[3].control,
+            // The data registers read back the counter
+            TADR..=TDDR => self.timers[register - TADR].counter,
+            UDR => {
+                self.registers[RSR] &= !BUFFER_FULL;
+                self.received
+            }
+            _ => self.registers[register],
+        }
+    }
+    fn write_register(&mut self, register: usize, value: u8) {
+        let value = value as u16;
+        match register {
+            IERA => {
+                self.ier = (self.ier & 0x00ff) | (value << 8);
+                self.ipr &= self.ier;
+            }
+            IERB => {
+                self.ier = (self.ier & 0xff00) | value;
+                self.ipr &= self.ier;
+            }
+            // Pending and in service bits can only be cleared by writing zeroes
+            IPRA => self.ipr &= (value << 8) | 0x00ff,
+            IPRB => self.ipr &= value | 0xff00,
+            ISRA => self.isr &= (value << 8) | 0x00ff,
+            ISRB => self.isr &= value | 0xff00,
+            IMRA => self.imr = (self.imr & 0x00ff) | (value << 8),
+            IMRB => self.imr = (self.imr & 0xff00) | value,
+            VR => {
+                self.registers[VR] = value as u8;
+                if !self.software_end_of_interrupt() {
+                    self.isr = 0;
+                }
+            }
+            TACR | TBCR => self.write_timer_control(register - TACR, value as u8 & 0x1f),
+            TCDCR => {
+                self.write_timer_control(2, (value as u8 >> 4) & 0x7);
+                self.write_timer_control(3, value as u8 & 0x7);
+            }
+            TADR..=TDDR => {
+                let timer = &mut self.timers[register - TADR];
+                timer.data = value as u8;
+                // A stopped timer loads the counter as well
+                if timer.mode() == TimerMode::Stopped {
+                    timer.counter = value as u8;
+                }
+            }
+            // The status bits are read only
+            RSR | TSR => self.registers[register] = (self.registers[register] & 0xd0) | (value as u8 & 0x2f),
+            UDR => {
+                if self.registers[TSR] & ENABLE != 0 && self.transmitting.is_none() {
+                    self.transmitting = Some((value as u8, self.clocks));
+                    self.registers[TSR] &= !BUFFER_EMPTY;
+                }
+            }
+            _ => self.registers[register] = value as u8,
+        }
+    }
+    fn read_byte(&mut self, address: usize) -> u8 {
+        let offset = address - (self.address & !1);
+        if offset & 1 == 0 || offset / 2 >= self.registers.len() {
+            return 0xff;
+        }
+        self.read_register(offset / 2)
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        let offset = address - (self.address & !1);
+        if offset & 1 != 0 && offset / 2 < self.registers.len() {
+            self.write_register(offset / 2, value);
+        }
+    }
+}
+
+impl Device for MultiFunctionPeripheral {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address & !1, (self.address & !1) + 0x30)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| self.write_byte(a, b));
+        self.update_inputs();
+        Signal::Ok
+    }
+    // The request stays pending until the CPU acknowledges it, so that the program can still
+    // withdraw it by disabling or masking the channel or clearing its pending bit
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        let channel = self.requesting_channel()?;
+        let vector = (self.registers[VR] & 0xf0) as usize + channel;
+        Some(IRQ { level: INTERRUPT_LEVEL, vector: Some(vector) })
+    }
+    // Only the channel requesting right now is taken, which may be a higher one than asked for
+    // before
+    fn interrupt_acknowledge(&mut self, irq: &IRQ) -> bool {
+        let vector = match irq.vector {
+            Some(vector) if irq.level == INTERRUPT_LEVEL && vector & 0xf0 == (self.registers[VR] & 0xf0) as usize => vector,
+            _ => return true,
+        };
+        if self.requesting_channel() != Some(vector & 0xf) {
+            return false;
+        }
+        let channel = vector & 0xf;
+        self.ipr &= !(1 << channel);
+        if self.software_end_of_interrupt() {
+            self.isr |= 1 << channel;
+        }
+        true
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        self.update_inputs();
+        self.update_timers(cycles * MFP_FREQUENCY / CPU_FREQUENCY);
+        self.update_usart();
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{edge_counter, signal_line};
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::rc::Rc;
+
+    fn write(mfp: &mut MultiFunctionPeripheral, register: usize, value: u8) {
+        mfp.write(0xfffa01 + 2 * register, OpResult::Byte(value));
+    }
+
+    fn read(mfp: &mut MultiFunctionPeripheral, register: usize) -> u8 {
+        mfp.read(0xfffa01 + 2 * register, Size::Byte).inner() as u8
+    }
+
+    fn irq(vector: usize) -> IRQ {
+        IRQ { level: INTERRUPT_LEVEL, vector: Some(vector) }
+    }
+
+    // Timer A (channel 13) and Timer C (channel 5) enabled, vectors from $40, software EOI
+    fn mfp() -> Box<MultiFunctionPeripheral> {
+        let mut mfp = MultiFunctionPeripheral::new(0xfffa01);
+        write(&mut mfp, VR, 0x48);
+        write(&mut mfp, IERA, 0x20);
+        write(&mut mfp, IMRA, 0x20);
+        write(&mut mfp, IERB, 0x20);
+        write(&mut mfp, IMRB, 0x20);
+        mfp
+    }
+
+    #[test]
+    fn xor_input_inverts_the_gpip_bit() {
+        let mut mfp = mfp();
+        let (monochrome, active) = (signal_line(), signal_line());
+        mfp.connect(7, Rc::clone(&monochrome));
+        mfp.connect_xor(7, Rc::clone(&active));
+        let mut gpip7 = |monochrome_level: bool, active_level: bool| {
+            *monochrome.borrow_mut() = monochrome_level;
+            *active.borrow_mut() = active_level;
+            mfp.clock(0);
+            read(&mut mfp, GPIP) >> 7
+        };
+        assert_eq!(gpip7(false, false), 1);
+        assert_eq!(gpip7(true, false), 0);
+        assert_eq!(gpip7(true, true), 1);
+        assert_eq!(gpip7(false, true), 0);
+    }
+
+    #[test]
+    fn request_stays_pending_until_acknowledged() {
+        let mut mfp = mfp();
+        mfp.request(13);
+        assert_eq!(mfp.interrupt_request(), Some(irq(0x4d)));
+        assert_eq!(mfp.interrupt_request(), Some(irq(0x4d)));
+        assert_eq!(read(&mut mfp, IPRA), 0x20);
+        assert_eq!(read(&mut mfp, ISRA), 0);
+        assert!(mfp.interrupt_acknowledge(&irq(0x4d)));
+        assert_eq!(read(&mut mfp, IPRA), 0);
+        assert_eq!(read(&mut mfp, ISRA), 0x20);
+        assert_eq!(mfp.interrupt_request(), None);
+    }
+
+    #[test]
+    fn withdrawn_requests_are_not_acknowledged() {
+        let mut mfp = mfp();
+        mfp.request(13);
+        let requested = mfp.interrupt_request().unwrap();
+        write(&mut mfp, IMRA, 0);
+        assert_eq!(mfp.interrupt_request(), None);
+        assert!(!mfp.interrupt_acknowledge(&requested));
+        assert_eq!(read(&mut mfp, ISRA), 0);
+        // Unmasked again it is still pending
+        write(&mut mfp, IMRA, 0x20);
+        assert!(mfp.interrupt_acknowledge(&requested));
+        write(&mut mfp, ISRA, 0);
+        mfp.request(5);
+        let requested = mfp.interrupt_request().unwrap();
+        write(&mut mfp, IPRB, !0x20);
+        assert!(!mfp.interrupt_acknowledge(&requested));
+    }
+
+    #[test]
+    fn acknowledge_takes_the_highest_channel() {
+        let mut mfp = mfp();
+        mfp.request(5);
+        let lower = mfp.interrupt_request().unwrap();
+        mfp.request(13);
+        assert!(!mfp.interrupt_acknowledge(&lower));
+        assert!(mfp.interrupt_acknowledge(&irq(0x4d)));
+        // Channel 13 in service holds back channel 5 until its end of interrupt
+        assert_eq!(mfp.interrupt_request(), None);
+        write(&mut mfp, ISRA, 0);
+        assert_eq!(mfp.interrupt_request(), Some(lower));
+        // Other devices' interrupts are none of the MFP's business
+        assert!(mfp.interrupt_acknowledge(&IRQ { level: 4, vector: None }));
+    }
+
+    // Clocks the MFP up to the given MFP clock cycle
+    fn clock(mfp: &mut MultiFunctionPeripheral, clocks: u64) {
+        mfp.clock((clocks * CPU_FREQUENCY).div_ceil(MFP_FREQUENCY));
+    }
+
+    #[test]
+    fn delay_mode_prescalers() {
+        for (control, &prescaler) in PRESCALERS.iter().enumerate().skip(1) {
+            let mut timer = Timer { control: control as u8, data: 10, counter: 10, ..Default::default() };
+            assert!(timer.mode() == TimerMode::Delay(prescaler));
+            assert_eq!(timer.prescale(10 * prescaler - 1, prescaler), 0, "prescaler {}", prescaler);
+            assert_eq!(timer.counter, 1);
+            assert_eq!(timer.prescale(1, prescaler), 1, "prescaler {}", prescaler);
+            assert_eq!((timer.counter, timer.prescaler), (10, 0));
+        }
+    }
+
+    #[test]
+    fn delay_mode_reloads_from_the_data_register() {
+        let mut timer = Timer { control: 1, data: 10, counter: 3, ..Default::default() };
+        // 3 ticks to the first timeout, then 10 each
+        assert_eq!(timer.prescale(4 * 25 + 2, 4), 3);
+        assert_eq!((timer.counter, timer.prescaler), (8, 2));
+        // A data register of 0 counts 256
+        let mut timer = Timer { control: 1, data: 0, counter: 0, ..Default::default() };
+        assert_eq!(timer.count(255), 0);
+        assert_eq!(timer.count(1), 1);
+        assert_eq!(timer.counter, 0);
+    }
+
+    #[test]
+    fn system_tick_runs_at_200_hz() {
+        let mut mfp = mfp();
+        // Timer C: 192 counts, prescaler 64. The data goes in first to load the counter too.
+        write(&mut mfp, TADR + 2, 192);
+        write(&mut mfp, TCDCR, 0x50);
+        clock(&mut mfp, 64 * 192 - 1);
+        assert_eq!(read(&mut mfp, IPRB), 0);
+        assert_eq!(read(&mut mfp, TADR + 2), 1);
+        clock(&mut mfp, 64 * 192);
+        assert_eq!(read(&mut mfp, IPRB), 0x20);
+        assert_eq!(read(&mut mfp, TADR + 2), 192);
+    }
+
+    #[test]
+    fn event_count_mode_counts_active_edges() {
+        let mut mfp = mfp();
+        let input = signal_line();
+        mfp.connect_timer(0, Rc::clone(&input));
+        write(&mut mfp, TADR, 2);
+        write(&mut mfp, TACR, 8);
+        // With AER bit 4 clear, falling edges count
+        let mut set = |level: bool| {
+            *input.borrow_mut() = level;
+            mfp.clock(0);
+            read(&mut mfp, IPRA) != 0
+        };
+        assert!(!set(true));
+        assert!(!set(false));
+        assert!(!set(true));
+        assert!(set(false));
+        assert_eq!(read(&mut mfp, TADR), 2);
+    }
+
+    #[test]
+    fn event_count_mode_counts_edges_missed_between_clocks() {
+        let mut mfp = mfp();
+        let (input, edges) = (signal_line(), edge_counter());
+        mfp.connect_timer(1, Rc::clone(&input));
+        mfp.connect_timer_edges(1, Rc::clone(&edges));
+        write(&mut mfp, IERA, 0x21);
+        write(&mut mfp, IMRA, 0x21);
+        write(&mut mfp, TADR + 1, 3);
+        write(&mut mfp, TBCR, 8);
+        // Two whole lines and the start of a third, of which the falling edges count
+        *edges.borrow_mut() += 5;
+        *input.borrow_mut() = true;
+        mfp.clock(0);
+        assert_eq!(read(&mut mfp, TADR + 1), 1);
+        *edges.borrow_mut() += 1;
+        *input.borrow_mut() = false;
+        mfp.clock(0);
+        assert_eq!(read(&mut mfp, IPRA), 0x01);
+        // With AER bit 3 set, rising edges count
+        write(&mut mfp, AER, 0x08);
+        *edges.borrow_mut() += 4;
+        mfp.clock(0);
+        assert_eq!(read(&mut mfp, TADR + 1), 1);
+    }
+
+    // Transmitter and receiver enabled with their interrupts, 8N1 at 2560 MFP clocks a character
+    // (Timer D prescaler 4 and data 2, divided by 16)
+    fn usart(link: SerialLink) -> Box<MultiFunctionPeripheral> {
+        let mut mfp = mfp();
+        write(&mut mfp, IERA, 0x34);
+        write(&mut mfp, IMRA, 0x34);
+        write(&mut mfp, TDDR, 2);
+        write(&mut mfp, TCDCR, 0x01);
+        write(&mut mfp, UCR, 0x88);
+        write(&mut mfp, TSR, ENABLE);
+        write(&mut mfp, RSR, ENABLE);
+        mfp.connect_serial(link);
+        mfp
+    }
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("em68k-usart-{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn transmitting_takes_a_character_time() {
+        let path = path("file");
+        let mut mfp = usart(SerialLink::open(&format!("file:{}", path)).unwrap());
+        clock(&mut mfp, 100);
+        write(&mut mfp, UDR, b'A');
+        assert_eq!(read(&mut mfp, TSR) & BUFFER_EMPTY, 0);
+        clock(&mut mfp, 100 + 2559);
+        assert_eq!(read(&mut mfp, TSR) & BUFFER_EMPTY, 0);
+        assert_eq!(read(&mut mfp, IPRA), 0);
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        clock(&mut mfp, 100 + 2560);
+        assert_eq!(read(&mut mfp, TSR) & BUFFER_EMPTY, BUFFER_EMPTY);
+        assert_eq!(read(&mut mfp, IPRA), 0x04);
+        assert_eq!(std::fs::read(&path).unwrap(), b"A");
+        // Nothing is sent with the transmitter disabled
+        write(&mut mfp, TSR, 0);
+        write(&mut mfp, UDR, b'B');
+        assert_eq!(read(&mut mfp, TSR) & BUFFER_EMPTY, BUFFER_EMPTY);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn receiving_takes_a_character_time() {
+        let path = path("receive");
+        let mut mfp = usart(SerialLink::open(&format!("unix:{}", path)).unwrap());
+        let mut peer = UnixStream::connect(&path).unwrap();
+        peer.write_all(b"hi").unwrap();
+        clock(&mut mfp, 100);
+        assert_eq!(read(&mut mfp, RSR) & BUFFER_FULL, BUFFER_FULL);
+        assert_eq!(read(&mut mfp, IPRA), 0x10);
+        assert_eq!(read(&mut mfp, UDR), b'h');
+        assert_eq!(read(&mut mfp, RSR) & BUFFER_FULL, 0);
+        write(&mut mfp, IPRA, 0);
+        clock(&mut mfp, 100 + 2559);
+        assert_eq!(read(&mut mfp, RSR) & BUFFER_FULL, 0);
+        clock(&mut mfp, 100 + 2560);
+        assert_eq!(read(&mut mfp, IPRA), 0x10);
+        assert_eq!(read(&mut mfp, UDR), b'i');
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn a_peer_is_taken_when_only_transmitting() {
+        let path = path("transmit");
+        let mut mfp = usart(SerialLink::open(&format!("unix:{}", path)).unwrap());
+        write(&mut mfp, RSR, 0);
+        let mut peer = UnixStream::connect(&path).unwrap();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        write(&mut mfp, UDR, b'A');
+        clock(&mut mfp, 2560);
+        let mut byte = [0];
+        peer.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"A");
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn pulse_width_mode_counts_while_the_input_is_inactive() {
+        let mut mfp = mfp();
+        let input = signal_line();
+        mfp.connect_timer(0, Rc::clone(&input));
+        write(&mut mfp, TADR, 100);
+        // Prescaler 4, counting while TAI is high (AER bit 4 clear)
+        write(&mut mfp, TACR, 9);
+        *input.borrow_mut() = true;
+        clock(&mut mfp, 4 * 10);
+        assert_eq!(read(&mut mfp, TADR), 90);
+        *input.borrow_mut() = false;
+        clock(&mut mfp, 4 * 20);
+        assert_eq!(read(&mut mfp, TADR), 90);
+    }
+}
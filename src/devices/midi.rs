@@ -0,0 +1,475 @@
+// The MIDI ports, an ACIA like the keyboard's running at 31250 baud. On the host the output is
+// recorded into a Standard MIDI File, each message stamped with the emulated time it was sent
+// at. Input is either played from a Standard MIDI File (*.mid), starting when the emulation does,
+// or read as it comes from anything delivering raw MIDI bytes, e.g. a FIFO or an ALSA raw MIDI
+// device (/dev/snd/midiC0D0).
+
+use super::acia::ACIA;
+use super::{read_bytes, write_bytes, Device, Signal, SignalLine, CPU_FREQUENCY};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::rc::Rc;
+
+// 10 bits at 31250 baud
+const CYCLES_PER_BYTE: u64 = 2560;
+
+// Recorded files count time in milliseconds: 500 ticks per quarter note at 120 bpm
+const TICKS_PER_QUARTER: u16 = 500;
+const TEMPO: u32 = 500000;
+
+fn variable_length(mut value: u64) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.insert(0, (value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes
+}
+
+// Number of data bytes following a status byte
+fn data_length(status: u8) -> usize {
+    match status {
+        0xc0..=0xdf | 0xf1 | 0xf3 => 1,
+        0x80..=0xef | 0xf2 => 2,
+        _ => 0,
+    }
+}
+
+// Puts the bytes sent into whole messages. Real time messages (clock, active sensing) are left
+// out of the recording.
+#[derive(Default)]
+struct MessageParser {
+    status: u8,
+    message: Vec<u8>,
+}
+
+impl MessageParser {
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            0xf8..=0xff => return None,
+            0xf7 if self.status == 0xf0 => {
+                self.message.push(byte);
+                self.status = 0;
+                return Some(std::mem::take(&mut self.message));
+            }
+            0xf7 => return None,
+            0x80..=0xff => {
+                self.status = byte;
+                self.message = vec![byte];
+            }
+            _ if self.status == 0 => return None,
+            _ => {
+                // Running status: the data bytes of another message like the last one
+                if self.message.is_empty() {
+                    self.message.push(self.status);
+                }
+                self.message.push(byte);
+            }
+        }
+        if self.status != 0xf0 && self.message.len() == data_length(self.status) + 1 {
+            // System common messages end running status
+            if self.status >= 0xf0 {
+                self.status = 0;
+            }
+            return Some(std::mem::take(&mut self.message));
+        }
+        None
+    }
+}
+
+// A Standard MIDI File with one track, kept complete after every message written, such that
+// nothing is lost however the emulation ends
+struct MIDIRecording {
+    file: File,
+    length: u64,
+    tick: u64,
+}
+
+impl MIDIRecording {
+    fn create(path: &str) -> Result<Self, String> {
+        let mut file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut header = b"MThd\0\0\0\x06\0\0\0\x01".to_vec();
+        header.extend(&TICKS_PER_QUARTER.to_be_bytes());
+        header.extend(b"MTrk\0\0\0\0");
+        file.write_all(&header).map_err(|e| format!("{}: {}", path, e))?;
+        let mut recording = Self { file, length: 0, tick: 0 };
+        let tempo = TEMPO.to_be_bytes();
+        recording.write(0, &[0xff, 0x51, 0x03, tempo[1], tempo[2], tempo[3]]);
+        Ok(recording)
+    }
+    fn write(&mut self, tick: u64, event: &[u8]) {
+        let mut bytes = variable_length(tick - self.tick);
+        bytes.extend(event);
+        self.tick = tick;
+        self.length += bytes.len() as u64;
+        // The end of track is overwritten by the next message
+        bytes.extend(&[0x00, 0xff, 0x2f, 0x00]);
+        let length = self.length as u32 + 4;
+        self.file.write_all(&bytes).ok();
+        self.file.seek(SeekFrom::Start(18)).ok();
+        self.file.write_all(&length.to_be_bytes()).ok();
+        self.file.seek(SeekFrom::Start(22 + self.length)).ok();
+    }
+    fn message(&mut self, cycles: u64, message: &[u8]) {
+        let tick = cycles * 1000 / CPU_FREQUENCY;
+        let mut event = Vec::new();
+        match message[0] {
+            0xf0 => {
+                event.push(0xf0);
+                event.extend(variable_length(message.len() as u64 - 1));
+                event.extend(&message[1..]);
+            }
+            // Other system messages are escaped
+            0xf1..=0xff => {
+                event.push(0xf7);
+                event.extend(variable_length(message.len() as u64));
+                event.extend(message);
+            }
+            _ => event.extend(message),
+        }
+        self.write(tick, &event);
+    }
+}
+
+fn read_variable_length(data: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    loop {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value = value << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+// An event of a track: when it happens in ticks, the MIDI bytes and a tempo change if any
+type TrackEvent = (u64, Vec<u8>, Option<u32>);
+
+fn read_track(data: &[u8]) -> Option<Vec<TrackEvent>> {
+    let mut events = Vec::new();
+    let (mut position, mut tick, mut status) = (0, 0, 0);
+    while position < data.len() {
+        tick = read_variable_length(data, &mut position)?.checked_add(tick)?;
+        let mut byte = *data.get(position)?;
+        if byte >= 0x80 {
+            position += 1;
+        } else {
+            byte = status;
+        }
+        match byte {
+            0xff => {
+                let kind = *data.get(position)?;
+                position += 1;
+                let length = read_variable_length(data, &mut position)? as usize;
+                let end = position.checked_add(length)?;
+                let meta = data.get(position..end)?;
+                position = end;
+                if kind == 0x51 && length == 3 {
+                    let tempo = u32::from_be_bytes([0, meta[0], meta[1], meta[2]]);
+                    events.push((tick, Vec::new(), Some(tempo)));
+                }
+            }
+            0xf0 | 0xf7 => {
+                let length = read_variable_length(data, &mut position)? as usize;
+                let mut bytes = if byte == 0xf0 { vec![0xf0] } else { Vec::new() };
+                let end = position.checked_add(length)?;
+                bytes.extend(data.get(position..end)?);
+                position = end;
+                events.push((tick, bytes, None));
+            }
+            0x80..=0xef => {
+                status = byte;
+                let length = data_length(byte);
+                let mut bytes = vec![byte];
+                bytes.extend(data.get(position..position + length)?);
+                position += length;
+                events.push((tick, bytes, None));
+            }
+            _ => return None,
+        }
+    }
+    Some(events)
+}
+
+// The bytes of a Standard MIDI File and when to send them, in cycles
+fn read_midi_file(path: &str) -> Result<VecDeque<(u64, u8)>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let invalid = || format!("{} is not a Standard MIDI File", path);
+    if data.len() < 14 || &data[0..4] != b"MThd" {
+        return Err(invalid());
+    }
+    let tracks = u16::from_be_bytes([data[10], data[11]]);
+    let division = u16::from_be_bytes([data[12], data[13]]);
+    let mut events = Vec::new();
+    let mut position = 8 + u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    for _ in 0..tracks {
+        let header = data.get(position..position + 8).ok_or_else(invalid)?;
+        let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let track = data.get(position + 8..position + 8 + length).ok_or_else(invalid)?;
+        if &header[0..4] == b"MTrk" {
+            events.extend(read_track(track).ok_or_else(invalid)?);
+        }
+        position += 8 + length;
+    }
+    // Tracks are played at once, tempo changes in any of them apply to all
+    events.sort_by_key(|(tick, _, _)| *tick);
+    // Microseconds per tick, either a share of a quarter note or of an SMPTE frame
+    let tick_time = |tempo: u32| match division {
+        division if division & 0x8000 != 0 => 1e6 / (-((division >> 8) as i8) as f64 * (division & 0xff) as f64),
+        division => tempo as f64 / division as f64,
+    };
+    let (mut tempo, mut last_tick, mut time) = (TEMPO, 0, 0.0);
+    let mut bytes = VecDeque::new();
+    for (tick, message, tempo_change) in events {
+        time += (tick - last_tick) as f64 * tick_time(tempo);
+        last_tick = tick;
+        if let Some(new_tempo) = tempo_change {
+            tempo = new_tempo;
+        }
+        let cycles = (time * CPU_FREQUENCY as f64 / 1e6) as u64;
+        bytes.extend(message.into_iter().map(|byte| (cycles, byte)));
+    }
+    Ok(bytes)
+}
+
+enum MIDIInput {
+    File(VecDeque<(u64, u8)>),
+    Stream(File),
+}
+
+impl MIDIInput {
+    fn open(path: &str) -> Result<Self, String> {
+        if path.to_ascii_lowercase().ends_with(".mid") {
+            return Ok(Self::File(read_midi_file(path)?));
+        }
+        let stream = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Self::Stream(stream))
+    }
+    fn receive(&mut self, cycles: u64) -> Option<u8> {
+        match self {
+            Self::File(bytes) => match bytes.front() {
+                Some((time, _)) if *time <= cycles => bytes.pop_front().map(|(_, byte)| byte),
+                _ => None,
+            },
+            Self::Stream(stream) => {
+                let mut byte = [0];
+                match stream.read(&mut byte) {
+                    Ok(1) => Some(byte[0]),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+pub struct MIDIAdapter {
+    address: usize,
+    acia: ACIA,
+    parser: MessageParser,
+    recording: Option<MIDIRecording>,
+    input: Option<MIDIInput>,
+    transmit_start: Option<u64>,
+    next_receive: u64,
+    pub interrupt: SignalLine,
+}
+
+impl MIDIAdapter {
+    pub fn new(address: usize) -> Box<Self> {
+        let acia = ACIA::default();
+        let interrupt = Rc::clone(&acia.interrupt);
+        Box::new(Self {
+            address,
+            acia,
+            parser: MessageParser::default(),
+            recording: None,
+            input: None,
+            transmit_start: None,
+            next_receive: 0,
+            interrupt,
+        })
+    }
+    pub fn record(&mut self, path: &str) -> Result<(), String> {
+        self.recording = Some(MIDIRecording::create(path)?);
+        Ok(())
+    }
+    pub fn play(&mut self, path: &str) -> Result<(), String> {
+        self.input = Some(MIDIInput::open(path)?);
+        Ok(())
+    }
+}
+
+impl Device for MIDIAdapter {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x4)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| match a - self.address {
+            offset @ (0 | 2) => self.acia.read(offset / 2),
+            _ => 0xff,
+        })
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| {
+            if let offset @ (0 | 2) = a - self.address {
+                self.acia.write(offset / 2, b);
+            }
+        });
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        if self.acia.transmitting() {
+            let start = *self.transmit_start.get_or_insert(cycles);
+            if cycles >= start + CYCLES_PER_BYTE {
+                self.transmit_start = None;
+                if let Some(byte) = self.acia.transmit() {
+                    if let (Some(recording), Some(message)) = (self.recording.as_mut(), self.parser.push(byte)) {
+                        recording.message(cycles, &message);
+                    }
+                }
+            }
+        }
+        // Input is looked at once per byte time
+        if cycles >= self.next_receive && self.acia.ready() {
+            self.next_receive = cycles + CYCLES_PER_BYTE;
+            if let Some(byte) = self.input.as_mut().and_then(|input| input.receive(cycles)) {
+                self.acia.receive(byte);
+            }
+        }
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary(name: &str) -> String {
+        std::env::temp_dir().join(format!("em68k-{}-{}.mid", name, std::process::id())).display().to_string()
+    }
+
+    // Cycles per millisecond, a tick at the default tempo and division
+    const MS: u64 = CPU_FREQUENCY / 1000;
+
+    #[test]
+    fn variable_length_quantities() {
+        for (value, bytes) in [
+            (0, vec![0x00]),
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0x81, 0x00]),
+            (0x3fff, vec![0xff, 0x7f]),
+            (0x200000, vec![0x81, 0x80, 0x80, 0x00]),
+        ] {
+            assert_eq!(variable_length(value), bytes);
+            let mut position = 0;
+            assert_eq!(read_variable_length(&bytes, &mut position), Some(value));
+            assert_eq!(position, bytes.len());
+        }
+        assert_eq!(read_variable_length(&[0x81], &mut 0), None);
+    }
+
+    #[test]
+    fn parser_completes_messages() {
+        let mut parser = MessageParser::default();
+        let bytes = [0x90, 0x3c, 0xf8, 0x40, 0x3e, 0x40, 0xc1, 0x05, 0xf0, 0x43, 0x10, 0xf7, 0x20, 0xf6];
+        let messages: Vec<Vec<u8>> = bytes.iter().filter_map(|&byte| parser.push(byte)).collect();
+        // The clock in between is dropped, the second note uses running status and the data byte
+        // after the SysEx has no status to go with
+        assert_eq!(
+            messages,
+            [vec![0x90, 0x3c, 0x40], vec![0x90, 0x3e, 0x40], vec![0xc1, 0x05], vec![0xf0, 0x43, 0x10, 0xf7], vec![0xf6]]
+        );
+    }
+
+    #[test]
+    fn recordings_play_back_at_the_same_times() {
+        let path = temporary("recording");
+        let mut recording = MIDIRecording::create(&path).unwrap();
+        recording.message(250 * MS, &[0x90, 0x3c, 0x40]);
+        recording.message(250 * MS, &[0xf0, 0x7e, 0xf7]);
+        recording.message(1000 * MS + 5, &[0xf2, 0x10, 0x00]);
+        let bytes: Vec<(u64, u8)> = read_midi_file(&path).unwrap().into_iter().collect();
+        std::fs::remove_file(&path).ok();
+        let expected = [
+            (250 * MS, 0x90),
+            (250 * MS, 0x3c),
+            (250 * MS, 0x40),
+            (250 * MS, 0xf0),
+            (250 * MS, 0x7e),
+            (250 * MS, 0xf7),
+            (1000 * MS, 0xf2),
+            (1000 * MS, 0x10),
+            (1000 * MS, 0x00),
+        ];
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn tempo_changes_apply_to_all_tracks() {
+        let mut data = b"MThd\0\0\0\x06\0\x01\0\x02\0\x60".to_vec();
+        // A tempo track doubling the speed after one quarter note, 96 ticks
+        let tempo =
+            [0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, 0x60, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, 0x00, 0xff, 0x2f, 0x00];
+        // Notes on each quarter note, the second one with running status
+        let notes = [0x00, 0x90, 0x3c, 0x40, 0x60, 0x3c, 0x00, 0x60, 0x80, 0x3c, 0x00, 0x00, 0xff, 0x2f, 0x00];
+        for track in [&tempo[..], &notes[..]] {
+            data.extend(b"MTrk");
+            data.extend(&(track.len() as u32).to_be_bytes());
+            data.extend(track);
+        }
+        let path = temporary("tempo");
+        std::fs::write(&path, &data).unwrap();
+        let bytes: Vec<(u64, u8)> = read_midi_file(&path).unwrap().into_iter().collect();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(
+            bytes,
+            [
+                (0, 0x90),
+                (0, 0x3c),
+                (0, 0x40),
+                (500 * MS, 0x90),
+                (500 * MS, 0x3c),
+                (500 * MS, 0x00),
+                (750 * MS, 0x80),
+                (750 * MS, 0x3c),
+                (750 * MS, 0x00)
+            ]
+        );
+    }
+
+    #[test]
+    fn files_without_header_are_refused() {
+        let path = temporary("invalid");
+        std::fs::write(&path, b"RIFF\0\0\0\0\0\0\0\0\0\0").unwrap();
+        assert!(read_midi_file(&path).is_err());
+        std::fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn lengths_and_times_past_the_end_are_refused() {
+        // A SysEx as long as the largest quantity, then two deltas adding up past it
+        let length = [0x00, 0xf0, 0x81, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xf7];
+        let time = [0x81, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0x01, 0x00, 0x01, 0xff, 0x2f, 0x00];
+        for track in [&length[..], &time[..]] {
+            assert_eq!(read_track(track), None);
+            let mut data = b"MThd\0\0\0\x06\0\x00\0\x01\0\x60MTrk".to_vec();
+            data.extend(&(track.len() as u32).to_be_bytes());
+            data.extend(track);
+            let path = temporary("overflow");
+            std::fs::write(&path, &data).unwrap();
+            assert!(read_midi_file(&path).unwrap_err().ends_with("is not a Standard MIDI File"));
+            std::fs::remove_file(&path).ok();
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// The ST's memory controller. RAM is organised in two banks of 128 KB, 512 KB or 2 MB, built from
+// 64 Kbit, 256 Kbit or 1 Mbit chips. TOS does not know which ones are fitted: at a cold boot it
+// programs both banks to 2 MB through the memory configuration register at $FF8001, writes a test
+// pattern and looks for it at the addresses the smaller chips would alias it to. The aliasing
+// follows from the way the MMU splits an address into the row and column addresses of the chips.
+
+use super::{read_bytes, write_bytes, Device, Signal};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+
+// Address bits per row/column of the chips of a bank of the given size
+const BANK_SIZES: [(usize, usize); 3] = [(0x20000, 8), (0x80000, 9), (0x200000, 10)];
+const RAM_WINDOW: usize = 0x400000;
+// The first eight bytes of the address space belong to the ROM (reset vectors)
+const RAM_START: usize = 8;
+
+pub struct MMU {
+    address: usize,
+    memory: Vec<u8>,
+    banks: [usize; 2],
+    configuration: u8,
+}
+
+impl MMU {
+    pub fn new(address: usize, ram_size: usize) -> Box<Self> {
+        let banks = match ram_size {
+            0x40000 => [0x20000, 0x20000],
+            0x100000 => [0x80000, 0x80000],
+            0x280000 => [0x200000, 0x80000],
+            0x400000 => [0x200000, 0x200000],
+            _ => [ram_size, 0],
+        };
+        if !BANK_SIZES.iter().any(|&(size, _)| size == banks[0]) {
+            panic!("Unsupported RAM size {:x}", ram_size);
+        }
+        Box::new(Self { address, memory: vec![0; ram_size], banks, configuration: 0 })
+    }
+    fn bank_size(&self, bank: usize) -> usize {
+        BANK_SIZES[((self.configuration >> (2 - 2 * bank)) & 0x3).min(2) as usize].0
+    }
+    // Translates an address into an index into the RAM, if there is any memory behind it
+    fn translate(&self, address: usize) -> Option<usize> {
+        let (bank, offset) = if address < self.bank_size(0) {
+            (0, address)
+        } else if address < self.bank_size(0) + self.bank_size(1) {
+            (1, address - self.bank_size(0))
+        } else {
+            return None;
+        };
+        let bits = |size: usize| BANK_SIZES.iter().find(|&&(s, _)| s == size).map(|&(_, bits)| bits);
+        let chip = bits(self.banks[bank])?;
+        let configured = bits(self.bank_size(bank)).unwrap();
+        let word = offset >> 1;
+        let column = word & ((1 << configured) - 1);
+        let row = (word >> configured) & ((1 << configured) - 1);
+        let mask = (1 << chip) - 1;
+        let word = ((row & mask) << chip) | (column & mask);
+        Some(bank * self.banks[0] + 2 * word + (offset & 1))
+    }
+    // After TOS has found the right configuration the address translation is the identity
+    fn direct(&self) -> bool {
+        self.bank_size(0) == self.banks[0] && (self.banks[1] == 0 || self.bank_size(1) == self.banks[1])
+    }
+    fn read_byte(&self, address: usize) -> u8 {
+        if address >= self.address {
+            return if address & 1 != 0 { self.configuration } else { 0xff };
+        }
+        self.translate(address).map_or(0xff, |index| self.memory[index])
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        if address >= self.address {
+            if address & 1 != 0 {
+                self.configuration = value & 0x0f;
+            }
+        } else if let Some(index) = self.translate(address) {
+            self.memory[index] = value;
+        }
+    }
+}
+
+impl Device for MMU {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(RAM_START, RAM_WINDOW), (self.address, self.address + 2)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        if self.direct() && address + (size as usize) <= self.memory.len() {
+            return size.from_be_bytes(&self.memory[address..address + size as usize]);
+        }
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        if self.direct() && address + (result.size() as usize) <= self.memory.len() {
+            for (j, byte) in result.to_be_bytes().iter().enumerate() {
+                self.memory[address + j] = *byte;
+            }
+        } else {
+            write_bytes(address, result, |a, b| self.write_byte(a, b));
+        }
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+}
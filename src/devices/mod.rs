@@ -0,0 +1,299 @@
+// Memory mapped peripherals. Every device claims a set of address ranges (see Device::memconfig)
+// and is handed all bus accesses falling into them. Devices are advanced in lockstep with the CPU
+// through Device::clock, which receives the number of bus cycles elapsed since power on. A device
+// which needs to access memory itself (DMA) answers the clock with Signal::BusRequest and is then
+// granted the bus via Device::bus_access.
+// Devices talk to each other through shared lines, e.g. the floppy controller's interrupt output
+// is wired to one of the MFP's general purpose inputs.
+
+use crate::fields::{OpResult, Size};
+use crate::memory::{Bus, MemoryRange};
+use crate::processor::IRQ;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+mod acia;
+mod acsi;
+mod audio;
+mod blitter;
+mod capture;
+mod dmasound;
+mod floppy;
+mod ikbd;
+mod joystick;
+mod mfp;
+mod midi;
+mod mmu;
+mod printer;
+mod psg;
+mod rtc;
+mod serial;
+mod video;
+pub use acsi::HardDisk;
+pub use blitter::Blitter;
+pub use capture::{listen_for_screenshot_requests, SoundRecording, VideoRecording};
+pub use dmasound::{DMASoundSystem, Microwire};
+pub use floppy::{DiskImage, Floppy};
+pub use ikbd::{input_queue, InputEvent, InputQueue, Keyboard, KeyboardLayout};
+pub use joystick::{JoystickInput, JoystickMapper, JoystickPort};
+pub use mfp::MultiFunctionPeripheral;
+pub use midi::MIDIAdapter;
+pub use mmu::MMU;
+pub use printer::Printer;
+pub use psg::SoundGenerator;
+pub use rtc::RealTimeClock;
+pub use serial::SerialLink;
+pub use video::Monitor;
+
+pub const CPU_FREQUENCY: u64 = 8_000_000;
+
+pub type DeviceList = Vec<(MemoryRange, Box<dyn Device>)>;
+pub type SignalLine = Rc<RefCell<bool>>;
+pub type Port = Rc<RefCell<u8>>;
+// Counts the level changes of a signal line, for inputs that may change more often than they are sampled
+pub type EdgeCounter = Rc<RefCell<u32>>;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Signal {
+    Ok,
+    NoOp,
+    Quit,
+    Remap,
+    BusRequest,
+}
+
+impl Signal {
+    pub fn add(&mut self, other: &Signal) {
+        match (*self, *other) {
+            (Signal::Quit, _) => {}
+            (_, Signal::Quit) => *self = Signal::Quit,
+            (Signal::Ok, signal) => *self = signal,
+            _ => {}
+        }
+    }
+}
+
+pub trait Device {
+    fn memconfig(&self) -> MemoryRange;
+    fn read(&mut self, address: usize, size: Size) -> OpResult;
+    fn write(&mut self, address: usize, result: OpResult) -> Signal;
+    fn interrupt_request(&mut self) -> Option<IRQ>;
+    // The CPU takes a requested interrupt. A device whose request has been withdrawn in the
+    // meantime answers false, and the CPU goes on without taking it.
+    fn interrupt_acknowledge(&mut self, _irq: &IRQ) -> bool {
+        true
+    }
+    fn poll(&self) -> Signal;
+    fn clock(&mut self, _cycles: u64) -> Signal {
+        Signal::Ok
+    }
+    fn bus_access(&mut self, _bus: &mut Bus) {}
+}
+
+pub fn signal_line() -> SignalLine {
+    Rc::new(RefCell::new(false))
+}
+
+pub fn edge_counter() -> EdgeCounter {
+    Rc::new(RefCell::new(0))
+}
+
+pub fn port(value: u8) -> Port {
+    Rc::new(RefCell::new(value))
+}
+
+// Most peripherals of the ST are 8 bit devices sitting on the odd or even half of the 16 bit data bus.
+// These helpers break up word and long word accesses into their individual bytes, such that the devices
+// only have to deal with byte wide registers.
+pub fn read_bytes<F>(address: usize, size: Size, mut read_byte: F) -> OpResult
+where
+    F: FnMut(usize) -> u8,
+{
+    let mut result = 0u32;
+    for j in 0..size as usize {
+        result = (result << 8) | read_byte(address + j) as u32;
+    }
+    size.from(result)
+}
+
+pub fn write_bytes<F>(address: usize, result: OpResult, mut write_byte: F)
+where
+    F: FnMut(usize, u8),
+{
+    for (j, byte) in result.to_be_bytes().iter().enumerate() {
+        write_byte(address + j, *byte);
+    }
+}
+
+pub struct Ram {
+    size: usize,
+    memory: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Box<Self> {
+        Box::new(Self { size, memory: vec![0; size] })
+    }
+}
+
+impl Device for Ram {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(0, self.size)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        size.from_be_bytes(&self.memory[address..address + size as usize])
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        for (j, byte) in result.to_be_bytes().iter().enumerate() {
+            self.memory[address + j] = *byte;
+        }
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+}
+
+// Read only memory. The ST maps the first eight bytes of the ROM to address 0 as well, which is
+// where the CPU fetches its initial stack pointer and program counter from at a reset.
+pub struct ROM {
+    base: usize,
+    data: Vec<u8>,
+}
+
+impl ROM {
+    pub fn new(base: usize, data: Vec<u8>) -> Box<Self> {
+        Box::new(Self { base, data })
+    }
+}
+
+impl Device for ROM {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(0, 8), (self.base, self.base + self.data.len())]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        let offset = if address < 8 { address } else { address - self.base };
+        read_bytes(offset, size, |a| self.data.get(a).copied().unwrap_or(0xff))
+    }
+    fn write(&mut self, _address: usize, _result: OpResult) -> Signal {
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+}
+
+// The cartridge port: up to 128 KB of ROM at $FA0000, read only like the TOS ROM. TOS runs what a
+// cartridge brings along itself: at a reset a diagnostic cartridge (starting with $FA52235F) takes
+// over at once, later the programs listed in an application cartridge (starting with $ABCDEF42)
+// are started or made available on the desktop. An empty port reads as $FF.
+pub struct CartridgeROM {
+    base: usize,
+    data: Vec<u8>,
+}
+
+const CARTRIDGE_SIZE: usize = 0x20000;
+const DIAGNOSTIC_CARTRIDGE: [u8; 4] = [0xfa, 0x52, 0x23, 0x5f];
+const APPLICATION_CARTRIDGE: [u8; 4] = [0xab, 0xcd, 0xef, 0x42];
+
+impl CartridgeROM {
+    pub fn new(base: usize) -> Box<Self> {
+        Box::new(Self { base, data: Vec::new() })
+    }
+    pub fn open(base: usize, path: &str) -> Result<Box<Self>, String> {
+        let mut data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        // Images in the STC format have four bytes in front of the contents
+        if path.to_ascii_lowercase().ends_with(".stc") && data.len() >= 4 {
+            data.drain(..4);
+        }
+        if data.len() > CARTRIDGE_SIZE {
+            return Err(format!("{} is larger than a cartridge ({} KB)", path, CARTRIDGE_SIZE / 1024));
+        }
+        if !data.starts_with(&DIAGNOSTIC_CARTRIDGE) && !data.starts_with(&APPLICATION_CARTRIDGE) {
+            eprintln!("{} has no cartridge header, TOS will not run it", path);
+        }
+        Ok(Box::new(Self { base, data }))
+    }
+}
+
+impl Device for CartridgeROM {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.base, self.base + CARTRIDGE_SIZE)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.data.get(a - self.base).copied().unwrap_or(0xff))
+    }
+    fn write(&mut self, _address: usize, _result: OpResult) -> Signal {
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+}
+
+// Plain register file for peripherals which are not emulated beyond remembering what was written to them.
+struct Registers {
+    base: usize,
+    data: Vec<u8>,
+}
+
+impl Registers {
+    fn new(base: usize, length: usize) -> Self {
+        Self { base, data: vec![0; length] }
+    }
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.base, self.base + self.data.len())]
+    }
+    fn read(&self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.data[a - self.base])
+    }
+    fn write(&mut self, address: usize, result: OpResult) {
+        let base = self.base;
+        let data = &mut self.data;
+        write_bytes(address, result, |a, b| data[a - base] = b);
+    }
+}
+
+macro_rules! register_device {
+    ($name:ident, $length:expr) => {
+        pub struct $name {
+            registers: Registers,
+        }
+
+        impl $name {
+            pub fn new(address: usize) -> Box<Self> {
+                Box::new(Self { registers: Registers::new(address, $length) })
+            }
+        }
+
+        impl Device for $name {
+            fn memconfig(&self) -> MemoryRange {
+                self.registers.memconfig()
+            }
+            fn read(&mut self, address: usize, size: Size) -> OpResult {
+                self.registers.read(address, size)
+            }
+            fn write(&mut self, address: usize, result: OpResult) -> Signal {
+                self.registers.write(address, result);
+                Signal::Ok
+            }
+            fn interrupt_request(&mut self) -> Option<IRQ> {
+                None
+            }
+            fn poll(&self) -> Signal {
+                Signal::Ok
+            }
+        }
+    };
+}
+
+register_device!(SystemControlUnit, 0x10);
@@ -0,0 +1,253 @@
+// A printer on the Centronics port. The ST puts a byte on the PSG's port B and pulses the strobe
+// (port A bit 5) low, the printer takes the byte and answers with a short pulse on BUSY, wired to
+// the MFP's GPIP 0. Without a printer BUSY stays high and TOS gives up after a while.
+//
+// What is printed goes into a host file, as the raw bytes or turned into something to look at:
+//   <path>        the bytes as they were sent
+//   text:<path>   the printable text, escape sequences and graphics left out
+//   pbm:<path>    the bit image graphics of an Epson compatible printer (such as the ALT-HELP
+//                 hardcopy of the screen) as PBM images, one per page, text left out
+
+use super::{signal_line, Device, Port, Signal, SignalLine};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+use std::fs::File;
+use std::io::Write;
+
+const STROBE: u8 = 0x20;
+// How long BUSY is held high for each byte
+const BUSY_CYCLES: u64 = 80;
+
+const ESC: u8 = 0x1b;
+// Escape sequences taking one parameter
+const ONE_PARAMETER: &[u8] = b"!-3AJNQRSUWClpx";
+// Bit image graphics: ESC K/L/Y/Z n1 n2 and ESC * m n1 n2, followed by n1 + 256 n2 columns of 8 dots
+const GRAPHICS: &[u8] = b"KLYZ*";
+
+enum Output {
+    Raw,
+    Text(Page),
+    Image(Page),
+}
+
+// The dots printed, in rows of 8 dots per column with the top one in bit 7
+#[derive(Default)]
+struct Page {
+    rows: Vec<Vec<u8>>,
+    x: usize,
+    // Dots the paper has moved since the current row started
+    y: usize,
+    // Escape sequence being received and graphics columns still to come
+    sequence: Vec<u8>,
+    columns: usize,
+}
+
+impl Page {
+    // Returns the byte unless it is part of an escape sequence or graphics
+    fn byte(&mut self, byte: u8) -> Option<u8> {
+        if self.columns > 0 {
+            self.columns -= 1;
+            self.dots(byte);
+            return None;
+        }
+        if !self.sequence.is_empty() {
+            self.sequence.push(byte);
+            self.escape();
+            return None;
+        }
+        match byte {
+            ESC => {
+                self.sequence.push(byte);
+                return None;
+            }
+            b'\r' => self.x = 0,
+            b'\n' | 0x0c => self.advance(8),
+            _ => {}
+        }
+        Some(byte)
+    }
+    fn escape(&mut self) {
+        let sequence = &self.sequence;
+        let command = sequence[1];
+        if GRAPHICS.contains(&command) {
+            let header = if command == b'*' { 5 } else { 4 };
+            if sequence.len() == header {
+                self.columns = sequence[header - 2] as usize + 256 * sequence[header - 1] as usize;
+                self.sequence.clear();
+            }
+        } else if ONE_PARAMETER.contains(&command) {
+            if sequence.len() == 3 {
+                // ESC J n feeds n/216 inch, three of them a dot
+                if command == b'J' {
+                    self.advance(sequence[2] as usize / 3);
+                }
+                self.sequence.clear();
+            }
+        } else {
+            self.sequence.clear();
+        }
+    }
+    fn row(&mut self) -> &mut Vec<u8> {
+        if self.rows.is_empty() {
+            self.rows.push(Vec::new());
+        }
+        self.rows.last_mut().unwrap()
+    }
+    fn dots(&mut self, column: u8) {
+        let x = self.x;
+        let row = self.row();
+        if row.len() <= x {
+            row.resize(x + 1, 0);
+        }
+        row[x] |= column;
+        self.x += 1;
+    }
+    fn advance(&mut self, dots: usize) {
+        self.row();
+        self.y += dots;
+        // Rows are 8 dots high, feeds of less are put together
+        while self.y >= 8 {
+            self.y -= 8;
+            self.rows.push(Vec::new());
+        }
+    }
+    // Appends the page as a binary PBM image unless nothing was drawn on it
+    fn write(&self, output: &mut impl Write) -> std::io::Result<()> {
+        let width = self.rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        if width == 0 {
+            return Ok(());
+        }
+        write!(output, "P4\n{} {}\n", width, self.rows.len() * 8)?;
+        let mut line = vec![0; width.div_ceil(8)];
+        for row in &self.rows {
+            for bit in (0..8).rev() {
+                line.fill(0);
+                for (x, column) in row.iter().enumerate() {
+                    if column & (1 << bit) != 0 {
+                        line[x / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+                output.write_all(&line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Printer {
+    file: File,
+    output: Output,
+    port_a: Port,
+    port_b: Port,
+    strobe: bool,
+    busy_until: u64,
+    // Pulls BUSY low
+    pub ready: SignalLine,
+}
+
+impl Printer {
+    pub fn open(spec: &str, port_a: Port, port_b: Port) -> Result<Box<Self>, String> {
+        let (output, path) = match spec.split_once(':') {
+            Some(("text", path)) => (Output::Text(Page::default()), path),
+            Some(("pbm", path)) => (Output::Image(Page::default()), path),
+            _ => (Output::Raw, spec),
+        };
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let ready = signal_line();
+        *ready.borrow_mut() = true;
+        Ok(Box::new(Self { file, output, port_a, port_b, strobe: true, busy_until: 0, ready }))
+    }
+    fn print(&mut self, byte: u8) {
+        match &mut self.output {
+            Output::Raw => {
+                self.file.write_all(&[byte]).ok();
+            }
+            Output::Text(page) => {
+                if let Some(byte) = page.byte(byte).filter(|byte| *byte >= 0x20 || b"\t\n\x0c".contains(byte)) {
+                    self.file.write_all(&[byte]).ok();
+                }
+            }
+            // Pages are written out when they are ejected
+            Output::Image(page) => {
+                if let Some(0x0c) = page.byte(byte) {
+                    page.write(&mut self.file).ok();
+                    *page = Page::default();
+                }
+            }
+        }
+    }
+}
+
+// The page still in the printer is written out at the end
+impl Drop for Printer {
+    fn drop(&mut self) {
+        if let Output::Image(page) = &self.output {
+            page.write(&mut self.file).ok();
+        }
+    }
+}
+
+impl Device for Printer {
+    fn memconfig(&self) -> MemoryRange {
+        Vec::new()
+    }
+    fn read(&mut self, _address: usize, size: Size) -> OpResult {
+        size.from(0u32)
+    }
+    fn write(&mut self, _address: usize, _result: OpResult) -> Signal {
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        let strobe = *self.port_a.borrow() & STROBE != 0;
+        // The byte is taken when the strobe goes low
+        if self.strobe && !strobe {
+            let byte = *self.port_b.borrow();
+            self.print(byte);
+            self.busy_until = cycles + BUSY_CYCLES;
+            *self.ready.borrow_mut() = false;
+        }
+        self.strobe = strobe;
+        if cycles >= self.busy_until {
+            *self.ready.borrow_mut() = true;
+        }
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graphics_go_into_rows_of_dots() {
+        let mut page = Page::default();
+        let bytes = [ESC, b'K', 3, 0, 0x80, 0x01, 0xff, b'A', ESC, b'A', 8, b'\r', b'\n', ESC, b'K', 1, 0, 0x40];
+        let passed: Vec<u8> = bytes.iter().filter_map(|&byte| page.byte(byte)).collect();
+        // Only the text and control codes are passed on
+        assert_eq!(passed, b"A\r\n");
+        assert_eq!(page.rows, [vec![0x80, 0x01, 0xff], vec![0x40]]);
+        let mut image = Vec::new();
+        page.write(&mut image).unwrap();
+        let mut expected = b"P4\n3 16\n".to_vec();
+        expected.extend([0xa0, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x60, 0x00, 0x80, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn pages_without_graphics_are_left_out() {
+        let mut page = Page::default();
+        b"Text\r\n\x0c".iter().for_each(|&byte| {
+            page.byte(byte);
+        });
+        let mut image = Vec::new();
+        page.write(&mut image).unwrap();
+        assert!(image.is_empty());
+    }
+}
@@ -0,0 +1,380 @@
+// The YM2149 programmable sound generator. Besides making noise, its two I/O ports are used
+// by the ST as general purpose outputs: port A selects the floppy drive and side and drives the
+// Centronics strobe, port B carries the data for the parallel port.
+//
+// The chip runs at 2 MHz. Its three square wave tone generators, the noise generator (a 17 bit
+// shift register) and the envelope generator all count down periods of 8 clock cycles, which
+// is the rate the chip is simulated at here. The mixer combines each tone channel with the
+// noise, the result is scaled by the channel's fixed volume or by the envelope. Both use the
+// same logarithmic DAC, the envelope with 32 steps of 1.5 dB, fixed volumes with every second
+// one of them.
+//
+// Samples are often played by writing the volume registers thousands of times per second. So no
+// write gets lost, the generators work on their own copy of the registers, which is updated in
+// the order of the writes, at the time each of them happened during the last instruction. The
+// output is low pass filtered before it is averaged down to the host's sample rate, which keeps
+// the steps of such samples from aliasing.
+
+use super::audio::{AudioChannel, Biquad, SAMPLE_RATE};
+use super::capture::SoundRecording;
+use super::dmasound::Mixer;
+use super::{port, read_bytes, write_bytes, Device, Port, Signal};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+
+const FINE_TONE: usize = 0;
+const NOISE_PERIOD: usize = 6;
+const MIXER: usize = 7;
+const AMPLITUDE: usize = 8;
+const FINE_ENVELOPE: usize = 11;
+const ENVELOPE_SHAPE: usize = 13;
+const PORT_A: usize = 14;
+const PORT_B: usize = 15;
+
+// Valid bits of each register
+const REGISTER_MASKS: [u8; 16] = [0xff, 0x0f, 0xff, 0x0f, 0xff, 0x0f, 0x1f, 0xff, 0x1f, 0x1f, 0x1f, 0xff, 0xff, 0x0f, 0xff, 0xff];
+
+// Envelope shape bits
+const HOLD: u8 = 0x1;
+const ALTERNATE: u8 = 0x2;
+const ATTACK: u8 = 0x4;
+const CONTINUE: u8 = 0x8;
+
+// CPU cycles per tick of the generators (8 cycles of the 2 MHz chip clock)
+const CYCLES_PER_TICK: u64 = 32;
+const TICK_RATE: u32 = 250_000;
+const SAMPLES_PER_PUSH: usize = 256;
+const FILTER_CUTOFF: f32 = 16000.0;
+
+pub struct SoundGenerator {
+    address: usize,
+    selected: usize,
+    registers: [u8; 16],
+    // Registers as seen by the generators and the writes they have not seen yet
+    latched: [u8; 16],
+    writes: Vec<(usize, u8)>,
+    cycles: u64,
+    tone_counters: [u16; 3],
+    tone_outputs: [bool; 3],
+    noise_counter: u16,
+    noise_prescaler: bool,
+    noise: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+    volumes: [f32; 32],
+    filter: Biquad,
+    sample_phase: u32,
+    sample_sum: f32,
+    sample_ticks: u32,
+    samples: Vec<f32>,
+    output: Option<AudioChannel>,
+    pub sound: bool,
+    pub port_a: Port,
+    pub port_b: Port,
+    // On the STE the output goes through the LMC1992 set up via the Microwire interface
+    pub mixer: Option<Mixer>,
+    // Also keeps the sound when there is no output to play it
+    pub recording: Option<SoundRecording>,
+}
+
+impl SoundGenerator {
+    pub fn new(address: usize) -> Box<Self> {
+        let mut volumes = [0.0; 32];
+        for (level, volume) in volumes.iter_mut().enumerate().skip(1) {
+            *volume = 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0);
+        }
+        Box::new(Self {
+            address,
+            selected: 0,
+            registers: [0; 16],
+            latched: [0; 16],
+            writes: Vec::new(),
+            cycles: 0,
+            tone_counters: [0; 3],
+            tone_outputs: [false; 3],
+            noise_counter: 0,
+            noise_prescaler: false,
+            noise: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+            volumes,
+            filter: Biquad::low_pass(FILTER_CUTOFF, TICK_RATE as f32),
+            sample_phase: 0,
+            sample_sum: 0.0,
+            sample_ticks: 0,
+            samples: Vec::with_capacity(SAMPLES_PER_PUSH),
+            output: None,
+            sound: true,
+            port_a: port(0xff),
+            port_b: port(0xff),
+            mixer: None,
+            recording: None,
+        })
+    }
+    fn read_byte(&mut self, address: usize) -> u8 {
+        match (address - self.address) & 0x3 {
+            0 => match self.selected {
+                PORT_A => *self.port_a.borrow(),
+                PORT_B => *self.port_b.borrow(),
+                register => self.registers[register],
+            },
+            _ => 0xff,
+        }
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        match (address - self.address) & 0x3 {
+            0 => self.selected = value as usize & 0xf,
+            2 => {
+                self.registers[self.selected] = value & REGISTER_MASKS[self.selected];
+                match self.selected {
+                    PORT_A => *self.port_a.borrow_mut() = value,
+                    PORT_B => *self.port_b.borrow_mut() = value,
+                    register => self.writes.push((register, value & REGISTER_MASKS[register])),
+                }
+            }
+            _ => {}
+        }
+    }
+    fn latch(&mut self, register: usize, value: u8) {
+        self.latched[register] = value;
+        if register == ENVELOPE_SHAPE {
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_attack = value & ATTACK != 0;
+            self.envelope_holding = false;
+        }
+    }
+    fn period(&self, register: usize) -> u16 {
+        u16::from_le_bytes([self.latched[register], self.latched[register + 1]]).max(1)
+    }
+    fn envelope_level(&self) -> usize {
+        if self.envelope_attack {
+            self.envelope_step as usize
+        } else {
+            31 - self.envelope_step as usize
+        }
+    }
+    // Advances the generators by 8 chip cycles and returns the output level
+    fn tick(&mut self) -> f32 {
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.period(FINE_TONE + 2 * channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+        // The noise generator runs at half the rate
+        self.noise_prescaler = !self.noise_prescaler;
+        if self.noise_prescaler {
+            self.noise_counter += 1;
+            if self.noise_counter >= (self.latched[NOISE_PERIOD] as u16).max(1) {
+                self.noise_counter = 0;
+                let bit = (self.noise ^ (self.noise >> 3)) & 1;
+                self.noise = (self.noise >> 1) | (bit << 16);
+            }
+        }
+        self.envelope_counter += 1;
+        if self.envelope_counter >= self.period(FINE_ENVELOPE) {
+            self.envelope_counter = 0;
+            if !self.envelope_holding {
+                self.step_envelope();
+            }
+        }
+        let mixer = self.latched[MIXER];
+        let mut output = 0.0;
+        for channel in 0..3 {
+            let tone = self.tone_outputs[channel] || mixer & (1 << channel) != 0;
+            let noise = self.noise & 1 != 0 || mixer & (8 << channel) != 0;
+            if tone && noise {
+                let amplitude = self.latched[AMPLITUDE + channel];
+                let level = if amplitude & 0x10 != 0 { self.envelope_level() } else { 2 * (amplitude as usize & 0xf) + 1 };
+                output += self.volumes[level.min(31)];
+            }
+        }
+        output / 3.0
+    }
+    fn step_envelope(&mut self) {
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+        // At the end of a cycle shapes without CONTINUE drop to 0 and stay there. HOLD keeps the
+        // last level, inverted by ALTERNATE. Otherwise the cycle starts over, in the opposite
+        // direction with ALTERNATE.
+        let shape = self.latched[ENVELOPE_SHAPE];
+        if shape & CONTINUE == 0 {
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else if shape & HOLD != 0 {
+            self.envelope_attack ^= shape & ALTERNATE != 0;
+            self.envelope_holding = true;
+        } else {
+            self.envelope_attack ^= shape & ALTERNATE != 0;
+            self.envelope_step = 0;
+        }
+    }
+    fn generate(&mut self, cycles: u64) {
+        for _ in self.cycles / CYCLES_PER_TICK..cycles / CYCLES_PER_TICK {
+            let output = self.tick();
+            self.sample_sum += self.filter.process(output);
+            self.sample_ticks += 1;
+            self.sample_phase += SAMPLE_RATE;
+            if self.sample_phase >= TICK_RATE {
+                self.sample_phase -= TICK_RATE;
+                self.samples.push(self.sample_sum / self.sample_ticks as f32);
+                self.sample_sum = 0.0;
+                self.sample_ticks = 0;
+            }
+        }
+        if self.samples.len() >= SAMPLES_PER_PUSH {
+            if let Some(mixer) = &self.mixer {
+                let gain = mixer.borrow().psg_gain();
+                self.samples.iter_mut().for_each(|sample| *sample *= gain);
+            }
+            if let Some(output) = self.output.as_mut() {
+                output.push(&self.samples);
+            }
+            if let Some(recording) = self.recording.as_mut() {
+                recording.push(&self.samples);
+            }
+            self.samples.clear();
+        }
+        self.cycles = cycles;
+    }
+}
+
+impl Device for SoundGenerator {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x100)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| self.write_byte(a, b));
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        if self.sound && self.output.is_none() {
+            match AudioChannel::open(1) {
+                Ok(output) => self.output = Some(output),
+                Err(error) => {
+                    eprintln!("Unable to play sound ({}), continuing without", error);
+                    self.sound = false;
+                }
+            }
+        }
+        // The writes are spread evenly over the last instruction
+        let writes = std::mem::take(&mut self.writes);
+        let (start, count) = (self.cycles, writes.len() as u64 + 1);
+        // Nobody can hear the sound chip without an output or a recording, so it need not be simulated then
+        let simulate = self.sound || self.recording.is_some();
+        for (j, (register, value)) in writes.into_iter().enumerate() {
+            if simulate {
+                self.generate(start + (cycles - start) * (j as u64 + 1) / count);
+            }
+            self.latch(register, value);
+        }
+        if simulate {
+            self.generate(cycles);
+        }
+        self.cycles = cycles;
+        Signal::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A sound generator without output, whose writes are latched at once
+    fn psg(registers: &[(usize, u8)]) -> Box<SoundGenerator> {
+        let mut psg = SoundGenerator::new(0xff8800);
+        psg.sound = false;
+        for &(register, value) in registers {
+            psg.write(0xff8800, OpResult::Byte(register as u8));
+            psg.write(0xff8802, OpResult::Byte(value));
+        }
+        psg.clock(0);
+        psg
+    }
+
+    // How often the given state changed during the ticks
+    fn changes<T: PartialEq>(psg: &mut SoundGenerator, ticks: usize, state: impl Fn(&SoundGenerator) -> T) -> usize {
+        let mut count = 0;
+        for _ in 0..ticks {
+            let before = state(psg);
+            psg.tick();
+            count += (state(psg) != before) as usize;
+        }
+        count
+    }
+
+    #[test]
+    fn tone_period() {
+        let mut psg = psg(&[(FINE_TONE, 5), (FINE_TONE + 3, 0xf1), (FINE_TONE + 2, 0x00)]);
+        // The coarse tune registers have 4 bits
+        assert_eq!(psg.period(FINE_TONE + 2), 0x100);
+        assert_eq!(changes(&mut psg, 100, |psg| psg.tone_outputs[0]), 20);
+        // A period of 0 counts as 1
+        assert_eq!(changes(&mut psg, 100, |psg| psg.tone_outputs[2]), 100);
+    }
+
+    #[test]
+    fn noise_period() {
+        let mut psg = psg(&[(NOISE_PERIOD, 0xe3)]);
+        // 5 bits of period, at half the rate of the tone generators
+        assert_eq!(psg.latched[NOISE_PERIOD], 3);
+        assert_eq!(changes(&mut psg, 60, |psg| psg.noise), 10);
+    }
+
+    #[test]
+    fn decaying_envelope_ends_at_zero() {
+        let mut psg = psg(&[(FINE_ENVELOPE, 2), (ENVELOPE_SHAPE, 0x00)]);
+        assert_eq!(psg.envelope_level(), 31);
+        assert_eq!(changes(&mut psg, 2 * 40, |psg| psg.envelope_level()), 31);
+        assert_eq!(psg.envelope_level(), 0);
+    }
+
+    #[test]
+    fn alternating_envelope_turns_at_the_ends() {
+        let mut triangle = psg(&[(FINE_ENVELOPE, 2), (ENVELOPE_SHAPE, CONTINUE | ATTACK | ALTERNATE)]);
+        assert_eq!(triangle.envelope_level(), 0);
+        let levels: Vec<usize> = (0..64)
+            .map(|_| {
+                triangle.tick();
+                triangle.tick();
+                triangle.envelope_level()
+            })
+            .collect();
+        assert_eq!(levels[30], 31);
+        // The top level lasts two periods, as the steps start over in the other direction
+        assert_eq!(levels[31], 31);
+        assert_eq!(levels[62], 0);
+        let mut hold = psg(&[(FINE_ENVELOPE, 2), (ENVELOPE_SHAPE, CONTINUE | ALTERNATE | HOLD)]);
+        assert_eq!(changes(&mut hold, 2 * 40, |psg| psg.envelope_level()), 32);
+        assert_eq!(hold.envelope_level(), 31);
+    }
+
+    #[test]
+    fn fixed_volumes() {
+        // Tone and noise off leave the channels at their volume
+        let mut loud = psg(&[(MIXER, 0x3f), (AMPLITUDE, 0), (AMPLITUDE, 15)]);
+        let expected = (loud.volumes[31] + 2.0 * loud.volumes[1]) / 3.0;
+        assert!((loud.tick() - expected).abs() < 1e-6);
+        // The last write to a register wins
+        let mut quiet = psg(&[(MIXER, 0x3f), (AMPLITUDE, 15), (AMPLITUDE, 0)]);
+        assert!((quiet.tick() - quiet.volumes[1]).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,228 @@
+// The Mega ST's real time clock, a Ricoh RP5C15. It has two banks of 13 registers of 4 bits each,
+// at the odd addresses from $FFFC21 on: bank 0 counts the time in decimal digits (seconds up to
+// the two digit year, which TOS counts from 1980), bank 1 holds the alarm time, the 12/24 hour
+// mode and the leap year counter. Three more registers, common to both banks, select the bank,
+// start and stop the clock and the alarm, and reset them.
+//
+// The clock starts at the time it is given, normally the host's, and then counts emulated time.
+// Its alarm output is not connected to anything in the Mega ST.
+
+use super::{read_bytes, signal_line, write_bytes, Device, Signal, SignalLine, CPU_FREQUENCY};
+use crate::fields::{OpResult, Size};
+use crate::memory::MemoryRange;
+use crate::processor::IRQ;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+
+// Counters of the time of day
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const WEEKDAY: usize = 3;
+const DAY: usize = 4;
+const MONTH: usize = 5;
+const YEAR: usize = 6;
+// The counter each register of bank 0 is a digit of and whether it is the tens
+const DIGITS: [(usize, bool); 13] = [
+    (SECONDS, false),
+    (SECONDS, true),
+    (MINUTES, false),
+    (MINUTES, true),
+    (HOURS, false),
+    (HOURS, true),
+    (WEEKDAY, false),
+    (DAY, false),
+    (DAY, true),
+    (MONTH, false),
+    (MONTH, true),
+    (YEAR, false),
+    (YEAR, true),
+];
+// Bank 1: the alarm registers match the time registers from the minutes to the day
+const ALARM: std::ops::RangeInclusive<usize> = 2..=8;
+const TWENTY_FOUR_HOURS: usize = 0xa;
+const LEAP_YEAR: usize = 0xb;
+// Registers common to both banks
+const MODE: usize = 0xd;
+const TEST: usize = 0xe;
+const RESET: usize = 0xf;
+
+// Mode register
+const BANK_1: u8 = 0x1;
+const ALARM_ENABLE: u8 = 0x4;
+const TIMER_ENABLE: u8 = 0x8;
+// Reset register
+const ALARM_RESET: u8 = 0x1;
+const TIMER_RESET: u8 = 0x2;
+
+const DAYS_PER_MONTH: [u8; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+pub struct RealTimeClock {
+    address: usize,
+    // Hours are always counted up to 24
+    time: [u8; 7],
+    bank_1: [u8; 13],
+    mode: u8,
+    next_second: u64,
+    // Low while the alarm time has been reached
+    pub alarm: SignalLine,
+}
+
+impl RealTimeClock {
+    pub fn new(address: usize, time: NaiveDateTime) -> Box<Self> {
+        let mut bank_1 = [0; 13];
+        bank_1[TWENTY_FOUR_HOURS] = 1;
+        bank_1[LEAP_YEAR] = (time.year() % 4) as u8;
+        let time = [
+            time.second() as u8,
+            time.minute() as u8,
+            time.hour() as u8,
+            time.weekday().num_days_from_sunday() as u8,
+            time.day() as u8,
+            time.month() as u8,
+            (time.year() - 1980).rem_euclid(100) as u8,
+        ];
+        Box::new(Self { address, time, bank_1, mode: TIMER_ENABLE, next_second: 0, alarm: signal_line() })
+    }
+    // The hours as they are counted, in 12 hour mode from 1 to 12 with bit 1 of the tens set
+    // in the afternoon
+    fn hours(&self) -> u8 {
+        let hours = self.time[HOURS];
+        if self.bank_1[TWENTY_FOUR_HOURS] & 1 != 0 {
+            hours
+        } else {
+            (hours + 11) % 12 + 1 + if hours >= 12 { 20 } else { 0 }
+        }
+    }
+    fn set_hours(&mut self, hours: u8) {
+        self.time[HOURS] = if self.bank_1[TWENTY_FOUR_HOURS] & 1 != 0 {
+            hours
+        } else {
+            (hours % 20) % 12 + if hours >= 20 { 12 } else { 0 }
+        };
+    }
+    fn time_digit(&self, register: usize) -> u8 {
+        let (counter, tens) = DIGITS[register];
+        let value = if counter == HOURS { self.hours() } else { self.time[counter] };
+        if tens {
+            value / 10
+        } else {
+            value % 10
+        }
+    }
+    fn set_time_digit(&mut self, register: usize, digit: u8) {
+        let (counter, tens) = DIGITS[register];
+        let value = if counter == HOURS { self.hours() } else { self.time[counter] };
+        let value = if tens { 10 * digit + value % 10 } else { value / 10 * 10 + digit };
+        if counter == HOURS {
+            self.set_hours(value);
+        } else {
+            self.time[counter] = value;
+        }
+    }
+    fn read_register(&self, register: usize) -> u8 {
+        match register {
+            MODE => self.mode,
+            TEST | RESET => 0,
+            register if self.mode & BANK_1 != 0 => self.bank_1[register],
+            register => self.time_digit(register),
+        }
+    }
+    fn write_register(&mut self, register: usize, value: u8) {
+        let value = value & 0xf;
+        match register {
+            MODE => self.mode = value,
+            TEST => {}
+            RESET => {
+                if value & ALARM_RESET != 0 {
+                    self.bank_1[ALARM].fill(0);
+                    *self.alarm.borrow_mut() = false;
+                }
+                // Restarts the second
+                if value & TIMER_RESET != 0 {
+                    self.next_second = 0;
+                }
+            }
+            register if self.mode & BANK_1 != 0 => self.bank_1[register] = value,
+            register => self.set_time_digit(register, value),
+        }
+    }
+    fn days_in_month(&self) -> u8 {
+        match self.time[MONTH] {
+            2 if self.bank_1[LEAP_YEAR] & 0x3 == 0 => 29,
+            month @ 1..=12 => DAYS_PER_MONTH[month as usize - 1],
+            _ => 31,
+        }
+    }
+    fn tick(&mut self) {
+        let time = &mut self.time;
+        time[SECONDS] += 1;
+        if time[SECONDS] < 60 {
+            return;
+        }
+        time[SECONDS] = 0;
+        time[MINUTES] += 1;
+        if time[MINUTES] >= 60 {
+            time[MINUTES] = 0;
+            time[HOURS] += 1;
+            if time[HOURS] >= 24 {
+                time[HOURS] = 0;
+                time[WEEKDAY] = (time[WEEKDAY] + 1) % 7;
+                time[DAY] += 1;
+                if time[DAY] > self.days_in_month() {
+                    let time = &mut self.time;
+                    time[DAY] = 1;
+                    time[MONTH] += 1;
+                    if time[MONTH] > 12 {
+                        time[MONTH] = 1;
+                        time[YEAR] = (time[YEAR] + 1) % 100;
+                        self.bank_1[LEAP_YEAR] = (self.bank_1[LEAP_YEAR] + 1) & 0x3;
+                    }
+                }
+            }
+        }
+        if self.mode & ALARM_ENABLE != 0 && ALARM.into_iter().all(|register| self.bank_1[register] == self.time_digit(register)) {
+            *self.alarm.borrow_mut() = true;
+        }
+    }
+}
+
+impl Device for RealTimeClock {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x20)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        // The upper four bits are not driven
+        read_bytes(address, size, |a| match a - self.address {
+            offset if offset & 1 != 0 => 0xf0 | self.read_register(offset / 2),
+            _ => 0xff,
+        })
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| {
+            let offset = a - self.address;
+            if offset & 1 != 0 {
+                self.write_register(offset / 2, b);
+            }
+        });
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        if self.next_second == 0 {
+            self.next_second = cycles + CPU_FREQUENCY;
+        }
+        while cycles >= self.next_second {
+            self.next_second += CPU_FREQUENCY;
+            // Stopping the timer holds the time while it is set
+            if self.mode & TIMER_ENABLE != 0 {
+                self.tick();
+            }
+        }
+        Signal::Ok
+    }
+}
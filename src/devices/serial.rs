@@ -0,0 +1,98 @@
+// Host side of the RS-232 port. The MFP's USART sends and receives its characters through one of
+//   pty           a pseudo terminal, for terminal programs on the host (its name is printed)
+//   unix:<path>   a Unix socket, which is listened on unless some other program (e.g. a second
+//                 emulator, making a null modem link) already does
+//   file:<path>   a file, which receives the output, input is never received
+// All of them are non-blocking, such that a silent line does not hold up the emulation.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+enum Connection {
+    Terminal(File),
+    Listening(UnixListener),
+    Socket(UnixStream),
+    Output(File),
+}
+
+pub struct SerialLink {
+    connection: Connection,
+}
+
+impl SerialLink {
+    pub fn open(spec: &str) -> Result<Self, String> {
+        let connection = match spec.split_once(':') {
+            None if spec == "pty" => Connection::Terminal(open_pty()?),
+            Some(("unix", path)) => match UnixStream::connect(path) {
+                Ok(stream) => {
+                    stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+                    Connection::Socket(stream)
+                }
+                Err(_) => {
+                    // A socket left behind by an earlier run is in the way of listening, anything
+                    // else there is not ours to remove
+                    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                        if !metadata.file_type().is_socket() {
+                            return Err(format!("{}: exists and is not a socket", path));
+                        }
+                        std::fs::remove_file(path).map_err(|e| format!("{}: {}", path, e))?;
+                    }
+                    let listener = UnixListener::bind(path).map_err(|e| format!("{}: {}", path, e))?;
+                    listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+                    Connection::Listening(listener)
+                }
+            },
+            Some(("file", path)) => Connection::Output(File::create(path).map_err(|e| format!("{}: {}", path, e))?),
+            _ => return Err(format!("Unknown serial port {} (expected pty, unix:<path> or file:<path>)", spec)),
+        };
+        Ok(Self { connection })
+    }
+    // Takes the connection of a peer that has turned up at the socket listened on
+    fn accept(&mut self) {
+        if let Connection::Listening(listener) = &self.connection {
+            if let Ok((stream, _)) = listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    self.connection = Connection::Socket(stream);
+                }
+            }
+        }
+    }
+    // Returns the next byte received, if any
+    pub fn receive(&mut self) -> Option<u8> {
+        self.accept();
+        let mut byte = [0];
+        let result = match &mut self.connection {
+            Connection::Terminal(file) => file.read(&mut byte),
+            Connection::Socket(stream) => stream.read(&mut byte),
+            _ => return None,
+        };
+        match result {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+    // Nobody listening is like an unplugged cable, bytes get lost then, as they would on a real line
+    pub fn send(&mut self, byte: u8) {
+        self.accept();
+        match &mut self.connection {
+            Connection::Terminal(file) | Connection::Output(file) => file.write_all(&[byte]).ok(),
+            Connection::Socket(stream) => stream.write_all(&[byte]).ok(),
+            Connection::Listening(_) => None,
+        };
+    }
+}
+
+fn open_pty() -> Result<File, String> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+        if fd < 0 || libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        let name = std::ffi::CStr::from_ptr(libc::ptsname(fd)).to_string_lossy().into_owned();
+        eprintln!("Serial port connected to {}", name);
+        Ok(File::from_raw_fd(fd))
+    }
+}
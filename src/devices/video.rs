@@ -0,0 +1,557 @@
+// The Shifter, which turns the screen memory into a picture. The screen is a block of 32000 bytes
+// at the video base address, organised in interleaved bit planes: in ST-Low every group of four
+// words holds 16 pixels with four bits each (one bit per word), ST-Med uses two words for 16
+// pixels and ST-High one. The bits of a pixel select one of the 16 palette entries at $FF8240,
+// except in the monochrome mode: there cleared pixels are white while bit 0 of the first entry is
+// set (as TOS sets it, for black on white) and black otherwise.
+//
+// The picture is built up in step with the CPU, just like the beam of the monitor. The video
+// address counter ($FF8205-$FF8209) is loaded from the video base at the start of a frame and
+// advances by one byte every two cycles while a line is displayed. Pixels are rendered as soon
+// as the beam has passed them, with the palette and resolution in effect at that moment, so
+// raster effects which change colours between (or within) lines show up as they would on a real
+// monitor. Every line raises the level 2 (HBL) interrupt, every frame the level 4 (VBL) one.
+//
+// The STE's Shifter adds the low byte of the video base ($FF820D), a writable video counter, a
+// line offset ($FF820F, words skipped at the end of each line) and a pixel scroll ($FF8265). For
+// the latter it fetches one extra group of words per line and shifts the picture to the left.
+// Its palette has four bits per colour gun instead of three.
+//
+// Without a window (headless) the picture is still rendered, for screenshots and recordings.
+
+use super::capture::{save_screenshot, screenshot_requested, VideoRecording};
+use super::{
+    edge_counter, input_queue, read_bytes, signal_line, write_bytes, Device, EdgeCounter, InputEvent, InputQueue, Signal,
+    SignalLine, CPU_FREQUENCY,
+};
+use crate::fields::{OpResult, Size};
+use crate::memory::{Bus, MemoryRange};
+use crate::processor::IRQ;
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window, WindowOptions};
+
+const BASE_HIGH: usize = 0x01;
+const BASE_MID: usize = 0x03;
+const COUNTER_HIGH: usize = 0x05;
+const COUNTER_MID: usize = 0x07;
+const COUNTER_LOW: usize = 0x09;
+const SYNC_MODE: usize = 0x0a;
+const BASE_LOW: usize = 0x0d;
+const LINE_OFFSET: usize = 0x0f;
+const PALETTE: usize = 0x40;
+const SHIFT_MODE: usize = 0x60;
+const PIXEL_SCROLL: usize = 0x65;
+
+const HBL_LEVEL: u32 = 2;
+const VBL_LEVEL: u32 = 4;
+
+// Line length in cycles and frame length in lines of the three kinds of monitor signal
+const PAL: (u64, usize) = (512, 313);
+const NTSC: (u64, usize) = (508, 263);
+const MONO: (u64, usize) = (224, 501);
+
+// The GLUE decides where the display starts and ends by comparing the beam position with fixed
+// values for each mode. The comparisons use the mode at that very moment, which is what overscan
+// tricks exploit: e.g. switching to 60 Hz at the end of the last 50 Hz line keeps the display on,
+// opening the bottom border. Cycle positions within a line:
+const START_HIGH: u64 = 4;
+const START_60: u64 = 52;
+const START_50: u64 = 56;
+const END_HIGH: u64 = 164;
+const END_60: u64 = 372;
+const END_50: u64 = 376;
+const END_BLANK: u64 = 460;
+// and lines within a frame
+const TOP_60: usize = 34;
+const TOP_50: usize = 63;
+const BOTTOM_60: usize = 234;
+const BOTTOM_50: usize = 263;
+const BOTTOM_BLANK: usize = 310;
+const BOTTOM_HIGH: usize = TOP_60 + 400;
+
+// The visible part of the picture (lines 34-310, cycles 8-424) is 416x276 ST-Low pixels, each
+// of which is two by two pixels in the frame buffer. The monochrome picture sits in its middle.
+pub const WIDTH: usize = 832;
+pub const HEIGHT: usize = 552;
+const VISIBLE_START: u64 = 8;
+const MONO_LEFT: usize = (WIDTH - 640) / 2;
+const MONO_TOP: usize = (HEIGHT - 400) / 2;
+
+pub struct Monitor {
+    address: usize,
+    registers: [u8; 0x70],
+    ste: bool,
+    cycles: u64,
+    line: usize,
+    line_start: u64,
+    frame_lines: usize,
+    position: u64,
+    vertical_enable: bool,
+    display_enable: bool,
+    words: Vec<u16>,
+    previous: Vec<u16>,
+    prefetch: usize,
+    line_fetched: bool,
+    counter: usize,
+    // Edges not passed on to the CPU yet: a single clock may cover several lines, e.g. after the
+    // blitter held the bus
+    hbl: u32,
+    vbl: u32,
+    window: Option<Window>,
+    // Where the mouse was at the last frame, movement not passed on yet and the buttons
+    mouse_position: Option<(f32, f32)>,
+    mouse_motion: (f32, f32),
+    mouse_buttons: (bool, bool, bool),
+    pub display: bool,
+    pub framebuffer: Vec<u32>,
+    pub monochrome: SignalLine,
+    // Display enable, high while a line of the picture is fetched. The MFP's Timer B counts it.
+    pub de: SignalLine,
+    // Its edges, as several lines may pass within a single clock
+    pub de_edges: EdgeCounter,
+    // Keys and mouse input from the window
+    pub input: InputQueue,
+    // Mouse movement per pixel the host mouse moves. A grabbed mouse is hidden and keeps moving
+    // when it leaves the window. The middle button grabs and releases it.
+    pub mouse_speed: f32,
+    pub grab_mouse: bool,
+    // Frames shown so far, counted from 1
+    frame: u64,
+    screenshot_key: bool,
+    pub headless: bool,
+    // A screenshot is taken of the given frame, and whenever SIGUSR1 or Scroll Lock asks for one,
+    // then into the file named with the frame number added
+    pub screenshot: Option<(String, Option<u64>)>,
+    pub video: Option<VideoRecording>,
+    // Stops the emulation after this many frames
+    pub frames: Option<u64>,
+}
+
+impl Monitor {
+    pub fn new(address: usize, ste: bool) -> Box<Self> {
+        Box::new(Self {
+            address,
+            registers: [0; 0x70],
+            ste,
+            cycles: 0,
+            line: 0,
+            line_start: 0,
+            frame_lines: NTSC.1,
+            position: 0,
+            vertical_enable: false,
+            display_enable: false,
+            words: Vec::new(),
+            previous: Vec::new(),
+            prefetch: 0,
+            line_fetched: false,
+            counter: 0,
+            hbl: 0,
+            vbl: 0,
+            window: None,
+            mouse_position: None,
+            mouse_motion: (0.0, 0.0),
+            mouse_buttons: (false, false, false),
+            display: true,
+            framebuffer: vec![0; WIDTH * HEIGHT],
+            monochrome: signal_line(),
+            de: signal_line(),
+            de_edges: edge_counter(),
+            input: input_queue(),
+            mouse_speed: 1.0,
+            grab_mouse: false,
+            frame: 0,
+            screenshot_key: false,
+            headless: false,
+            screenshot: None,
+            video: None,
+            frames: None,
+        })
+    }
+    fn video_base(&self) -> usize {
+        (self.registers[BASE_HIGH] as usize) << 16 | (self.registers[BASE_MID] as usize) << 8 | (self.registers[BASE_LOW] & 0xfe) as usize
+    }
+    fn planes(&self) -> usize {
+        match self.registers[SHIFT_MODE] & 0x3 {
+            0 => 4,
+            1 => 2,
+            _ => 1,
+        }
+    }
+    fn high(&self) -> bool {
+        self.planes() == 1
+    }
+    fn hz50(&self) -> bool {
+        self.registers[SYNC_MODE] & 0x2 != 0
+    }
+    fn timing(&self) -> (u64, usize) {
+        if self.high() {
+            MONO
+        } else if self.hz50() {
+            PAL
+        } else {
+            NTSC
+        }
+    }
+    fn color(&self, index: usize) -> u32 {
+        let entry = (self.registers[PALETTE + 2 * index] as u32) << 8 | self.registers[PALETTE + 2 * index + 1] as u32;
+        let mut rgb = 0;
+        for shift in [8, 4, 0] {
+            let gun = (entry >> shift) & 0xf;
+            // On the STE the fourth bit is the least significant one
+            let intensity = if self.ste { (((gun & 0x7) << 1) | (gun >> 3)) * 0x11 } else { (gun & 0x7) * 0xff / 7 };
+            rgb = rgb << 8 | intensity;
+        }
+        rgb
+    }
+    fn palette(&self) -> Vec<u32> {
+        if self.high() {
+            let (black, white) = (0, 0xffffff);
+            if self.registers[PALETTE + 1] & 1 != 0 { vec![white, black] } else { vec![black, white] }
+        } else {
+            (0..16).map(|index| self.color(index)).collect()
+        }
+    }
+    // Vertical display enable, decided at the start of each line
+    fn start_line(&mut self) {
+        let line = self.line;
+        if (line == TOP_60 && (self.high() || !self.hz50())) || (line == TOP_50 && !self.high() && self.hz50()) {
+            self.vertical_enable = true;
+        }
+        if (line == BOTTOM_HIGH && self.high())
+            || (line == BOTTOM_60 && !self.high() && !self.hz50())
+            || (line == BOTTOM_50 && !self.high() && self.hz50())
+            || (line == BOTTOM_BLANK && !self.high())
+        {
+            self.vertical_enable = false;
+        }
+        // The border has the background colour
+        if self.display && self.frame_lines != MONO.1 && (TOP_60..TOP_60 + HEIGHT / 2).contains(&line) {
+            let background = self.color(0);
+            let row = 2 * (line - TOP_60) * WIDTH;
+            self.framebuffer[row..row + 2 * WIDTH].fill(background);
+        }
+    }
+    // Horizontal display enable, decided at fixed positions within the line
+    fn display_enable(&mut self, position: u64) {
+        let (high, hz50) = (self.high(), self.hz50());
+        match position {
+            START_HIGH if high => self.display_enable = true,
+            START_60 if !high && !hz50 => self.display_enable = true,
+            START_50 if !high && hz50 => self.display_enable = true,
+            END_HIGH if high => self.display_enable = false,
+            END_60 if !hz50 => self.display_enable = false,
+            END_50 if hz50 => self.display_enable = false,
+            END_BLANK => self.display_enable = false,
+            _ => {}
+        }
+    }
+    // Draws the 16 pixels in the shift registers, which were fetched ending at the given position.
+    // When the picture is scrolled, the pixels come from the previous and the current group.
+    fn draw(&mut self, position: u64) {
+        let planes = self.words.len();
+        let mut start = position + 4 - 4 * planes as u64;
+        let scroll = (self.registers[PIXEL_SCROLL] & 0xf) as usize;
+        let words: Vec<u32> = if scroll == 0 {
+            self.words.iter().map(|&word| (word as u32) << 16).collect()
+        } else if self.previous.len() == planes {
+            start -= 4 * planes as u64;
+            self.previous.iter().zip(&self.words).map(|(&previous, &word)| ((previous as u32) << 16 | word as u32) << scroll).collect()
+        } else {
+            self.previous = self.words.clone();
+            return;
+        };
+        self.previous = self.words.clone();
+        let palette = self.palette();
+        // The frame buffer has two pixels per cycle in the colour modes and one per ST-Low pixel
+        let (x, y, width, height) = if self.frame_lines == MONO.1 {
+            if !(TOP_60..BOTTOM_HIGH).contains(&self.line) {
+                return;
+            }
+            (MONO_LEFT + 4 * start.saturating_sub(START_HIGH) as usize, MONO_TOP + self.line - TOP_60, 1, 1)
+        } else {
+            if !(TOP_60..TOP_60 + HEIGHT / 2).contains(&self.line) || start < VISIBLE_START {
+                return;
+            }
+            (2 * (start - VISIBLE_START) as usize, 2 * (self.line - TOP_60), (planes / 2).max(1), 2)
+        };
+        for pixel in 0..16 {
+            let bit = 31 - pixel;
+            let index = words.iter().enumerate().fold(0, |index, (plane, word)| index | (((word >> bit) & 1) as usize) << plane);
+            let color = palette[index.min(palette.len() - 1)];
+            let left = x + pixel * width;
+            if left + width > WIDTH {
+                break;
+            }
+            for row in y..y + height {
+                self.framebuffer[row * WIDTH + left..row * WIDTH + left + width].fill(color);
+            }
+        }
+    }
+    fn update_de(&mut self) {
+        let de = self.vertical_enable && self.display_enable;
+        if *self.de.borrow() != de {
+            *self.de.borrow_mut() = de;
+            let mut edges = self.de_edges.borrow_mut();
+            *edges = edges.wrapping_add(1);
+        }
+    }
+    // Moves the beam up to the current cycle, four cycles (one word of screen memory) at a time.
+    // Returns true if screen memory has to be read for that and no bus was given.
+    fn advance(&mut self, mut bus: Option<&mut Bus>) -> bool {
+        loop {
+            let line_length = self.timing().0;
+            let target = self.cycles.saturating_sub(self.line_start).min(line_length);
+            while self.position < target {
+                if self.position == 0 {
+                    self.start_line();
+                }
+                let enabled = self.display_enable;
+                self.display_enable(self.position);
+                self.update_de();
+                if enabled && !self.display_enable && self.registers[PIXEL_SCROLL] & 0xf != 0 {
+                    self.prefetch = self.planes();
+                }
+                if self.vertical_enable && (self.display_enable || self.prefetch > 0) {
+                    match bus.as_mut() {
+                        Some(bus) => self.words.push(bus.read(self.counter, Size::Word).inner() as u16),
+                        // Without a display only the video counter moves on
+                        None if !self.display => self.words.push(0),
+                        None => return true,
+                    }
+                    if !self.display_enable {
+                        self.prefetch -= 1;
+                    }
+                    self.counter += 2;
+                    self.line_fetched = true;
+                    if self.words.len() >= self.planes() {
+                        if self.display {
+                            self.draw(self.position);
+                        }
+                        self.words.clear();
+                    }
+                }
+                self.position += 4;
+            }
+            if self.cycles < self.line_start + line_length {
+                return false;
+            }
+            if self.line_fetched {
+                self.counter += 2 * self.registers[LINE_OFFSET] as usize;
+            }
+            self.line_start += line_length;
+            self.line += 1;
+            self.position = 0;
+            self.display_enable = false;
+            self.update_de();
+            self.words.clear();
+            self.previous.clear();
+            self.prefetch = 0;
+            self.line_fetched = false;
+            self.hbl += 1;
+            if self.line >= self.frame_lines {
+                self.line = 0;
+                self.counter = self.video_base();
+                self.vertical_enable = false;
+                self.vbl += 1;
+                self.frame += 1;
+                if self.display {
+                    self.show();
+                }
+                self.frame_lines = self.timing().1;
+                if self.frame_lines == MONO.1 {
+                    self.framebuffer.fill(0);
+                }
+            }
+        }
+    }
+    fn show(&mut self) {
+        if !self.headless && self.window.is_none() {
+            match Window::new("em68k", WIDTH, HEIGHT, WindowOptions::default()) {
+                Ok(mut window) => {
+                    window.limit_update_rate(None);
+                    window.set_cursor_visibility(!self.grab_mouse);
+                    self.window = Some(window);
+                }
+                Err(error) => {
+                    eprintln!("Unable to open a window ({}), continuing without display", error);
+                    // Screenshots and recordings still need the picture
+                    if self.screenshot.is_none() && self.video.is_none() {
+                        self.display = false;
+                        return;
+                    }
+                    self.headless = true;
+                }
+            }
+        }
+        if let Some(window) = self.window.as_mut() {
+            window.update_with_buffer(&self.framebuffer, WIDTH, HEIGHT).ok();
+        }
+        self.poll_input();
+        self.capture();
+    }
+    fn capture(&mut self) {
+        let frame_cycles = self.timing().0 * self.frame_lines as u64;
+        if let Some(video) = self.video.as_mut() {
+            video.frame(&self.framebuffer, WIDTH, HEIGHT, (CPU_FREQUENCY, frame_cycles));
+        }
+        let requested = std::mem::take(&mut self.screenshot_key) | screenshot_requested();
+        let path = match &self.screenshot {
+            Some((path, Some(frame))) if *frame == self.frame => path.clone(),
+            Some((path, _)) if requested => match path.rsplit_once('.') {
+                Some((stem, extension)) => format!("{}-{}.{}", stem, self.frame, extension),
+                None => format!("{}-{}", path, self.frame),
+            },
+            _ => return,
+        };
+        if let Err(error) = save_screenshot(&path, &self.framebuffer, WIDTH, HEIGHT) {
+            eprintln!("Unable to save the screenshot ({})", error);
+        }
+    }
+    fn poll_input(&mut self) {
+        let window = match self.window.as_mut() {
+            Some(window) => window,
+            None => return,
+        };
+        let mut input = self.input.borrow_mut();
+        for key in window.get_keys_released().unwrap_or_default() {
+            input.push_back(InputEvent::Key(key, false));
+        }
+        for key in window.get_keys_pressed(KeyRepeat::No).unwrap_or_default() {
+            if key == Key::ScrollLock {
+                self.screenshot_key = true;
+            }
+            input.push_back(InputEvent::Key(key, true));
+        }
+        let buttons = (window.get_mouse_down(MouseButton::Left), window.get_mouse_down(MouseButton::Right), window.get_mouse_down(MouseButton::Middle));
+        if buttons.2 && !self.mouse_buttons.2 {
+            self.grab_mouse = !self.grab_mouse;
+            window.set_cursor_visibility(!self.grab_mouse);
+        }
+        if (buttons.0, buttons.1) != (self.mouse_buttons.0, self.mouse_buttons.1) {
+            input.push_back(InputEvent::MouseButtons(buttons.0, buttons.1));
+        }
+        self.mouse_buttons = buttons;
+        let mode = if self.grab_mouse { MouseMode::Pass } else { MouseMode::Discard };
+        let position = window.get_mouse_pos(mode);
+        if let (Some((x, y)), Some((last_x, last_y))) = (position, self.mouse_position) {
+            // A colour pixel is two pixels wide in the frame buffer
+            let scale = if self.frame_lines == MONO.1 { self.mouse_speed } else { self.mouse_speed / 2.0 };
+            let (dx, dy) = (self.mouse_motion.0 + (x - last_x) * scale, self.mouse_motion.1 + (y - last_y) * scale);
+            self.mouse_motion = (dx.fract(), dy.fract());
+            if dx.trunc() != 0.0 || dy.trunc() != 0.0 {
+                input.push_back(InputEvent::MouseMove(dx.trunc() as i32, dy.trunc() as i32));
+            }
+        }
+        self.mouse_position = position;
+    }
+    fn read_byte(&self, address: usize) -> u8 {
+        match address - self.address {
+            COUNTER_HIGH => (self.counter >> 16) as u8,
+            COUNTER_MID => (self.counter >> 8) as u8,
+            COUNTER_LOW => self.counter as u8,
+            offset => self.registers[offset],
+        }
+    }
+    fn write_byte(&mut self, address: usize, value: u8) {
+        let offset = address - self.address;
+        if self.ste {
+            match offset {
+                COUNTER_HIGH => self.counter = (self.counter & 0x00ffff) | (value as usize) << 16,
+                COUNTER_MID => self.counter = (self.counter & 0xff00ff) | (value as usize) << 8,
+                COUNTER_LOW => self.counter = (self.counter & 0xffff00) | (value & 0xfe) as usize,
+                // For compatibility with the ST, setting the base the old way clears its low byte
+                BASE_HIGH | BASE_MID => self.registers[BASE_LOW] = 0,
+                _ => {}
+            }
+        }
+        self.registers[offset] = match offset {
+            COUNTER_HIGH | COUNTER_MID | COUNTER_LOW => return,
+            BASE_LOW | LINE_OFFSET | PIXEL_SCROLL if !self.ste => return,
+            // The ST's palette only has three bits per colour gun
+            PALETTE..=0x5f if !self.ste => value & if offset & 1 == 0 { 0x07 } else { 0x77 },
+            _ => value,
+        };
+    }
+}
+
+impl Device for Monitor {
+    fn memconfig(&self) -> MemoryRange {
+        vec![(self.address, self.address + 0x70)]
+    }
+    fn read(&mut self, address: usize, size: Size) -> OpResult {
+        read_bytes(address, size, |a| self.read_byte(a))
+    }
+    fn write(&mut self, address: usize, result: OpResult) -> Signal {
+        write_bytes(address, result, |a, b| self.write_byte(a, b));
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        if self.vbl > 0 {
+            self.vbl -= 1;
+            return Some(IRQ { level: VBL_LEVEL, vector: None });
+        }
+        if self.hbl > 0 {
+            self.hbl -= 1;
+            return Some(IRQ { level: HBL_LEVEL, vector: None });
+        }
+        None
+    }
+    fn poll(&self) -> Signal {
+        match (&self.window, self.frames) {
+            (Some(window), _) if !window.is_open() => Signal::Quit,
+            (_, Some(frames)) if self.frame >= frames => Signal::Quit,
+            _ => Signal::Ok,
+        }
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        self.cycles = cycles;
+        if self.advance(None) {
+            Signal::BusRequest
+        } else {
+            Signal::Ok
+        }
+    }
+    fn bus_access(&mut self, bus: &mut Bus) {
+        // The Shifter's memory accesses are interleaved with the CPU's and do not slow it down
+        let cycles = bus.cycles;
+        self.advance(Some(bus));
+        bus.cycles = cycles;
+        bus.bus_error = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_passed_in_one_clock_each_request_an_hbl() {
+        let mut monitor = Monitor::new(0xff8200, false);
+        monitor.display = false;
+        let line_length = monitor.timing().0;
+        monitor.clock(3 * line_length + 1);
+        for _ in 0..3 {
+            assert_eq!(monitor.interrupt_request(), Some(IRQ { level: HBL_LEVEL, vector: None }));
+        }
+        assert_eq!(monitor.interrupt_request(), None);
+    }
+
+    #[test]
+    fn vbl_is_requested_before_pending_hbls() {
+        let mut monitor = Monitor::new(0xff8200, false);
+        monitor.display = false;
+        let (line_length, lines) = monitor.timing();
+        monitor.clock(lines as u64 * line_length + 1);
+        assert_eq!(monitor.interrupt_request(), Some(IRQ { level: VBL_LEVEL, vector: None }));
+        assert_eq!(monitor.interrupt_request(), Some(IRQ { level: HBL_LEVEL, vector: None }));
+    }
+
+    #[test]
+    fn lines_passed_in_one_clock_each_count_their_display_enable_edges() {
+        let mut monitor = Monitor::new(0xff8200, false);
+        monitor.display = false;
+        let (line_length, lines) = monitor.timing();
+        monitor.clock(lines as u64 * line_length + 1);
+        assert!(!*monitor.de.borrow());
+        assert_eq!(*monitor.de_edges.borrow(), 2 * 200);
+    }
+}
@@ -0,0 +1,828 @@
+// High level emulation of the operating system: instead of booting TOS, a program (PRG/TOS/TTP) is
+// loaded into memory directly and the GEMDOS (TRAP #1), BIOS (TRAP #13) and XBIOS (TRAP #14) calls
+// it makes are implemented natively. Console I/O goes to the host's stdin/stdout, file I/O to a
+// host directory mounted as drive C. This is good enough to run command line tools and test
+// programs without a ROM image and without a display.
+
+use crate::devices::{Monitor, Ram, Signal};
+use crate::fields::{OpResult, OpResult::*, Size};
+use crate::hostfs::*;
+use crate::memory::Bus;
+use crate::processor::{TrapHandler, CPU};
+use crate::Configuration;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::time::SystemTime;
+
+pub const BIOS: usize = 45;
+pub const XBIOS: usize = 46;
+
+const RAM_SIZE: u32 = 0x400000;
+const VIDEO_BASE: u32 = 0x3f8000;
+const SUPERVISOR_STACK: u32 = 0x2000;
+const TPA_START: u32 = SUPERVISOR_STACK;
+const TPA_END: u32 = VIDEO_BASE;
+
+// Small code fragments the CPU is sent to by the emulated operating system
+const RETURN_FROM_EXCEPTION: u32 = 0x600;
+const TERMINATE: u32 = 0x602;
+const ABORT: u32 = 0x606;
+const ENVIRONMENT: u32 = 0x700;
+
+const DRIVE: char = 'C';
+
+const MEMORY_LAYOUT: [(usize, OpResult); 13] = [
+    (0x0, Long(SUPERVISOR_STACK)),
+    (0x4, Long(TERMINATE)),
+    // rte
+    (0x600, Word(0x4e73)),
+    // clr.w -(sp); trap #1 (Pterm0)
+    (0x602, Word(0x4267)),
+    (0x604, Word(0x4e41)),
+    // move.w #-1,-(sp); move.w #$4c,-(sp); trap #1 (Pterm(-1))
+    (0x606, Word(0x3f3c)),
+    (0x608, Word(0xffff)),
+    (0x60a, Word(0x3f3c)),
+    (0x60c, Word(0x004c)),
+    (0x60e, Word(0x4e41)),
+    //   $42E.L      Phystop (Physical RAM top)
+    (0x42e, Long(RAM_SIZE)),
+    //   $44E.L      _V_bas_ad (Screen memory base pointer)
+    (0x44e, Long(VIDEO_BASE)),
+    //   $4C2.L      _Drvbits (32 bit vector of live block devices)
+    (0x4c2, Long(1 << 2)),
+];
+
+// A machine with nothing but RAM and the video registers, for running programs without TOS
+pub fn configuration() -> Configuration {
+    let mut bus = Bus::with_address_lines(24);
+    bus.attach(Ram::new(RAM_SIZE as usize));
+    let mut monitor = Monitor::new(0xff8200, false);
+    monitor.display = false;
+    bus.attach(monitor);
+    let mut memory_layout = Vec::from(MEMORY_LAYOUT);
+    // Bus errors, address errors, illegal instructions etc. terminate the program
+    for vector in 2..12 {
+        memory_layout.push((4 * vector, Long(ABORT)));
+    }
+    // Interrupts (e.g. the vertical blank) are ignored
+    for vector in 24..32 {
+        memory_layout.push((4 * vector, Long(RETURN_FROM_EXCEPTION)));
+    }
+    memory_layout.push((0xff8201, Byte((VIDEO_BASE >> 16) as u8)));
+    memory_layout.push((0xff8203, Byte((VIDEO_BASE >> 8) as u8)));
+    memory_layout.push((ENVIRONMENT as usize, Long(0)));
+    Configuration {
+        base_address: TPA_START,
+        start_address: TERMINATE,
+        initial_ssp: SUPERVISOR_STACK,
+        bus,
+        memory_layout,
+    }
+}
+
+const PTERM0: u16 = 0x00;
+const CCONIN: u16 = 0x01;
+const CCONOUT: u16 = 0x02;
+const CAUXIN: u16 = 0x03;
+const CAUXOUT: u16 = 0x04;
+const CPRNOUT: u16 = 0x05;
+const CRAWIO: u16 = 0x06;
+const CRAWCIN: u16 = 0x07;
+const CNECIN: u16 = 0x08;
+const CCONWS: u16 = 0x09;
+const CCONRS: u16 = 0x0a;
+const CCONIS: u16 = 0x0b;
+const CCONOS: u16 = 0x10;
+const CPRNOS: u16 = 0x11;
+const CAUXIS: u16 = 0x12;
+const CAUXOS: u16 = 0x13;
+const DGETDRV: u16 = 0x19;
+const SUPER: u16 = 0x20;
+const TGETDATE: u16 = 0x2a;
+const TSETDATE: u16 = 0x2b;
+const TGETTIME: u16 = 0x2c;
+const TSETTIME: u16 = 0x2d;
+const FGETDTA: u16 = 0x2f;
+const SVERSION: u16 = 0x30;
+const PTERMRES: u16 = 0x31;
+const MXALLOC: u16 = 0x44;
+const MALLOC: u16 = 0x48;
+const MFREE: u16 = 0x49;
+const MSHRINK: u16 = 0x4a;
+const PEXEC: u16 = 0x4b;
+const PTERM: u16 = 0x4c;
+
+const BCONSTAT: u16 = 0x01;
+const BCONIN: u16 = 0x02;
+const BCONOUT: u16 = 0x03;
+const SETEXC: u16 = 0x05;
+const TICKCAL: u16 = 0x06;
+const BCOSTAT: u16 = 0x08;
+const MEDIACH: u16 = 0x09;
+const DRVMAP: u16 = 0x0a;
+const KBSHIFT: u16 = 0x0b;
+
+const INITMOUS: u16 = 0x00;
+const PHYSBASE: u16 = 0x02;
+const LOGBASE: u16 = 0x03;
+const GETREZ: u16 = 0x04;
+const SETSCREEN: u16 = 0x05;
+const SETPALETTE: u16 = 0x06;
+const SETCOLOR: u16 = 0x07;
+const RANDOM: u16 = 0x11;
+const CURSCONF: u16 = 0x15;
+const SETTIME: u16 = 0x16;
+const GETTIME: u16 = 0x17;
+const VSYNC: u16 = 0x25;
+const SUPEXEC: u16 = 0x26;
+
+const EDRIVE: i32 = -46;
+const ENSMEM: i32 = -39;
+const EIMBA: i32 = -40;
+const EPLFMT: i32 = -66;
+const EGSBF: i32 = -67;
+
+// Pexec modes
+const LOAD_AND_GO: u16 = 0;
+const LOAD: u16 = 3;
+const GO: u16 = 4;
+const CREATE_BASEPAGE: u16 = 5;
+const GO_AND_FREE: u16 = 6;
+const CREATE_BASEPAGE_WITH_FLAGS: u16 = 7;
+
+const VIDEO_BASE_HIGH: u32 = 0xff8201;
+const VIDEO_BASE_MID: u32 = 0xff8203;
+const PALETTE: u32 = 0xff8240;
+const SHIFT_MODE: u32 = 0xff8260;
+
+const BASEPAGE_SIZE: u32 = 0x100;
+const PRG_MAGIC: u16 = 0x601a;
+const PRG_HEADER_SIZE: usize = 0x1c;
+
+struct MemoryBlock {
+    start: u32,
+    length: u32,
+    owner: u32,
+}
+
+// The processor state of a parent process while it waits for its child to terminate
+struct Context {
+    pc: u32,
+    sr: u32,
+    dr: [u32; 8],
+    ar: [u32; 8],
+    ssp: u32,
+    dta: Option<u32>,
+}
+
+impl Context {
+    fn save(cpu: &CPU, dta: Option<u32>) -> Self {
+        let mut context = Context { pc: cpu.pc, sr: cpu.sr, dr: [0; 8], ar: [0; 8], ssp: *cpu.ssp.borrow(), dta };
+        for j in 0..8 {
+            context.dr[j] = *cpu.dr[j].borrow();
+            context.ar[j] = *cpu.ar[j].borrow();
+        }
+        context
+    }
+    fn restore(&self, cpu: &mut CPU) {
+        for j in 0..8 {
+            *cpu.dr[j].borrow_mut() = self.dr[j];
+            *cpu.ar[j].borrow_mut() = self.ar[j];
+        }
+        *cpu.ssp.borrow_mut() = self.ssp;
+        cpu.sr = self.sr;
+        cpu.pc = self.pc;
+    }
+}
+
+struct Process {
+    basepage: u32,
+    parent: Option<Context>,
+}
+
+pub struct HighLevelEmulation {
+    hostfs: HostFileSystem,
+    memory: Vec<MemoryBlock>,
+    processes: Vec<Process>,
+    logbase: u32,
+    seed: u32,
+    pub exit_code: i32,
+}
+
+impl HighLevelEmulation {
+    pub fn new(directory: &str) -> Self {
+        let mut hostfs = HostFileSystem::new(DRIVE, directory);
+        hostfs.current_drive = Some((DRIVE as u8 - b'A') as usize);
+        hostfs.dta = Some(0);
+        Self { hostfs, memory: Vec::new(), processes: Vec::new(), logbase: VIDEO_BASE, seed: 1, exit_code: 0 }
+    }
+    fn allocate(&mut self, length: u32, owner: u32) -> Option<u32> {
+        let length = (length + 1) & !1;
+        let start = self.free_blocks().into_iter().find(|&(_, free)| free >= length)?.0;
+        self.memory.push(MemoryBlock { start, length, owner });
+        self.memory.sort_by_key(|block| block.start);
+        Some(start)
+    }
+    fn free_blocks(&self) -> Vec<(u32, u32)> {
+        let mut free = Vec::new();
+        let mut start = TPA_START;
+        for block in &self.memory {
+            if block.start > start {
+                free.push((start, block.start - start));
+            }
+            start = block.start + block.length;
+        }
+        if TPA_END > start {
+            free.push((start, TPA_END - start));
+        }
+        free
+    }
+    fn largest_free_block(&self) -> (u32, u32) {
+        self.free_blocks().into_iter().max_by_key(|&(_, length)| length).unwrap_or((0, 0))
+    }
+    fn current_process(&self) -> u32 {
+        self.processes.last().map_or(0, |process| process.basepage)
+    }
+    // Creates a basepage owning the largest free memory block, like TOS does for a new process
+    fn create_basepage(&mut self, command_line: &[u8], environment: u32, cpu: &CPU) -> Option<u32> {
+        let (start, length) = self.largest_free_block();
+        if length < BASEPAGE_SIZE {
+            return None;
+        }
+        self.memory.push(MemoryBlock { start, length, owner: start });
+        self.memory.sort_by_key(|block| block.start);
+        write_memory(cpu, start, &[0; BASEPAGE_SIZE as usize]);
+        poke(cpu, start, Long(start));
+        poke(cpu, start + 0x04, Long(start + length));
+        poke(cpu, start + 0x08, Long(start + BASEPAGE_SIZE));
+        poke(cpu, start + 0x20, Long(start + 0x80));
+        poke(cpu, start + 0x24, Long(self.current_process()));
+        poke(cpu, start + 0x2c, Long(environment));
+        poke(cpu, start + 0x37, Byte(DRIVE as u8 - b'A'));
+        let length = command_line.first().map_or(0, |&length| length.min(125) as usize);
+        write_memory(cpu, start + 0x80, &command_line[..command_line.len().min(length + 1)]);
+        Some(start)
+    }
+    // Loads a program into a new basepage and returns the basepage or a GEMDOS error
+    fn load(&mut self, program: &[u8], command_line: &[u8], environment: u32, cpu: &CPU) -> i32 {
+        let word = |offset: usize| u16::from_be_bytes([program[offset], program[offset + 1]]);
+        let long = |offset: usize| u32::from_be_bytes([program[offset], program[offset + 1], program[offset + 2], program[offset + 3]]);
+        if program.len() < PRG_HEADER_SIZE || word(0) != PRG_MAGIC {
+            return EPLFMT;
+        }
+        let (text, data, bss, symbols) = (long(2), long(6), long(10), long(14));
+        let relocate = word(26) == 0;
+        // The sizes in a damaged header may add up to more than there is room for in any type
+        let image = match text.checked_add(data) {
+            Some(image) => image,
+            None => return EPLFMT,
+        };
+        let end = match PRG_HEADER_SIZE.checked_add(image as usize) {
+            Some(end) if end <= program.len() => end,
+            _ => return EPLFMT,
+        };
+        let mut position = match end.checked_add(symbols as usize) {
+            Some(position) => position,
+            None => return EPLFMT,
+        };
+        let size = match BASEPAGE_SIZE.checked_add(image).and_then(|size| size.checked_add(bss)) {
+            Some(size) => size,
+            None => return ENSMEM,
+        };
+        let basepage = match self.create_basepage(command_line, environment, cpu) {
+            Some(basepage) => basepage,
+            None => return ENSMEM,
+        };
+        if peek(cpu, basepage + 4, Size::Long) - basepage < size {
+            self.free_memory(basepage);
+            return ENSMEM;
+        }
+        let tbase = basepage + BASEPAGE_SIZE;
+        poke(cpu, basepage + 0x0c, Long(text));
+        poke(cpu, basepage + 0x10, Long(tbase + text));
+        poke(cpu, basepage + 0x14, Long(data));
+        poke(cpu, basepage + 0x18, Long(tbase + image));
+        poke(cpu, basepage + 0x1c, Long(bss));
+        write_memory(cpu, tbase, &program[PRG_HEADER_SIZE..end]);
+        write_memory(cpu, tbase + image, &vec![0; bss as usize]);
+        // The fixup table lists the longs to relocate: the offset of the first one, followed by the
+        // distances to the next ones (1 meaning 254 bytes further without a fixup), ended by a zero.
+        if relocate && position <= program.len() - 4 && long(position) != 0 {
+            let mut offset = tbase.wrapping_add(long(position));
+            position += 4;
+            loop {
+                let value = peek(cpu, offset, Size::Long);
+                poke(cpu, offset, Long(value.wrapping_add(tbase)));
+                loop {
+                    match program.get(position) {
+                        None | Some(0) => return basepage as i32,
+                        Some(1) => offset = offset.wrapping_add(254),
+                        Some(&distance) => {
+                            offset = offset.wrapping_add(distance as u32);
+                            position += 1;
+                            break;
+                        }
+                    }
+                    position += 1;
+                }
+            }
+        }
+        basepage as i32
+    }
+    fn free_memory(&mut self, owner: u32) {
+        self.memory.retain(|block| block.owner != owner);
+    }
+    // Starts the process of the given basepage, remembering the calling one if there is any
+    fn start(&mut self, basepage: u32, cpu: &mut CPU) {
+        let parent = if self.processes.is_empty() { None } else { Some(Context::save(cpu, self.hostfs.dta)) };
+        self.processes.push(Process { basepage, parent });
+        self.hostfs.dta = Some(basepage + 0x80);
+        let sp = peek(cpu, basepage + 4, Size::Long) - 8;
+        poke(cpu, sp, Long(TERMINATE));
+        poke(cpu, sp + 4, Long(basepage));
+        for j in 0..8 {
+            *cpu.dr[j].borrow_mut() = 0;
+            *cpu.ar[j].borrow_mut() = 0;
+        }
+        *cpu.ar[7].borrow_mut() = sp;
+        *cpu.ssp.borrow_mut() = SUPERVISOR_STACK;
+        cpu.sr = 0;
+        cpu.pc = peek(cpu, basepage + 8, Size::Long);
+    }
+    fn terminate(&mut self, exit_code: i32, keep: bool, cpu: &mut CPU) -> Signal {
+        let process = match self.processes.pop() {
+            Some(process) => process,
+            None => return Signal::Quit,
+        };
+        if !keep {
+            self.free_memory(process.basepage);
+        }
+        match process.parent {
+            Some(context) => {
+                context.restore(cpu);
+                self.hostfs.dta = context.dta;
+                *cpu.dr[0].borrow_mut() = exit_code as u32;
+                Signal::Ok
+            }
+            None => {
+                self.exit_code = exit_code;
+                Signal::Quit
+            }
+        }
+    }
+    // Loads the program from the host and runs it as the initial process
+    pub fn run(&mut self, program: &str, arguments: &[String], cpu: &mut CPU) -> Result<(), String> {
+        let program = fs::read(program).map_err(|e| format!("{}: {}", program, e))?;
+        let mut command_line = arguments.join(" ").into_bytes();
+        command_line.truncate(125);
+        command_line.insert(0, command_line.len() as u8);
+        command_line.push(0);
+        match self.load(&program, &command_line, ENVIRONMENT, cpu) {
+            EPLFMT => Err(String::from("Not a GEMDOS executable")),
+            ENSMEM => Err(String::from("Program does not fit into memory")),
+            basepage => {
+                self.start(basepage as u32, cpu);
+                Ok(())
+            }
+        }
+    }
+    fn pexec(&mut self, args: &mut Arguments, cpu: &mut CPU) -> Option<Signal> {
+        let mode = args.word(cpu);
+        let name = args.long(cpu);
+        let command_line = args.long(cpu);
+        let environment = args.long(cpu);
+        let environment = if environment == 0 {
+            peek(cpu, self.current_process() + 0x2c, Size::Long)
+        } else {
+            environment
+        };
+        let result = match mode {
+            LOAD_AND_GO | LOAD => {
+                let path = match self.hostfs.host_path(&read_string(cpu, name), cpu) {
+                    Some(Ok(path)) => path,
+                    Some(Err(error)) => return self.result(error, cpu),
+                    None => return self.result(EDRIVE, cpu),
+                };
+                let program = match fs::read(path) {
+                    Ok(program) => program,
+                    Err(_) => return self.result(EFILNF, cpu),
+                };
+                let command_line = read_memory(cpu, command_line, 128);
+                let basepage = self.load(&program, &command_line, environment, cpu);
+                if mode == LOAD || basepage < 0 {
+                    basepage
+                } else {
+                    self.start(basepage as u32, cpu);
+                    return Some(Signal::Ok);
+                }
+            }
+            GO | GO_AND_FREE => {
+                // The basepage is passed in place of the command line
+                let basepage = command_line;
+                if mode == GO {
+                    // The memory stays with the parent
+                    let parent = self.current_process();
+                    for block in self.memory.iter_mut().filter(|block| block.owner == basepage) {
+                        block.owner = parent;
+                    }
+                }
+                self.start(basepage, cpu);
+                return Some(Signal::Ok);
+            }
+            CREATE_BASEPAGE | CREATE_BASEPAGE_WITH_FLAGS => {
+                let command_line = read_memory(cpu, command_line, 128);
+                match self.create_basepage(&command_line, environment, cpu) {
+                    Some(basepage) => basepage as i32,
+                    None => ENSMEM,
+                }
+            }
+            _ => EINVFN,
+        };
+        self.result(result, cpu)
+    }
+    fn result(&self, result: i32, cpu: &mut CPU) -> Option<Signal> {
+        *cpu.dr[0].borrow_mut() = result as u32;
+        Some(Signal::Ok)
+    }
+    // Switches between user and supervisor mode on behalf of the program
+    fn set_supervisor(&self, stack: u32, cpu: &mut CPU) -> i32 {
+        if stack == 1 {
+            return if cpu.in_supervisor_mode() { -1 } else { 0 };
+        }
+        if cpu.in_supervisor_mode() {
+            let sp = *cpu.ssp.borrow();
+            *cpu.ar[7].borrow_mut() = sp;
+            *cpu.ssp.borrow_mut() = stack;
+            cpu.supervisor_mode(false);
+            0
+        } else {
+            let old_stack = *cpu.ssp.borrow();
+            let stack = if stack == 0 { *cpu.ar[7].borrow() } else { stack };
+            *cpu.ssp.borrow_mut() = stack;
+            cpu.supervisor_mode(true);
+            old_stack as i32
+        }
+    }
+    // Calls a routine in supervisor mode. It returns through an exception frame, which restores
+    // the mode of the caller.
+    fn supervisor_call(&self, routine: u32, cpu: &mut CPU) {
+        let (sr, pc) = (cpu.sr, cpu.pc);
+        cpu.supervisor_mode(true);
+        let ssp = *cpu.ssp.borrow() - 10;
+        poke(cpu, ssp, Long(RETURN_FROM_EXCEPTION));
+        poke(cpu, ssp + 4, Word(sr as u16));
+        poke(cpu, ssp + 6, Long(pc));
+        *cpu.ssp.borrow_mut() = ssp;
+        cpu.pc = routine;
+    }
+    fn gemdos(&mut self, cpu: &mut CPU) -> Option<Signal> {
+        let sp = *cpu.ar(7).borrow();
+        if let Some(result) = self.hostfs.gemdos(peek(cpu, sp, Size::Word) as u16, &mut Arguments::new(sp + 2), cpu) {
+            return self.result(result, cpu);
+        }
+        let mut args = Arguments::new(sp);
+        let opcode = args.word(cpu);
+        let result = match opcode {
+            PTERM0 => return Some(self.terminate(0, false, cpu)),
+            PTERM => {
+                let exit_code = args.word(cpu) as i16 as i32;
+                return Some(self.terminate(exit_code, false, cpu));
+            }
+            PTERMRES => {
+                let keep = args.long(cpu);
+                let exit_code = args.word(cpu) as i16 as i32;
+                let basepage = self.current_process();
+                if let Some(block) = self.memory.iter_mut().find(|block| block.start == basepage) {
+                    block.length = keep.max(BASEPAGE_SIZE).min(block.length);
+                }
+                return Some(self.terminate(exit_code, true, cpu));
+            }
+            CCONIN | CRAWCIN | CNECIN => read_console() as i32,
+            CCONOUT => write_console(&[args.word(cpu) as u8]),
+            CRAWIO => match args.word(cpu) {
+                0xff => read_console() as i32,
+                c => write_console(&[c as u8]),
+            },
+            CCONWS => write_console(read_string(cpu, args.long(cpu)).as_bytes()),
+            CCONRS => {
+                let buffer = args.long(cpu);
+                let length = peek(cpu, buffer, Size::Byte) as usize;
+                let mut line = String::new();
+                io::stdin().lock().read_line(&mut line).ok();
+                let line: Vec<u8> = line.trim_end_matches(&['\r', '\n'][..]).bytes().take(length).collect();
+                poke(cpu, buffer + 1, Byte(line.len() as u8));
+                write_memory(cpu, buffer + 2, &line);
+                line.len() as i32
+            }
+            CCONIS | CCONOS | CPRNOS | CAUXOS => -1,
+            CAUXIS | CAUXIN => 0,
+            CAUXOUT | CPRNOUT => -1,
+            DGETDRV => (DRIVE as u8 - b'A') as i32,
+            SUPER => {
+                let stack = args.long(cpu);
+                self.set_supervisor(stack, cpu)
+            }
+            TGETDATE | TGETTIME => {
+                let (time, date) = dos_datetime(SystemTime::now());
+                if opcode == TGETDATE { date as i32 } else { time as i32 }
+            }
+            TSETDATE | TSETTIME => E_OK,
+            FGETDTA => self.hostfs.dta.unwrap_or(0) as i32,
+            SVERSION => 0x1500,
+            MALLOC | MXALLOC => {
+                let length = args.long(cpu);
+                if length == u32::MAX {
+                    self.largest_free_block().1 as i32
+                } else {
+                    let owner = self.current_process();
+                    self.allocate(length, owner).unwrap_or(0) as i32
+                }
+            }
+            MFREE => {
+                let start = args.long(cpu);
+                match self.memory.iter().position(|block| block.start == start) {
+                    Some(j) => {
+                        self.memory.remove(j);
+                        E_OK
+                    }
+                    None => EIMBA,
+                }
+            }
+            MSHRINK => {
+                args.word(cpu);
+                let start = args.long(cpu);
+                let length = (args.long(cpu) + 1) & !1;
+                match self.memory.iter_mut().find(|block| block.start == start) {
+                    Some(block) if length <= block.length => {
+                        block.length = length;
+                        E_OK
+                    }
+                    Some(_) => EGSBF,
+                    None => EIMBA,
+                }
+            }
+            PEXEC => return self.pexec(&mut args, cpu),
+            // Calls left over by the host file system concern other drives or the standard handles
+            DCREATE | DDELETE | DSETPATH | FCREATE | FOPEN | FDELETE | FATTRIB | FSFIRST | FRENAME | DFREE | DGETPATH => EDRIVE,
+            FSNEXT => ENMFIL,
+            FREAD => match args.word(cpu) {
+                0 => {
+                    let count = args.long(cpu);
+                    let buffer = args.long(cpu);
+                    read_into_memory(&mut io::stdin(), cpu, buffer, count).unwrap_or(0) as i32
+                }
+                _ => EIHNDL,
+            },
+            FWRITE => match args.word(cpu) {
+                handle @ 1..=2 => {
+                    let count = args.long(cpu);
+                    let buffer = args.long(cpu);
+                    if handle == 1 {
+                        let mut stdout = io::stdout();
+                        write_from_memory(&mut stdout, cpu, buffer, count).ok();
+                        stdout.flush().ok();
+                    } else {
+                        write_from_memory(&mut io::stderr(), cpu, buffer, count).ok();
+                    }
+                    count as i32
+                }
+                _ => EIHNDL,
+            },
+            FCLOSE => match args.word(cpu) {
+                0..=5 => E_OK,
+                _ => EIHNDL,
+            },
+            FSEEK | FDATIME => EIHNDL,
+            _ => {
+                eprintln!("GEMDOS call {:#04x} is not implemented", opcode);
+                EINVFN
+            }
+        };
+        self.result(result, cpu)
+    }
+    fn bios(&mut self, cpu: &mut CPU) -> Option<Signal> {
+        let mut args = Arguments::new(*cpu.ar(7).borrow());
+        let opcode = args.word(cpu);
+        let result = match opcode {
+            BCONSTAT | BCOSTAT => -1,
+            BCONIN => read_console() as i32,
+            BCONOUT => {
+                let device = args.word(cpu);
+                let c = args.word(cpu) as u8;
+                if device == 2 || device == 5 {
+                    write_console(&[c]);
+                }
+                -1
+            }
+            SETEXC => {
+                let address = 4 * args.word(cpu) as u32;
+                let vector = args.long(cpu);
+                let old_vector = peek(cpu, address, Size::Long);
+                if vector != u32::MAX {
+                    poke(cpu, address, Long(vector));
+                }
+                old_vector as i32
+            }
+            TICKCAL => 20,
+            MEDIACH => 0,
+            DRVMAP => peek(cpu, 0x4c2, Size::Long) as i32,
+            KBSHIFT => 0,
+            _ => {
+                eprintln!("BIOS call {:#04x} is not implemented", opcode);
+                EINVFN
+            }
+        };
+        self.result(result, cpu)
+    }
+    fn xbios(&mut self, cpu: &mut CPU) -> Option<Signal> {
+        let mut args = Arguments::new(*cpu.ar(7).borrow());
+        let opcode = args.word(cpu);
+        let result = match opcode {
+            INITMOUS | CURSCONF | SETTIME | VSYNC => 0,
+            PHYSBASE => {
+                let high = peek(cpu, VIDEO_BASE_HIGH, Size::Byte);
+                let mid = peek(cpu, VIDEO_BASE_MID, Size::Byte);
+                (high << 16 | mid << 8) as i32
+            }
+            LOGBASE => self.logbase as i32,
+            GETREZ => (peek(cpu, SHIFT_MODE, Size::Byte) & 0x3) as i32,
+            SETSCREEN => {
+                let logbase = args.long(cpu);
+                let physbase = args.long(cpu);
+                let resolution = args.word(cpu);
+                if logbase as i32 >= 0 {
+                    self.logbase = logbase;
+                }
+                if physbase as i32 >= 0 {
+                    poke(cpu, VIDEO_BASE_HIGH, Byte((physbase >> 16) as u8));
+                    poke(cpu, VIDEO_BASE_MID, Byte((physbase >> 8) as u8));
+                }
+                if resolution as i16 >= 0 {
+                    poke(cpu, SHIFT_MODE, Byte(resolution as u8));
+                }
+                0
+            }
+            SETPALETTE => {
+                let palette = args.long(cpu);
+                for j in 0..16 {
+                    let color = peek(cpu, palette + 2 * j, Size::Word);
+                    poke(cpu, PALETTE + 2 * j, Word(color as u16));
+                }
+                0
+            }
+            SETCOLOR => {
+                let register = PALETTE + 2 * (args.word(cpu) as u32 & 0xf);
+                let color = args.word(cpu);
+                let old_color = peek(cpu, register, Size::Word);
+                if color as i16 >= 0 {
+                    poke(cpu, register, Word(color & 0x777));
+                }
+                old_color as i32
+            }
+            RANDOM => {
+                // The linear congruential generator of TOS
+                self.seed = self.seed.wrapping_mul(3141592621).wrapping_add(1);
+                ((self.seed >> 8) & 0xffffff) as i32
+            }
+            GETTIME => {
+                let (time, date) = dos_datetime(SystemTime::now());
+                ((date as u32) << 16 | time as u32) as i32
+            }
+            SUPEXEC => {
+                let routine = args.long(cpu);
+                self.supervisor_call(routine, cpu);
+                return Some(Signal::Ok);
+            }
+            _ => {
+                eprintln!("XBIOS call {:#04x} is not implemented", opcode);
+                EINVFN
+            }
+        };
+        self.result(result, cpu)
+    }
+}
+
+fn read_console() -> u8 {
+    let mut c = [0];
+    io::stdout().flush().ok();
+    match io::stdin().read(&mut c) {
+        Ok(1) => c[0],
+        _ => 0,
+    }
+}
+
+fn write_console(data: &[u8]) -> i32 {
+    let mut stdout = io::stdout();
+    stdout.write_all(data).ok();
+    stdout.flush().ok();
+    E_OK
+}
+
+impl TrapHandler for HighLevelEmulation {
+    fn trap(&mut self, vector: usize, cpu: &mut CPU) -> Option<Signal> {
+        match vector {
+            GEMDOS => self.gemdos(cpu),
+            BIOS => self.bios(cpu),
+            XBIOS => self.xbios(cpu),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    const STACK: u32 = 0x1000;
+    const NAME: u32 = 0x1100;
+
+    fn cpu() -> CPU {
+        let register = || Rc::new(RefCell::new(0));
+        let mut bus = Bus::new();
+        bus.attach(Ram::new(0x10000));
+        CPU::new(0, 0, [(); 8].map(|_| register()), [(); 8].map(|_| register()), register(), Rc::new(RefCell::new(bus)))
+    }
+
+    fn emulation(name: &str) -> (HighLevelEmulation, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("em68k-hle-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir(&directory).unwrap();
+        (HighLevelEmulation::new(directory.to_str().unwrap()), directory)
+    }
+
+    // Calls GEMDOS with the arguments on the stack, a name taking the place of its address, and
+    // returns D0
+    fn call(hle: &mut HighLevelEmulation, cpu: &mut CPU, arguments: &[OpResult], name: &str) -> i32 {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        write_memory(cpu, NAME, &name);
+        let mut address = STACK;
+        for argument in arguments {
+            poke(cpu, address, *argument);
+            address += argument.size() as u32;
+        }
+        *cpu.ar(7).borrow_mut() = STACK;
+        assert!(hle.trap(GEMDOS, cpu).is_some());
+        *cpu.dr[0].borrow() as i32
+    }
+
+    #[test]
+    fn programs_and_files_stay_within_the_directory() {
+        let (mut hle, directory) = emulation("escape");
+        let mut cpu = cpu();
+        let pexec = [Word(PEXEC), Word(LOAD), Long(NAME), Long(0), Long(0)];
+        assert_eq!(call(&mut hle, &mut cpu, &pexec, "C:\\/BIN/SH"), EPTHNF);
+        assert_eq!(call(&mut hle, &mut cpu, &pexec, "..\\../BIN/SH"), EPTHNF);
+        assert_eq!(call(&mut hle, &mut cpu, &pexec, "C:\\NONE.PRG"), EFILNF);
+        let fopen = [Word(FOPEN), Long(NAME), Word(0)];
+        assert_eq!(call(&mut hle, &mut cpu, &fopen, "C:\\/ETC/PASSWD"), EPTHNF);
+        assert_eq!(call(&mut hle, &mut cpu, &fopen, "A:\\/ETC/PASSWD"), EDRIVE);
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    fn program(text: &[u8], data: u32, bss: u32, symbols: u32, fixups: &[u8]) -> Vec<u8> {
+        let mut program = vec![0x60, 0x1a];
+        for size in &[text.len() as u32, data, bss, symbols, 0, 0] {
+            program.extend_from_slice(&size.to_be_bytes());
+        }
+        program.extend_from_slice(&[0, 0]);
+        program.extend_from_slice(text);
+        program.extend_from_slice(fixups);
+        program
+    }
+
+    #[test]
+    fn load_relocates_the_program() {
+        let (mut hle, directory) = emulation("load");
+        let cpu = cpu();
+        let text = [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 8];
+        let basepage = hle.load(&program(&text, 0, 0x10, 0, &[0, 0, 0, 4, 8, 0]), &[0], 0, &cpu);
+        assert_eq!(basepage, TPA_START as i32);
+        let tbase = TPA_START + BASEPAGE_SIZE;
+        assert_eq!(peek(&cpu, basepage as u32 + 0x18, Size::Long), tbase + 16);
+        assert_eq!(read_memory(&cpu, tbase, 4), [0xff; 4]);
+        assert_eq!(peek(&cpu, tbase + 4, Size::Long), tbase + 4);
+        assert_eq!(peek(&cpu, tbase + 12, Size::Long), tbase + 8);
+        fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn damaged_headers_are_refused() {
+        let (mut hle, directory) = emulation("header");
+        let cpu = cpu();
+        let mut text_and_data = program(&[0; 4], u32::MAX - 2, 0, 0, &[]);
+        assert_eq!(hle.load(&text_and_data, &[0], 0, &cpu), EPLFMT);
+        text_and_data[6..10].copy_from_slice(&0x10u32.to_be_bytes());
+        assert_eq!(hle.load(&text_and_data, &[0], 0, &cpu), EPLFMT);
+        assert_eq!(hle.load(&program(&[0; 4], 0, u32::MAX - 0x80, 0, &[]), &[0], 0, &cpu), ENSMEM);
+        assert_eq!(hle.load(&program(&[0; 4], 0, TPA_END, 0, &[]), &[0], 0, &cpu), ENSMEM);
+        // The memory of refused programs is not kept
+        assert!(hle.memory.is_empty());
+        assert_eq!(hle.load(&program(&[0; 4], 0, 0, u32::MAX, &[]), &[0], 0, &cpu), TPA_START as i32);
+        fs::remove_dir_all(&directory).ok();
+    }
+}
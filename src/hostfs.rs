@@ -0,0 +1,766 @@
+// A GEMDOS drive backed by a directory of the host file system. The file related GEMDOS calls
+// (TRAP #1) are intercepted before they reach TOS; calls concerning the host drive are served
+// here, everything else is passed on to TOS.
+// Host file names are presented to the ST in 8.3 format, i.e. upper case and truncated, and
+// GEMDOS names are mapped back by looking for the host file with the same 8.3 name.
+
+use crate::devices::Signal;
+use crate::fields::{OpResult, Size};
+use crate::processor::{TrapHandler, CPU};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const GEMDOS: usize = 33;
+
+pub const DSETDRV: u16 = 0x0e;
+pub const FSETDTA: u16 = 0x1a;
+pub const DFREE: u16 = 0x36;
+pub const DCREATE: u16 = 0x39;
+pub const DDELETE: u16 = 0x3a;
+pub const DSETPATH: u16 = 0x3b;
+pub const FCREATE: u16 = 0x3c;
+pub const FOPEN: u16 = 0x3d;
+pub const FCLOSE: u16 = 0x3e;
+pub const FREAD: u16 = 0x3f;
+pub const FWRITE: u16 = 0x40;
+pub const FDELETE: u16 = 0x41;
+pub const FSEEK: u16 = 0x42;
+pub const FATTRIB: u16 = 0x43;
+pub const DGETPATH: u16 = 0x47;
+pub const FSFIRST: u16 = 0x4e;
+pub const FSNEXT: u16 = 0x4f;
+pub const FRENAME: u16 = 0x56;
+pub const FDATIME: u16 = 0x57;
+
+pub const E_OK: i32 = 0;
+pub const EFILNF: i32 = -33;
+pub const EPTHNF: i32 = -34;
+pub const EACCDN: i32 = -36;
+pub const EIHNDL: i32 = -37;
+pub const EINVFN: i32 = -32;
+pub const ENMFIL: i32 = -49;
+
+const FA_READONLY: u8 = 0x01;
+const FA_HIDDEN: u8 = 0x02;
+const FA_SYSTEM: u8 = 0x04;
+const FA_VOLUME: u8 = 0x08;
+const FA_DIRECTORY: u8 = 0x10;
+const FA_ARCHIVE: u8 = 0x20;
+
+// Handles of host files are kept clear of the ones handed out by TOS
+const FIRST_HANDLE: u16 = 64;
+
+// System variables and the basepage, which hold the current process' drive and DTA
+const SYSBASE: u32 = 0x4f2;
+const DRVBITS: u32 = 0x4c2;
+const OS_RUN: u32 = 0x28;
+const P_DTA: u32 = 0x20;
+const P_DEFDRV: u32 = 0x37;
+
+pub fn peek(cpu: &CPU, address: u32, size: Size) -> u32 {
+    cpu.bus.borrow_mut().read(address as usize, size).inner()
+}
+
+pub fn poke(cpu: &CPU, address: u32, result: OpResult) {
+    cpu.bus.borrow_mut().write(address as usize, result);
+}
+
+pub fn read_string(cpu: &CPU, address: u32) -> String {
+    let mut string = String::new();
+    for j in 0.. {
+        match peek(cpu, address + j, Size::Byte) as u8 {
+            0 => break,
+            c => string.push(c as char),
+        }
+    }
+    string
+}
+
+pub fn write_memory(cpu: &CPU, address: u32, data: &[u8]) {
+    for (j, &byte) in data.iter().enumerate() {
+        poke(cpu, address + j as u32, OpResult::Byte(byte));
+    }
+}
+
+// Reads up to count bytes into memory, in chunks so that the guest's count is never allocated at
+// once. A short read ends it, as at the end of a file or of a line on the console.
+pub fn read_into_memory(input: &mut impl Read, cpu: &CPU, address: u32, count: u32) -> io::Result<u32> {
+    let mut data = vec![0; count.min(0x10000) as usize];
+    let mut length = 0;
+    while length < count {
+        let chunk = (count - length).min(data.len() as u32) as usize;
+        match input.read(&mut data[..chunk]) {
+            Ok(read) => {
+                write_memory(cpu, address + length, &data[..read]);
+                length += read as u32;
+                if read < chunk {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(length)
+}
+
+// Writes count bytes from memory, in chunks as well
+pub fn write_from_memory(output: &mut impl Write, cpu: &CPU, address: u32, count: u32) -> io::Result<u32> {
+    let mut length = 0;
+    while length < count {
+        let chunk = (count - length).min(0x10000);
+        output.write_all(&read_memory(cpu, address + length, chunk as usize))?;
+        length += chunk;
+    }
+    Ok(length)
+}
+
+pub fn read_memory(cpu: &CPU, address: u32, length: usize) -> Vec<u8> {
+    (0..length as u32).map(|j| peek(cpu, address + j, Size::Byte) as u8).collect()
+}
+
+// Arguments of an operating system call as found on the caller's stack
+pub struct Arguments {
+    address: u32,
+}
+
+impl Arguments {
+    pub fn new(address: u32) -> Self {
+        Self { address }
+    }
+    pub fn word(&mut self, cpu: &CPU) -> u16 {
+        self.address += 2;
+        peek(cpu, self.address - 2, Size::Word) as u16
+    }
+    pub fn long(&mut self, cpu: &CPU) -> u32 {
+        self.address += 4;
+        peek(cpu, self.address - 4, Size::Long)
+    }
+}
+
+pub fn dos_datetime(time: SystemTime) -> (u16, u16) {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let t = Local.timestamp(seconds, 0);
+    let time = (t.hour() << 11 | t.minute() << 5 | (t.second() / 2)) as u16;
+    let date = (((t.year() - 1980).max(0) as u32) << 9 | t.month() << 5 | t.day()) as u16;
+    (time, date)
+}
+
+fn system_time(time: u16, date: u16) -> Option<SystemTime> {
+    let (year, month, day) = (1980 + (date >> 9) as i32, (date >> 5) as u32 & 0xf, date as u32 & 0x1f);
+    let (hour, minute, second) = ((time >> 11) as u32, (time >> 5) as u32 & 0x3f, 2 * (time as u32 & 0x1f));
+    let datetime = Local.ymd_opt(year, month, day).single()?.and_hms_opt(hour, minute, second)?;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(datetime.timestamp().max(0) as u64))
+}
+
+// Converts a host file name into an upper case 8.3 name
+pub fn short_name(name: &str) -> String {
+    let valid = |c: &char| c.is_ascii_alphanumeric() || "_-!#$%&'()@^{}~".contains(*c);
+    if name == "." || name == ".." {
+        return String::from(name);
+    }
+    let (base, extension) = match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(j) => (&name[..j], &name[j + 1..]),
+    };
+    let base: String = base.chars().filter(valid).take(8).collect();
+    let extension: String = extension.chars().filter(valid).take(3).collect();
+    let mut short = base.to_ascii_uppercase();
+    if !extension.is_empty() {
+        short.push('.');
+        short.push_str(&extension.to_ascii_uppercase());
+    }
+    short
+}
+
+// GEMDOS style wildcard matching, where name and extension are matched separately
+pub fn matches(pattern: &str, name: &str) -> bool {
+    fn split(name: &str) -> (&str, &str) {
+        match name.rfind('.') {
+            Some(j) if name != "." && name != ".." => (&name[..j], &name[j + 1..]),
+            _ => (name, ""),
+        }
+    }
+    fn glob(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => true,
+            (Some(b'?'), None) => glob(&pattern[1..], name),
+            (Some(b'?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) => p.eq_ignore_ascii_case(n) && glob(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+    let (pattern_base, pattern_extension) = split(pattern);
+    let (base, extension) = split(name);
+    glob(pattern_base.as_bytes(), base.as_bytes()) && glob(pattern_extension.as_bytes(), extension.as_bytes())
+}
+
+struct DirectoryEntry {
+    name: String,
+    attributes: u8,
+    time: u16,
+    date: u16,
+    length: u32,
+}
+
+impl DirectoryEntry {
+    fn new(name: String, path: &PathBuf) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let (time, date) = dos_datetime(metadata.modified().unwrap_or(UNIX_EPOCH));
+        let mut attributes = if metadata.is_dir() { FA_DIRECTORY } else { FA_ARCHIVE };
+        if metadata.permissions().readonly() {
+            attributes |= FA_READONLY;
+        }
+        Some(Self { name, attributes, time, date, length: metadata.len().min(u32::MAX as u64) as u32 })
+    }
+    // Layout of the public part of the disk transfer address (DTA)
+    fn write_dta(&self, cpu: &CPU, dta: u32) {
+        poke(cpu, dta + 21, OpResult::Byte(self.attributes));
+        poke(cpu, dta + 22, OpResult::Word(self.time));
+        poke(cpu, dta + 24, OpResult::Word(self.date));
+        poke(cpu, dta + 26, OpResult::Long(if self.attributes & FA_DIRECTORY != 0 { 0 } else { self.length }));
+        let mut name = self.name.as_bytes().to_vec();
+        name.resize(14, 0);
+        write_memory(cpu, dta + 30, &name);
+    }
+}
+
+pub struct HostFileSystem {
+    drive: usize,
+    root: PathBuf,
+    path: Vec<String>,
+    files: HashMap<u16, File>,
+    searches: HashMap<u32, Vec<DirectoryEntry>>,
+    // Used when there is no TOS keeping track of the current process
+    pub current_drive: Option<usize>,
+    pub dta: Option<u32>,
+}
+
+impl HostFileSystem {
+    pub fn new(drive: char, directory: &str) -> Self {
+        Self {
+            drive: (drive.to_ascii_uppercase() as u8 - b'A') as usize,
+            root: PathBuf::from(directory),
+            path: Vec::new(),
+            files: HashMap::new(),
+            searches: HashMap::new(),
+            current_drive: None,
+            dta: None,
+        }
+    }
+    fn basepage(&self, cpu: &CPU) -> u32 {
+        let sysbase = peek(cpu, SYSBASE, Size::Long);
+        if sysbase == 0 {
+            return 0;
+        }
+        peek(cpu, peek(cpu, sysbase + OS_RUN, Size::Long), Size::Long)
+    }
+    fn current_drive(&self, cpu: &CPU) -> usize {
+        if let Some(drive) = self.current_drive {
+            return drive;
+        }
+        match self.basepage(cpu) {
+            0 => 0,
+            basepage => peek(cpu, basepage + P_DEFDRV, Size::Byte) as usize,
+        }
+    }
+    fn dta(&self, cpu: &CPU) -> u32 {
+        if let Some(dta) = self.dta {
+            return dta;
+        }
+        match self.basepage(cpu) {
+            0 => 0,
+            basepage => peek(cpu, basepage + P_DTA, Size::Long),
+        }
+    }
+    fn host_name(directory: &PathBuf, name: &str) -> PathBuf {
+        if let Ok(entries) = fs::read_dir(directory) {
+            for entry in entries.flatten() {
+                let host_name = entry.file_name().to_string_lossy().to_string();
+                if short_name(&host_name).eq_ignore_ascii_case(name) {
+                    return directory.join(host_name);
+                }
+            }
+        }
+        directory.join(name)
+    }
+    // Splits a GEMDOS path into the components relative to the root directory of the host drive,
+    // or returns None if the path refers to a different drive. Names that the host would take for
+    // more than one component, or for an absolute path, are refused.
+    fn components(&self, path: &str, cpu: &CPU) -> Option<Result<Vec<String>, i32>> {
+        let (drive, path) = match path.as_bytes() {
+            [letter, b':', ..] => ((letter.to_ascii_uppercase().wrapping_sub(b'A')) as usize, &path[2..]),
+            _ => (self.current_drive(cpu), path),
+        };
+        if drive != self.drive {
+            return None;
+        }
+        let mut components = if path.starts_with('\\') { Vec::new() } else { self.path.clone() };
+        for component in path.split('\\') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                name if name.contains(&['/', '\0'][..]) => return Some(Err(EPTHNF)),
+                name => match Path::new(name).components().next() {
+                    Some(Component::Normal(_)) => components.push(name.to_ascii_uppercase()),
+                    _ => return Some(Err(EPTHNF)),
+                },
+            }
+        }
+        Some(Ok(components))
+    }
+    // Symbolic links may still point out of the root directory, so the part of the path that
+    // exists has to be found within it
+    fn resolve(&self, components: &[String]) -> Result<PathBuf, i32> {
+        let path = components.iter().fold(self.root.clone(), |directory, name| Self::host_name(&directory, name));
+        let root = self.root.canonicalize().map_err(|_| EPTHNF)?;
+        match path.ancestors().find_map(|ancestor| ancestor.canonicalize().ok()) {
+            Some(existing) if existing.starts_with(&root) => Ok(path),
+            _ => Err(EPTHNF),
+        }
+    }
+    // Returns None for paths on other drives, or the host path or a GEMDOS error
+    pub fn host_path(&self, path: &str, cpu: &CPU) -> Option<Result<PathBuf, i32>> {
+        self.components(path, cpu).map(|components| components.and_then(|components| self.resolve(&components)))
+    }
+    fn owns(&self, handle: u16) -> bool {
+        self.files.contains_key(&handle)
+    }
+    fn open(&mut self, file: File) -> i32 {
+        let handle = (FIRST_HANDLE..).find(|handle| !self.files.contains_key(handle)).unwrap();
+        self.files.insert(handle, file);
+        handle as i32
+    }
+    fn search(&mut self, pattern: &str, attributes: u8, cpu: &CPU) -> Option<i32> {
+        let mut components = match self.components(pattern, cpu)? {
+            Ok(components) => components,
+            Err(error) => return Some(error),
+        };
+        let dta = self.dta(cpu);
+        let pattern = components.pop().unwrap_or_else(|| String::from("*.*"));
+        let directory = match self.resolve(&components) {
+            Ok(directory) => directory,
+            Err(error) => return Some(error),
+        };
+        let mut entries = Vec::new();
+        if let Ok(listing) = fs::read_dir(&directory) {
+            let mut names: Vec<String> = listing.flatten().map(|e| e.file_name().to_string_lossy().to_string()).collect();
+            names.sort();
+            if !components.is_empty() {
+                names.insert(0, String::from(".."));
+                names.insert(0, String::from("."));
+            }
+            for name in names {
+                let short = short_name(&name);
+                if short.is_empty() || !matches(&pattern, &short) {
+                    continue;
+                }
+                if let Some(entry) = DirectoryEntry::new(short, &directory.join(&name)) {
+                    let hidden = entry.attributes & (FA_HIDDEN | FA_SYSTEM | FA_DIRECTORY);
+                    if hidden & !attributes == 0 && attributes != FA_VOLUME {
+                        entries.push(entry);
+                    }
+                }
+            }
+        } else {
+            return Some(EPTHNF);
+        }
+        entries.reverse();
+        self.searches.insert(dta, entries);
+        Some(self.search_next(dta, cpu))
+    }
+    fn search_next(&mut self, dta: u32, cpu: &CPU) -> i32 {
+        match self.searches.get_mut(&dta).and_then(|entries| entries.pop()) {
+            Some(entry) => {
+                entry.write_dta(cpu, dta);
+                E_OK
+            }
+            None => {
+                self.searches.remove(&dta);
+                ENMFIL
+            }
+        }
+    }
+    // Executes a GEMDOS call if it concerns the host drive and returns the result for D0
+    pub fn gemdos(&mut self, opcode: u16, args: &mut Arguments, cpu: &CPU) -> Option<i32> {
+        match opcode {
+            DSETDRV => {
+                let drive = args.word(cpu) as usize;
+                if self.current_drive.is_some() {
+                    self.current_drive = Some(drive);
+                    return Some(1 << self.drive | 0x3);
+                }
+                None
+            }
+            FSETDTA => {
+                let dta = args.long(cpu);
+                if self.dta.is_some() {
+                    self.dta = Some(dta);
+                    return Some(E_OK);
+                }
+                None
+            }
+            DFREE => {
+                let buffer = args.long(cpu);
+                let drive = args.word(cpu) as usize;
+                let drive = if drive == 0 { self.current_drive(cpu) } else { drive - 1 };
+                if drive != self.drive {
+                    return None;
+                }
+                // Free clusters, total clusters, sector size and sectors per cluster
+                for (j, &value) in [0x8000u32, 0x10000, 512, 2].iter().enumerate() {
+                    poke(cpu, buffer + 4 * j as u32, OpResult::Long(value));
+                }
+                Some(E_OK)
+            }
+            DCREATE | DDELETE => {
+                let path = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                let result = if opcode == DCREATE { fs::create_dir(&path) } else { fs::remove_dir(&path) };
+                Some(if result.is_ok() { E_OK } else { EACCDN })
+            }
+            DSETPATH => {
+                let components = match self.components(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(components) => components,
+                    Err(error) => return Some(error),
+                };
+                if !self.resolve(&components).is_ok_and(|directory| directory.is_dir()) {
+                    return Some(EPTHNF);
+                }
+                self.path = components;
+                Some(E_OK)
+            }
+            DGETPATH => {
+                let buffer = args.long(cpu);
+                let drive = args.word(cpu) as usize;
+                let drive = if drive == 0 { self.current_drive(cpu) } else { drive - 1 };
+                if drive != self.drive {
+                    return None;
+                }
+                let mut path: Vec<u8> = self.path.iter().flat_map(|c| format!("\\{}", c).into_bytes()).collect();
+                path.push(0);
+                write_memory(cpu, buffer, &path);
+                Some(E_OK)
+            }
+            FCREATE => {
+                let path = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                let attributes = args.word(cpu) as u8;
+                if attributes & FA_VOLUME != 0 {
+                    return Some(EACCDN);
+                }
+                match File::create(&path) {
+                    Ok(file) => Some(self.open(file)),
+                    Err(_) => Some(EACCDN),
+                }
+            }
+            FOPEN => {
+                let path = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                let mode = args.word(cpu) & 0x3;
+                let file = OpenOptions::new().read(mode != 1).write(mode != 0).open(&path);
+                match file {
+                    Ok(file) => Some(self.open(file)),
+                    Err(_) if path.exists() => Some(EACCDN),
+                    Err(_) => Some(EFILNF),
+                }
+            }
+            FCLOSE => {
+                let handle = args.word(cpu);
+                self.files.remove(&handle).map(|_| E_OK)
+            }
+            FREAD | FWRITE => {
+                let handle = args.word(cpu);
+                if !self.owns(handle) {
+                    return None;
+                }
+                let count = args.long(cpu);
+                let buffer = args.long(cpu);
+                let file = self.files.get_mut(&handle).unwrap();
+                if opcode == FREAD {
+                    match read_into_memory(file, cpu, buffer, count) {
+                        Ok(length) => Some(length as i32),
+                        Err(_) => Some(EACCDN),
+                    }
+                } else {
+                    match write_from_memory(file, cpu, buffer, count) {
+                        Ok(length) => Some(length as i32),
+                        Err(_) => Some(EACCDN),
+                    }
+                }
+            }
+            FDELETE => {
+                let path = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                match fs::remove_file(&path) {
+                    Ok(_) => Some(E_OK),
+                    Err(_) if path.exists() => Some(EACCDN),
+                    Err(_) => Some(EFILNF),
+                }
+            }
+            FSEEK => {
+                let offset = args.long(cpu) as i32 as i64;
+                let handle = args.word(cpu);
+                let mode = args.word(cpu);
+                let file = self.files.get_mut(&handle)?;
+                let position = match mode {
+                    0 => SeekFrom::Start(offset.max(0) as u64),
+                    1 => SeekFrom::Current(offset),
+                    _ => SeekFrom::End(offset),
+                };
+                Some(file.seek(position).map_or(EIHNDL, |position| position as i32))
+            }
+            FATTRIB => {
+                let path = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                let set = args.word(cpu) != 0;
+                let attributes = args.word(cpu) as u8;
+                let metadata = match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_) => return Some(EFILNF),
+                };
+                if set {
+                    let mut permissions = metadata.permissions();
+                    permissions.set_readonly(attributes & FA_READONLY != 0);
+                    if fs::set_permissions(&path, permissions).is_err() {
+                        return Some(EACCDN);
+                    }
+                    return Some(attributes as i32);
+                }
+                let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+                Some(DirectoryEntry::new(name, &path).map_or(EFILNF, |entry| entry.attributes as i32))
+            }
+            FSFIRST => {
+                let pattern = read_string(cpu, args.long(cpu));
+                let attributes = args.word(cpu) as u8;
+                let result = self.search(&pattern, attributes, cpu);
+                if result.is_none() {
+                    // The DTA now belongs to a search on a TOS drive
+                    let dta = self.dta(cpu);
+                    self.searches.remove(&dta);
+                }
+                result
+            }
+            FSNEXT => {
+                let dta = self.dta(cpu);
+                if !self.searches.contains_key(&dta) {
+                    return None;
+                }
+                Some(self.search_next(dta, cpu))
+            }
+            FRENAME => {
+                args.word(cpu);
+                let old = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                let new = match self.host_path(&read_string(cpu, args.long(cpu)), cpu)? {
+                    Ok(path) => path,
+                    Err(error) => return Some(error),
+                };
+                Some(if fs::rename(&old, &new).is_ok() { E_OK } else { EACCDN })
+            }
+            FDATIME => {
+                let buffer = args.long(cpu);
+                let handle = args.word(cpu);
+                let set = args.word(cpu) != 0;
+                let file = self.files.get_mut(&handle)?;
+                if set {
+                    let time = peek(cpu, buffer, Size::Word) as u16;
+                    let date = peek(cpu, buffer + 2, Size::Word) as u16;
+                    return match system_time(time, date).map(|modified| file.set_modified(modified)) {
+                        Some(Ok(_)) => Some(E_OK),
+                        _ => Some(EACCDN),
+                    };
+                }
+                let modified = file.metadata().and_then(|m| m.modified()).unwrap_or(UNIX_EPOCH);
+                let (time, date) = dos_datetime(modified);
+                poke(cpu, buffer, OpResult::Word(time));
+                poke(cpu, buffer + 2, OpResult::Word(date));
+                Some(E_OK)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl TrapHandler for HostFileSystem {
+    fn trap(&mut self, vector: usize, cpu: &mut CPU) -> Option<Signal> {
+        if vector != GEMDOS {
+            return None;
+        }
+        // TOS only lets programs access drives registered in _drvbits
+        let drvbits = peek(cpu, DRVBITS, Size::Long);
+        poke(cpu, DRVBITS, OpResult::Long(drvbits | 1 << self.drive));
+        let sp = *cpu.ar(7).borrow();
+        let mut args = Arguments::new(sp);
+        let opcode = args.word(cpu);
+        let result = self.gemdos(opcode, &mut args, cpu)?;
+        *cpu.dr[0].borrow_mut() = result as u32;
+        Some(Signal::Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Ram;
+    use crate::memory::Bus;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const ARGUMENTS: u32 = 0x100;
+    const DTA: u32 = 0x200;
+    const NAME: u32 = 0x300;
+    const BUFFER: u32 = 0x1000;
+
+    fn cpu() -> CPU {
+        let register = || Rc::new(RefCell::new(0));
+        let mut bus = Bus::new();
+        bus.attach(Ram::new(0x40000));
+        CPU::new(0, 0, [(); 8].map(|_| register()), [(); 8].map(|_| register()), register(), Rc::new(RefCell::new(bus)))
+    }
+
+    // A host directory as drive C:, which is also the current drive
+    fn host_drive(name: &str) -> (HostFileSystem, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("em68k-hostfs-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+        fs::create_dir(&directory).unwrap();
+        let mut host = HostFileSystem::new('C', directory.to_str().unwrap());
+        host.current_drive = Some(2);
+        host.dta = Some(DTA);
+        (host, directory)
+    }
+
+    // Calls GEMDOS with the arguments on the "stack", a name taking the place of its address
+    fn call(host: &mut HostFileSystem, cpu: &CPU, opcode: u16, arguments: &[OpResult], name: &str) -> Option<i32> {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        write_memory(cpu, NAME, &name);
+        let mut address = ARGUMENTS;
+        for argument in arguments {
+            poke(cpu, address, *argument);
+            address += argument.size() as u32;
+        }
+        host.gemdos(opcode, &mut Arguments::new(ARGUMENTS), cpu)
+    }
+
+    fn names(host: &mut HostFileSystem, cpu: &CPU, pattern: &str, attributes: u16) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut result = call(host, cpu, FSFIRST, &[OpResult::Long(NAME), OpResult::Word(attributes)], pattern);
+        while result == Some(E_OK) {
+            names.push(read_string(cpu, DTA + 30));
+            result = call(host, cpu, FSNEXT, &[], "");
+        }
+        assert_eq!(result, Some(ENMFIL));
+        names
+    }
+
+    fn cleanup(directory: &Path) {
+        fs::remove_dir_all(directory).ok();
+    }
+
+    #[test]
+    fn create_write_and_read_back() {
+        let (mut host, directory) = host_drive("files");
+        let cpu = cpu();
+        let handle = call(&mut host, &cpu, FCREATE, &[OpResult::Long(NAME), OpResult::Word(0)], "C:\\HELLO.TXT").unwrap();
+        assert!(handle >= FIRST_HANDLE as i32);
+        write_memory(&cpu, BUFFER, b"Hello");
+        let write = [OpResult::Word(handle as u16), OpResult::Long(5), OpResult::Long(BUFFER)];
+        assert_eq!(call(&mut host, &cpu, FWRITE, &write, ""), Some(5));
+        assert_eq!(call(&mut host, &cpu, FCLOSE, &[OpResult::Word(handle as u16)], ""), Some(E_OK));
+        assert_eq!(fs::read(directory.join("HELLO.TXT")).unwrap(), b"Hello");
+        let handle = call(&mut host, &cpu, FOPEN, &[OpResult::Long(NAME), OpResult::Word(0)], "hello.txt").unwrap();
+        write_memory(&cpu, BUFFER, &[0; 8]);
+        // Asking for far more than there is reads up to the end of the file
+        let read = [OpResult::Word(handle as u16), OpResult::Long(0x7fffffff), OpResult::Long(BUFFER)];
+        assert_eq!(call(&mut host, &cpu, FREAD, &read, ""), Some(5));
+        assert_eq!(read_memory(&cpu, BUFFER, 6), b"Hello\0");
+        assert_eq!(call(&mut host, &cpu, FREAD, &read, ""), Some(0));
+        cleanup(&directory);
+    }
+
+    #[test]
+    fn writes_longer_than_a_chunk() {
+        let (mut host, directory) = host_drive("chunks");
+        let cpu = cpu();
+        let data: Vec<u8> = (0..0x24000u32).map(|j| (j % 251) as u8).collect();
+        write_memory(&cpu, BUFFER, &data);
+        let handle = call(&mut host, &cpu, FCREATE, &[OpResult::Long(NAME), OpResult::Word(0)], "C:\\LONG.DAT").unwrap();
+        let write = [OpResult::Word(handle as u16), OpResult::Long(data.len() as u32), OpResult::Long(BUFFER)];
+        assert_eq!(call(&mut host, &cpu, FWRITE, &write, ""), Some(data.len() as i32));
+        call(&mut host, &cpu, FCLOSE, &[OpResult::Word(handle as u16)], "");
+        assert_eq!(fs::read(directory.join("LONG.DAT")).unwrap(), data);
+        cleanup(&directory);
+    }
+
+    #[test]
+    fn missing_files_and_other_drives() {
+        let (mut host, directory) = host_drive("missing");
+        let cpu = cpu();
+        assert_eq!(call(&mut host, &cpu, FOPEN, &[OpResult::Long(NAME), OpResult::Word(0)], "C:\\NONE.TXT"), Some(EFILNF));
+        // Files on other drives and handles of TOS are left to TOS
+        assert_eq!(call(&mut host, &cpu, FOPEN, &[OpResult::Long(NAME), OpResult::Word(0)], "A:\\NONE.TXT"), None);
+        let read = [OpResult::Word(6), OpResult::Long(1), OpResult::Long(BUFFER)];
+        assert_eq!(call(&mut host, &cpu, FREAD, &read, ""), None);
+        cleanup(&directory);
+    }
+
+    #[test]
+    fn paths_stay_within_the_root() {
+        let (mut host, directory) = host_drive("escape");
+        let cpu = cpu();
+        let outside = directory.parent().unwrap().join("ESCAPE.TXT");
+        fs::remove_file(&outside).ok();
+        let create = [OpResult::Long(NAME), OpResult::Word(0)];
+        assert_eq!(call(&mut host, &cpu, FCREATE, &create, "C:\\../ESCAPE.TXT"), Some(EPTHNF));
+        assert!(!outside.exists());
+        assert_eq!(call(&mut host, &cpu, FOPEN, &[OpResult::Long(NAME), OpResult::Word(0)], "C:\\/ETC/PASSWD"), Some(EPTHNF));
+        assert_eq!(call(&mut host, &cpu, FDELETE, &[OpResult::Long(NAME)], "SUB/../../ESCAPE.TXT"), Some(EPTHNF));
+        // Going up stops at the root
+        assert!(call(&mut host, &cpu, FCREATE, &create, "C:\\..\\..\\INSIDE.TXT").unwrap() >= FIRST_HANDLE as i32);
+        assert!(directory.join("INSIDE.TXT").exists());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(directory.parent().unwrap(), directory.join("link")).unwrap();
+            assert_eq!(call(&mut host, &cpu, FCREATE, &create, "C:\\LINK\\ESCAPE.TXT"), Some(EPTHNF));
+            assert!(!outside.exists());
+        }
+        cleanup(&directory);
+    }
+
+    #[test]
+    fn search_lists_short_names() {
+        let (mut host, directory) = host_drive("search");
+        let cpu = cpu();
+        fs::write(directory.join("readme.txt"), b"0123456789").unwrap();
+        fs::write(directory.join("LongFileName.data"), b"").unwrap();
+        fs::create_dir(directory.join("sub")).unwrap();
+        fs::write(directory.join("sub").join("inner.prg"), b"").unwrap();
+        assert_eq!(names(&mut host, &cpu, "C:\\*.*", 0), ["LONGFILE.DAT", "README.TXT"]);
+        assert_eq!(names(&mut host, &cpu, "C:\\*.*", FA_DIRECTORY as u16), ["LONGFILE.DAT", "README.TXT", "SUB"]);
+        assert_eq!(names(&mut host, &cpu, "C:\\READ*.TXT", 0), ["README.TXT"]);
+        assert_eq!(peek(&cpu, DTA + 26, Size::Long), 10);
+        assert_eq!(names(&mut host, &cpu, "C:\\SUB\\*.PRG", 0), ["INNER.PRG"]);
+        assert_eq!(call(&mut host, &cpu, FSFIRST, &[OpResult::Long(NAME), OpResult::Word(0)], "C:\\NONE\\*.*"), Some(EPTHNF));
+        cleanup(&directory);
+    }
+}
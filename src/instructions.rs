@@ -235,6 +235,17 @@ impl Instruction {
                 *sp += 4;
             }
             Self::TRAP { vector } => {
+                for handler in cpu.traps.clone() {
+                    if let Some(signal) = handler.borrow_mut().trap(vector, cpu) {
+                        return signal;
+                    }
+                }
+                // Line-A and Line-F exceptions stack the address of the opcode itself, whose
+                // handlers (e.g. TOS' AES dispatcher) read it from there
+                if vector == 10 || vector == 11 {
+                    cpu.pc = cpu.jmp;
+                }
+                let sr = cpu.sr;
                 cpu.supervisor_mode(true);
                 let _ssp = cpu.ar(7);
                 let mut ssp = _ssp.as_ref().borrow_mut();
@@ -243,7 +254,7 @@ impl Instruction {
                 ram_handle.write(OpResult::Long(cpu.pc));
                 *ssp -= 2;
                 ram_handle.offset(-2);
-                ram_handle.write(OpResult::Word(cpu.sr as u16));
+                ram_handle.write(OpResult::Word(sr as u16));
                 ram_handle = MemoryHandle::new(None, Some(4 * vector as usize), None, cpu);
                 cpu.pc = ram_handle.read(Long).inner();
             }
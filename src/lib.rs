@@ -13,6 +13,11 @@ use devices::Signal;
 pub mod fields;
 use fields::{EAMode, OpResult};
 pub mod atari;
+pub mod tos;
+pub mod hostfs;
+use hostfs::HostFileSystem;
+pub mod hle;
+use hle::HighLevelEmulation;
 
 pub struct Configuration {
     pub base_address: u32,
@@ -30,6 +35,23 @@ pub struct Emulator {
 impl Emulator {
     pub fn run(&mut self, program: &str, debug: bool) {
         self.load(program);
+        self.execute(debug);
+    }
+    // Starts a machine whose ROM is part of the configuration, like a real one from a reset
+    pub fn boot(&mut self, debug: bool) {
+        self.cpu.reset();
+        self.execute(debug);
+    }
+    // Runs a GEMDOS program without TOS (see hle::configuration) and returns its exit code
+    pub fn run_program(&mut self, program: &str, arguments: &[String], directory: &str, debug: bool) -> Result<i32, String> {
+        let hle = Rc::new(RefCell::new(HighLevelEmulation::new(directory)));
+        hle.borrow_mut().run(program, arguments, &mut self.cpu)?;
+        self.cpu.traps.push(hle.clone());
+        self.execute(debug);
+        let exit_code = hle.borrow().exit_code;
+        Ok(exit_code)
+    }
+    fn execute(&mut self, debug: bool) {
         let mut debugger = if debug { Some(Debugger::new()) } else { None };
         let mut idle = false;
         loop {
@@ -53,12 +75,21 @@ impl Emulator {
                     _ => (),
                 };
             }
+            match self.cpu.clock_devices() {
+                Signal::Quit => break,
+                _ => (),
+            }
             match self.cpu.poll_devices() {
                 Signal::Quit => break,
                 _ => (),
             }
         }
     }
+    // Makes the given host directory available to GEMDOS programs as drive `drive`
+    pub fn mount(&mut self, drive: char, directory: &str) {
+        let hostfs = HostFileSystem::new(drive, directory);
+        self.cpu.traps.push(Rc::new(RefCell::new(hostfs)));
+    }
     fn load(&mut self, progname: &str) {
         let program = fs::read(progname).expect("Program does not exist!");
         for (j, &b) in program.iter().enumerate() {
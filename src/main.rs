@@ -1,10 +1,139 @@
-use em68k::{Emulator, atari::st1040};
-use std::collections::HashSet;
+use chrono::NaiveDateTime;
+use em68k::atari::{Machine, MachineModel};
+use em68k::devices::{JoystickInput, KeyboardLayout};
+use em68k::tos::{MachineType, TOSImage};
+use em68k::{hle, Emulator};
 use std::env;
+use std::path::Path;
+use std::process;
+
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).and_then(|j| args.get(j + 1))
+}
+
+fn fail(error: String) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
 
 fn main() {
-    let args: HashSet<String> = env::args().collect();
+    let args: Vec<String> = env::args().collect();
     let debug = args.contains(&String::from("--debug"));
-    let mut em = Emulator::new(st1040());
-    em.run("tos/TOS104GE.IMG", debug);
+    let host_directory = option(&args, "--hostfs");
+    // --hle <program> [arguments...] runs a program without TOS
+    if let Some(j) = args.iter().position(|arg| arg == "--hle") {
+        let program = args.get(j + 1).expect("No program given!");
+        let directory = match host_directory {
+            Some(directory) => directory.clone(),
+            None => match Path::new(program).parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().to_string(),
+                _ => String::from("."),
+            },
+        };
+        let mut em = Emulator::new(hle::configuration());
+        match em.run_program(program, &args[j + 2..], &directory, debug) {
+            Ok(exit_code) => process::exit(exit_code),
+            Err(error) => fail(error),
+        }
+    }
+    let tos = option(&args, "--tos").map(|rom| TOSImage::open(rom).unwrap_or_else(|error| fail(error)));
+    // Without a model the one matching the TOS image is used
+    let model = match option(&args, "--model") {
+        Some(name) => MachineModel::from(name).unwrap_or_else(|| fail(format!("Unknown machine model {}", name))),
+        None => match tos.as_ref().map(|tos| tos.machine()) {
+            Some(MachineType::STE) => MachineModel::STE,
+            _ => MachineModel::ST1040,
+        },
+    };
+    let mut machine = Machine::new(model);
+    if let Some(tos) = tos {
+        eprintln!("{}", tos);
+        machine = machine.tos(tos);
+    }
+    // --ram <size in KB>
+    if let Some(size) = option(&args, "--ram") {
+        let size: usize = size.parse().unwrap_or_else(|_| fail(format!("Invalid RAM size {}", size)));
+        machine = machine.ram_size(size * 1024);
+    }
+    if args.contains(&String::from("--blitter")) {
+        machine = machine.blitter(true);
+    }
+    if args.contains(&String::from("--mono")) {
+        machine = machine.monochrome(true);
+    }
+    // The demo disk goes into drive A unless another one is given
+    machine = machine.floppy(option(&args, "--floppy").map_or("examples/ST0001 Mono Demos.st", String::as_str));
+    if let Some(image) = option(&args, "--acsi") {
+        machine = machine.hard_disk(image);
+    }
+    if let Some(image) = option(&args, "--cartridge") {
+        machine = machine.cartridge(image);
+    }
+    // --layout de|us|uk|fr
+    if let Some(name) = option(&args, "--layout") {
+        let layout = KeyboardLayout::from(name).unwrap_or_else(|| fail(format!("Unknown keyboard layout {}", name)));
+        machine = machine.keyboard_layout(layout);
+    }
+    // --mouse-speed <factor>, --grab hides the host mouse in the window
+    let mouse_speed = match option(&args, "--mouse-speed") {
+        Some(speed) => speed.parse().unwrap_or_else(|_| fail(format!("Invalid mouse speed {}", speed))),
+        None => 1.0,
+    };
+    machine = machine.mouse(mouse_speed, args.contains(&String::from("--grab")));
+    // --joystick 0|1|a|b=keys[:space|ctrl|alt|shift|insert]|<input device>, once per port
+    for spec in args.windows(2).filter(|pair| pair[0] == "--joystick").map(|pair| &pair[1]) {
+        let (port, input) = spec.split_once('=').unwrap_or_else(|| fail(format!("Invalid joystick {}", spec)));
+        let port = match port {
+            "0" => 0,
+            "1" => 1,
+            "a" | "A" => 2,
+            "b" | "B" => 3,
+            _ => fail(format!("Unknown joystick port {}", port)),
+        };
+        let input = JoystickInput::from(input).unwrap_or_else(|| fail(format!("Unknown joystick input {}", input)));
+        machine = machine.joystick(port, input);
+    }
+    // --midi-out <file.mid>, --midi-in <file.mid>|<raw MIDI device or FIFO>
+    if let Some(path) = option(&args, "--midi-out") {
+        machine = machine.midi_output(path);
+    }
+    if let Some(path) = option(&args, "--midi-in") {
+        machine = machine.midi_input(path);
+    }
+    // --printer [text:|pbm:]<path>
+    if let Some(spec) = option(&args, "--printer") {
+        machine = machine.printer(spec);
+    }
+    // --time <YYYY-MM-DDTHH:MM:SS>
+    if let Some(time) = option(&args, "--time") {
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S").unwrap_or_else(|_| fail(format!("Invalid time {}", time)));
+        machine = machine.time(time);
+    }
+    // --headless, --screenshot <file.png|ppm>[@<frame>], --record <file.y4m>, --frames <count>
+    if args.contains(&String::from("--headless")) {
+        machine = machine.headless(true);
+    }
+    if let Some(spec) = option(&args, "--screenshot") {
+        let (path, frame) = match spec.rsplit_once('@') {
+            Some((path, frame)) => (path, Some(frame.parse().unwrap_or_else(|_| fail(format!("Invalid frame {}", frame))))),
+            None => (spec.as_str(), None),
+        };
+        machine = machine.screenshot(path, frame);
+    }
+    if let Some(path) = option(&args, "--record") {
+        machine = machine.record(path);
+    }
+    if let Some(frames) = option(&args, "--frames") {
+        let frames = frames.parse().unwrap_or_else(|_| fail(format!("Invalid frame count {}", frames)));
+        machine = machine.frames(frames);
+    }
+    // --serial pty|unix:<path>|file:<path>
+    if let Some(spec) = option(&args, "--serial") {
+        machine = machine.serial(spec);
+    }
+    let mut em = Emulator::new(machine.build().unwrap_or_else(|error| fail(error)));
+    if let Some(directory) = host_directory {
+        em.mount('C', directory);
+    }
+    em.boot(debug);
 }
@@ -74,18 +74,26 @@ impl MemoryHandle {
 }
 
 pub struct Bus {
-    pub devices: DeviceList
+    pub devices: DeviceList,
+    pub cycles: u64,
+    pub bus_error: Option<(usize, bool)>,   // Address and direction of an unanswered access
+    address_mask: usize,
 }
 
 impl Bus {
     pub fn new() -> Self {
-        Bus { devices: DeviceList::new() }
+        Bus { devices: DeviceList::new(), cycles: 0, bus_error: None, address_mask: 0xffffffff }
+    }
+    // The 68000 only has 24 address lines, so the upper byte of an address is ignored
+    pub fn with_address_lines(lines: u32) -> Self {
+        Bus { devices: DeviceList::new(), cycles: 0, bus_error: None, address_mask: (1 << lines) - 1 }
     }
     pub fn attach(&mut self, device: Box<dyn Device>) {
         self.devices.push((device.memconfig(), device));
     }
     pub fn read(&mut self, address: usize, size: Size) -> OpResult {
-        let trunc_address = address & 0xffffffff;
+        self.bus_cycles(size);
+        let trunc_address = address & self.address_mask;
         for (range, device) in &mut self.devices {
             for (fromaddr, toaddr) in range {
                 if *fromaddr <= trunc_address && *toaddr > trunc_address {
@@ -93,11 +101,13 @@ impl Bus {
                 }
             }
         } 
-        panic!("Address {:0x} is not assigned!", trunc_address)
+        self.bus_error = Some((trunc_address, true));
+        size.from(u32::MAX)
     }
     pub fn write(&mut self, address: usize, result: OpResult) {
+        self.bus_cycles(result.size());
         let mut written = false;
-        let trunc_address = address & 0xffffffff;
+        let trunc_address = address & self.address_mask;
         for (range, device) in &mut self.devices {
             let mut remap = false;
             for (fromaddr, toaddr) in range.iter() {
@@ -118,7 +128,7 @@ impl Bus {
             }
         }
         if !written {
-            panic!("Address {:0x} is not assigned!", trunc_address)
+            self.bus_error = Some((trunc_address, false));
         }
     }
     pub fn interrupt_requests(&mut self) -> VecDeque<IRQ> {
@@ -130,6 +140,28 @@ impl Bus {
         }
         irqs
     }
+    pub fn acknowledge_interrupt(&mut self, irq: &IRQ) -> bool {
+        self.devices.iter_mut().all(|(_, device)| device.interrupt_acknowledge(irq))
+    }
+    // Every bus access takes four clock cycles per word transferred; this is what drives the
+    // timing of the devices.
+    fn bus_cycles(&mut self, size: Size) {
+        self.cycles += if size == Size::Long { 8 } else { 4 };
+    }
+    pub fn clock(&mut self) -> Signal {
+        let mut signal = Signal::Ok;
+        for j in 0..self.devices.len() {
+            match self.devices[j].1.clock(self.cycles) {
+                Signal::BusRequest => {
+                    let (range, mut device) = self.devices.remove(j);
+                    device.bus_access(self);
+                    self.devices.insert(j, (range, device));
+                }
+                device_signal => signal.add(&device_signal),
+            }
+        }
+        signal
+    }
     pub fn poll_devices(&self) -> Signal {
         let mut signal = Signal::Ok;
         for (_, device) in &self.devices {
@@ -8,6 +8,8 @@ use crate::instructions::Instruction;
 use crate::memory::{MemoryHandle, BusPtr, RegPtr};
 use crate::parser::parse_instruction;
 use crate::devices::Signal;
+use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::rc::Rc;
@@ -29,11 +31,22 @@ pub struct CPU {
     pub jmp: u32,               // Last jump location (debugger)
     pub irq: VecDeque<IRQ>,     // Interrupt request queue
     pub irp: bool,              // Interrupt in process (debugger)
+    pub traps: Vec<TrapHandlerPtr>, // Native handlers for exceptions
 }
 
-#[derive(Debug, Copy, Clone)]
+// Native implementations of exception handlers, e.g. operating system calls. A handler gets to see
+// every exception before it is processed and returns a signal if it has taken care of it, in which
+// case the CPU carries on with the next instruction instead of jumping through the exception vector.
+pub trait TrapHandler {
+    fn trap(&mut self, vector: usize, cpu: &mut CPU) -> Option<Signal>;
+}
+
+pub type TrapHandlerPtr = Rc<RefCell<dyn TrapHandler>>;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct IRQ {
-    pub level: u32
+    pub level: u32,
+    pub vector: Option<usize>,      // Autovector if None
 }
 
 
@@ -82,7 +95,15 @@ impl CCRFlags {
 
 impl CPU {
     pub fn new(pc: u32, sr: u32, dr: [RegPtr; 8], ar: [RegPtr; 8], ssp: RegPtr, bus: BusPtr) -> Self {
-        CPU { pc, sr, dr, ar, ssp, bus, nxt: Instruction::NOP, prev: 0, jmp: 0, irq: VecDeque::new(), irp: false }
+        CPU { pc, sr, dr, ar, ssp, bus, nxt: Instruction::NOP, prev: 0, jmp: 0, irq: VecDeque::new(), irp: false, traps: Vec::new() }
+    }
+    // A reset fetches the initial supervisor stack pointer and program counter from the first two
+    // long words of the address space.
+    pub fn reset(&mut self) {
+        self.sr = 0x2700;
+        let ssp = self.bus.borrow_mut().read(0, Size::Long).inner();
+        self.ssp.replace(ssp);
+        self.pc = self.bus.borrow_mut().read(4, Size::Long).inner();
     }
     pub fn clock_cycle(&mut self) -> Signal {
         let next_instruction = self.nxt;
@@ -91,15 +112,40 @@ impl CPU {
             Signal::Quit => return Signal::Quit,
             _ => {}
         }
+        self.bus_error();
         self.jmp = self.pc;
         let opcode = self.next_instruction();
-        if let Some(instruction) = parse_instruction(opcode, self) {
-            self.nxt = instruction;
-            Signal::Ok
-        } else {
-            self.nxt = Instruction::NOP;
-            Signal::Ok
+        self.nxt = parse_instruction(opcode, self).unwrap_or(Instruction::NOP);
+        if self.bus_error() {
+            self.jmp = self.pc;
+            let opcode = self.next_instruction();
+            self.nxt = parse_instruction(opcode, self).unwrap_or(Instruction::NOP);
         }
+        Signal::Ok
+    }
+    // Takes the bus error exception if the last bus accesses went to an address nobody answers to.
+    // The group 0 exception frame holds the access address and a status word on top of SR and PC.
+    fn bus_error(&mut self) -> bool {
+        let (address, read) = match self.bus.borrow_mut().bus_error.take() {
+            Some(error) => error,
+            None => return false,
+        };
+        let (sr, supervisor) = (self.sr, self.in_supervisor_mode());
+        self.supervisor_mode(true);
+        let ssp = *self.ssp.borrow() - 14;
+        let status = (read as u16) << 4 | if supervisor { 5 } else { 1 };
+        let mut bus = self.bus.borrow_mut();
+        let opcode = bus.read(self.jmp as usize, Size::Word);
+        bus.write(ssp as usize, OpResult::Word(status));
+        bus.write(ssp as usize + 2, OpResult::Long(address as u32));
+        bus.write(ssp as usize + 6, opcode);
+        bus.write(ssp as usize + 8, OpResult::Word(sr as u16));
+        bus.write(ssp as usize + 10, OpResult::Long(self.pc));
+        self.pc = bus.read(8, Size::Long).inner();
+        bus.bus_error = None;
+        drop(bus);
+        self.ssp.replace(ssp);
+        true
     }
     pub fn next_instruction(&mut self) -> u16 {
         let instr = self.lookahead(0);
@@ -246,30 +292,43 @@ impl CPU {
             };
             disassembly.push_back((pc, opcodes, instr_txt));
         }
+        // Looking ahead must not cause bus errors
+        self.bus.borrow_mut().bus_error = None;
         disassembly
     }
     pub fn interrupt_mask(&self) -> u32 {
         (self.sr & 0x700) >> 8
     }
     pub fn serve_interrupt_requests(&mut self) {
+        // Masked requests stay pending (once per level and vector) until the mask is lowered
         let requested_irq = self.bus.borrow_mut().interrupt_requests();
         for irq in requested_irq {
-            if irq.level == 7 || irq.level > self.interrupt_mask() {
+            if !self.irq.contains(&irq) {
                 self.irq.push_back(irq);
             }
         }
-        if let Some(irq) = self.irq.pop_front() {
-            if irq.level == 7 || irq.level > self.interrupt_mask() {
-                println!("Interrupt (level {}) occured!", irq.level);
-                self.irp = true;
-                let trap = Instruction::TRAP { vector: 24 + irq.level as usize };
-                trap.execute(self);
-            }
+        let mask = self.interrupt_mask();
+        let next = (0..self.irq.len())
+            .filter(|&j| self.irq[j].level == 7 || self.irq[j].level > mask)
+            .max_by_key(|&j| (self.irq[j].level, Reverse(j)));
+        if let Some(irq) = next.and_then(|j| self.irq.remove(j)).filter(|irq| self.bus.borrow_mut().acknowledge_interrupt(irq)) {
+            self.irp = true;
+            // The prefetched instruction has not been executed yet, so it is where the handler returns to
+            self.pc = self.jmp;
+            let trap = Instruction::TRAP { vector: irq.vector.unwrap_or(24 + irq.level as usize) };
+            trap.execute(self);
+            self.sr = (self.sr & !0x700) | (irq.level << 8);
+            self.jmp = self.pc;
+            let opcode = self.next_instruction();
+            self.nxt = parse_instruction(opcode, self).unwrap_or(Instruction::NOP);
         }
     }
     pub fn poll_devices(&self) -> Signal {
         self.bus.borrow().poll_devices()
     }
+    pub fn clock_devices(&self) -> Signal {
+        self.bus.borrow_mut().clock()
+    }
 }
 
 impl fmt::Display for CPU {
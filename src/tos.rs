@@ -0,0 +1,98 @@
+// TOS ROM images. Every TOS starts with an OSHEADER, which tells where the ROM lives in the address
+// space, where execution starts after a reset and which version, country and build date it is.
+// Old TOS versions (up to 1.04) occupy 192 KB at $FC0000, later ones 256 KB at $E00000.
+
+use std::fmt;
+use std::fs;
+
+pub const ST_ROM_BASE: u32 = 0xfc0000;
+pub const STE_ROM_BASE: u32 = 0xe00000;
+const ST_ROM_SIZE: usize = 0x30000;
+const STE_ROM_SIZE: usize = 0x40000;
+const HEADER_SIZE: usize = 0x30;
+const BRA: u8 = 0x60;
+
+const COUNTRIES: [&str; 16] = [
+    "USA", "Germany", "France", "UK", "Spain", "Italy", "Sweden", "Switzerland (French)",
+    "Switzerland (German)", "Turkey", "Finland", "Norway", "Denmark", "Saudi Arabia", "Netherlands",
+    "Czechoslovakia",
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MachineType {
+    ST,
+    STE,
+}
+
+pub struct TOSImage {
+    pub data: Vec<u8>,
+    pub version: u16,
+    pub base: u32,
+    pub reset: u32,
+    pub country: usize,
+    pub pal: bool,
+    pub date: (u32, u32, u32),
+}
+
+impl TOSImage {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::from(data)
+    }
+    pub fn from(mut data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < HEADER_SIZE || data[0] != BRA {
+            return Err(String::from("Not a TOS image (no OSHEADER found)"));
+        }
+        let word = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        let long = |offset: usize| u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+        let (version, reset, base) = (word(0x02), long(0x04), long(0x08));
+        let configuration = word(0x1c);
+        // The build date is stored as BCD $MMDDYYYY
+        let bcd = |value: u32| (0..8).rev().fold(0, |result, digit| 10 * result + ((value >> (4 * digit)) & 0xf));
+        let date = long(0x18);
+        let date = (bcd(date & 0xffff), bcd(date >> 24), bcd((date >> 16) & 0xff));
+        let size = match base {
+            ST_ROM_BASE => ST_ROM_SIZE,
+            STE_ROM_BASE => STE_ROM_SIZE,
+            _ => return Err(format!("Unsupported TOS base address {:06x}", base)),
+        };
+        if data.len() > size {
+            return Err(format!("TOS image is too large ({} bytes, at most {} expected)", data.len(), size));
+        }
+        if reset < base || reset >= base + size as u32 {
+            return Err(format!("Reset address {:06x} lies outside of the ROM", reset));
+        }
+        // Some dumps are missing their last bytes, which are not used anyway
+        data.resize(size, 0xff);
+        Ok(Self { data, version, base, reset, country: configuration as usize >> 1, pal: configuration & 1 != 0, date })
+    }
+    pub fn machine(&self) -> MachineType {
+        // TOS 1.06 and 1.62 were made for the STE, 2.0x runs on both and is used on the STE here
+        if self.base == STE_ROM_BASE {
+            MachineType::STE
+        } else {
+            MachineType::ST
+        }
+    }
+    pub fn country(&self) -> &str {
+        COUNTRIES.get(self.country).unwrap_or(&"unknown country")
+    }
+}
+
+impl fmt::Display for TOSImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.date;
+        write!(
+            f,
+            "TOS {}.{:02x} ({}, {}, {:04}-{:02}-{:02}) for the {:?}",
+            self.version >> 8,
+            self.version & 0xff,
+            self.country(),
+            if self.pal { "PAL" } else { "NTSC" },
+            year,
+            month,
+            day,
+            self.machine()
+        )
+    }
+}
@@ -0,0 +1,84 @@
+// Cold boots the bundled TOS without a window and watches it come up. A probe on the bus looks at
+// the system variables every frame: TOS' exception handler leaves $12345678 in proc_lives ($380)
+// when it shows bombs, and _hz_200 ($4BA) starts over from 0 when the machine resets. The screen
+// stays blank until the desktop is drawn.
+
+use chrono::NaiveDate;
+use em68k::atari::{Machine, MachineModel};
+use em68k::devices::{Device, Signal};
+use em68k::fields::{OpResult, Size};
+use em68k::memory::{Bus, MemoryRange};
+use em68k::processor::IRQ;
+use em68k::Emulator;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const FRAMES: u64 = 200;
+const FRAME_CYCLES: u64 = 160256;
+
+#[derive(Default)]
+struct Observations {
+    bombs: bool,
+    resets: usize,
+    hz_200: u32,
+    drawn: bool,
+}
+
+struct Probe {
+    next: u64,
+    observations: Rc<RefCell<Observations>>,
+}
+
+impl Device for Probe {
+    fn memconfig(&self) -> MemoryRange {
+        Vec::new()
+    }
+    fn read(&mut self, _address: usize, size: Size) -> OpResult {
+        size.zero()
+    }
+    fn write(&mut self, _address: usize, _result: OpResult) -> Signal {
+        Signal::Ok
+    }
+    fn interrupt_request(&mut self) -> Option<IRQ> {
+        None
+    }
+    fn poll(&self) -> Signal {
+        Signal::Ok
+    }
+    fn clock(&mut self, cycles: u64) -> Signal {
+        if cycles < self.next {
+            return Signal::Ok;
+        }
+        self.next = cycles + FRAME_CYCLES;
+        Signal::BusRequest
+    }
+    fn bus_access(&mut self, bus: &mut Bus) {
+        let mut observations = self.observations.borrow_mut();
+        let hz_200 = bus.read(0x4ba, Size::Long).inner();
+        if hz_200 < observations.hz_200 {
+            observations.resets += 1;
+        }
+        observations.hz_200 = hz_200;
+        observations.bombs |= bus.read(0x380, Size::Long).inner() == 0x12345678;
+        let screen = bus.read(0x44e, Size::Long).inner() as usize;
+        observations.drawn |=
+            screen != 0 && (0..32000).step_by(32).any(|offset| bus.read(screen + offset, Size::Long).inner() != 0);
+    }
+}
+
+#[test]
+fn cold_boot_reaches_the_desktop() {
+    let time = NaiveDate::from_ymd(1990, 1, 1).and_hms(12, 0, 0);
+    // With drive A empty, TOS would spend most of the frames waiting for floppy timeouts
+    let machine = Machine::new(MachineModel::ST1040).floppy("examples/ST0001 Mono Demos.st");
+    let mut configuration = machine.headless(true).frames(FRAMES).time(time).build().unwrap();
+    let observations = Rc::new(RefCell::new(Observations::default()));
+    configuration.bus.attach(Box::new(Probe { next: 0, observations: Rc::clone(&observations) }));
+    Emulator::new(configuration).boot(false);
+    let observations = observations.borrow();
+    assert!(!observations.bombs, "TOS crashed");
+    assert_eq!(observations.resets, 0, "the machine reset");
+    assert!(observations.drawn, "the desktop was not drawn");
+    // The 200 Hz timer runs from early in the boot on
+    assert!(observations.hz_200 > 2 * FRAMES as u32, "_hz_200 is only {}", observations.hz_200);
+}
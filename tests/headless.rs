@@ -0,0 +1,64 @@
+// Boots the bundled TOS on a monochrome monitor without a window and checks the screenshot of the
+// desktop: a white menu bar with black text above the dotted desktop background.
+
+use std::convert::TryInto;
+use std::process::Command;
+
+const WIDTH: usize = 832;
+const HEIGHT: usize = 552;
+// Where the 640 x 400 picture of the monochrome monitor is
+const LEFT: usize = (WIDTH - 640) / 2;
+const TOP: usize = (HEIGHT - 400) / 2;
+
+// Reads the RGB pixels of a PNG image whose data is in stored blocks, as the screenshots are
+fn decode_png(png: &[u8]) -> (usize, usize, Vec<u8>) {
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    let (mut position, mut width, mut height, mut zlib) = (8, 0, 0, Vec::new());
+    while position < png.len() {
+        let length = u32::from_be_bytes(png[position..position + 4].try_into().unwrap()) as usize;
+        let data = &png[position + 8..position + 8 + length];
+        match &png[position + 4..position + 8] {
+            b"IHDR" => {
+                width = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+                height = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+                assert_eq!(data[8..10], [8, 2], "not 8 bit RGB");
+            }
+            b"IDAT" => zlib.extend(data),
+            _ => {}
+        }
+        position += 12 + length;
+    }
+    let (mut position, mut raw) = (2, Vec::new());
+    loop {
+        let length = u16::from_le_bytes([zlib[position + 1], zlib[position + 2]]) as usize;
+        raw.extend(&zlib[position + 5..position + 5 + length]);
+        position += 5 + length;
+        if zlib[position - 5 - length] & 1 != 0 {
+            break;
+        }
+    }
+    let pixels = raw.chunks(3 * width + 1).flat_map(|row| row[1..].to_vec()).collect();
+    (width, height, pixels)
+}
+
+#[test]
+fn screenshot_of_the_monochrome_desktop() {
+    let path = std::env::temp_dir().join(format!("em68k-desktop-{}.png", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_myatari"))
+        .args(["--mono", "--headless", "--frames", "250", "--screenshot"])
+        .arg(format!("{}@250", path.display()))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let png = std::fs::read(&path).expect("no screenshot");
+    std::fs::remove_file(&path).ok();
+    let (width, height, pixels) = decode_png(&png);
+    assert_eq!((width, height), (WIDTH, HEIGHT));
+    let white = |x: usize, y: usize| pixels[3 * (y * WIDTH + x)] == 0xff;
+    let white_in_row = |y: usize| (LEFT..LEFT + 640).filter(|&x| white(x, y)).count();
+    // The menu bar is white with black text, the desktop below dotted and the border black
+    assert!(white_in_row(TOP + 2) > 600, "the menu bar is not white");
+    assert!((TOP..TOP + 19).any(|y| white_in_row(y) < 600), "the menu bar has no text");
+    assert!((TOP + 100..TOP + 300).all(|y| (300..540).contains(&white_in_row(y))), "the desktop is not dotted");
+    assert_eq!(white_in_row(TOP - 1), 0);
+}